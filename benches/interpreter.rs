@@ -0,0 +1,95 @@
+#[macro_use]
+extern crate criterion;
+extern crate rust_bpf;
+
+use criterion::{black_box, Criterion, Throughput};
+
+use rust_bpf::common::instruction::*;
+use rust_bpf::filter::builder::FilterBuilder;
+use rust_bpf::vm::compiler::compile;
+use rust_bpf::vm::machine::PsuedoMachine;
+
+/// Returns an Ethernet frame of `len` bytes holding an IPv4 TCP segment to port 80.
+fn tcp_packet(len: usize) -> Vec<u8> {
+  let mut pkt = vec![0u8; len];
+  pkt[12] = 0x08;
+  pkt[14] = 0x45;
+  pkt[23] = 6;
+  pkt[36..38].copy_from_slice(&80u16.to_be_bytes());
+  for (i, byte) in pkt.iter_mut().enumerate().skip(54) {
+    *byte = i as u8;
+  }
+  pkt
+}
+
+/// Returns the filter for `ip and tcp dst port 80`.
+fn port_filter() -> Vec<Instruction> {
+  FilterBuilder::new()
+    .ether_type(0x0800)
+    .ip_protocol(6)
+    .tcp_dst_port(80)
+    .build()
+    .unwrap()
+}
+
+/// Returns a filter comparing the destination port against `n` other ports before accepting, so
+/// every packet runs the whole chain of jumps.
+fn jump_chain(n: u32) -> Vec<Instruction> {
+  let mut prog = vec![Instruction::new(LDH, 0, 0, 36)];
+  for port in 0..n {
+    prog.push(Instruction::new(JMPEQ, 1, 0, 1000 + port));
+  }
+  prog.push(Instruction::new(RETK, 0, 0, 0xFFFF));
+  prog.push(Instruction::new(RETK, 0, 0, 0));
+  prog
+}
+
+/// Returns a program summing every word of a 1500 byte packet's payload, touching all of it.
+fn payload_sum() -> Vec<Instruction> {
+  let mut prog = vec![Instruction::new(LDI, 0, 0, 0), Instruction::new(ST, 0, 0, 0)];
+  for offset in (54..1500 - 4).step_by(4) {
+    prog.push(Instruction::new(LDW, 0, 0, offset));
+    prog.push(Instruction::new(TAX, 0, 0, 0));
+    prog.push(Instruction::new(LDWM, 0, 0, 0));
+    prog.push(Instruction::new(ADDX, 0, 0, 0));
+    prog.push(Instruction::new(ST, 0, 0, 0));
+  }
+  prog.push(Instruction::new(RETA, 0, 0, 0));
+  prog
+}
+
+fn run_program(c: &mut Criterion) {
+  let small = tcp_packet(54);
+  let large = tcp_packet(1500);
+  let cases = [
+    ("port_filter", port_filter(), &small),
+    ("jump_chain", jump_chain(200), &small),
+    ("port_filter_1500", port_filter(), &large),
+    ("payload_sum_1500", payload_sum(), &large),
+  ];
+
+  let mut group = c.benchmark_group("run_program");
+  for &(name, ref prog, pkt) in cases.iter() {
+    assert!(
+      PsuedoMachine::new().run_program(prog, &pkt[..]).is_ok(),
+      "{} failed",
+      name
+    );
+    group.throughput(Throughput::Bytes(pkt.len() as u64));
+    group.bench_function(name, |b| {
+      b.iter(|| PsuedoMachine::new().run_program(black_box(prog), black_box(&pkt[..])))
+    });
+  }
+  group.finish();
+
+  let mut group = c.benchmark_group("compiled");
+  for &(name, ref prog, pkt) in cases.iter() {
+    let compiled = compile(prog);
+    group.throughput(Throughput::Bytes(pkt.len() as u64));
+    group.bench_function(name, |b| b.iter(|| compiled.run(black_box(pkt))));
+  }
+  group.finish();
+}
+
+criterion_group!(benches, run_program);
+criterion_main!(benches);
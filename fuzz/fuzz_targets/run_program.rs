@@ -0,0 +1,30 @@
+//! Runs random programs over random packets, checking the interpreter never panics and always
+//! agrees with `CompiledFilter`.
+//!
+//! The input is a byte saying how many instructions follow, that many 8 byte `struct sock_filter`
+//! records in host byte order, and then the packet. Run with a nightly toolchain from the
+//! repository root:
+//!
+//!     cargo install cargo-fuzz
+//!     cargo +nightly fuzz run run_program
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_bpf::common::instruction::Instruction;
+use rust_bpf::vm::compiler::compile;
+use rust_bpf::vm::machine::PsuedoMachine;
+
+fuzz_target!(|data: &[u8]| {
+  let (len, rest) = match data.split_first() {
+    Some((&len, rest)) => (len as usize, rest),
+    None => return,
+  };
+  let len = len.min(rest.len() / 8);
+  let (prog_bytes, pkt) = rest.split_at(len * 8);
+  let prog: Vec<Instruction> = prog_bytes.chunks(8).filter_map(Instruction::from_bytes).collect();
+
+  // Every jump moves forward, so the default instruction limit is never the only thing stopping
+  // a run; either way it has to come back with a result rather than panic.
+  let interpreted = PsuedoMachine::new().run_program(&prog, pkt);
+  assert_eq!(interpreted, compile(&prog).run(pkt));
+});
@@ -0,0 +1,101 @@
+use common::instruction::*;
+use common::mnemonic::Mnemonic;
+
+/// Renders the mnemonic and operand of `instr`, as if it were the instruction at index `pc`.
+/// Jump targets are printed as absolute instruction indices, like `tcpdump -d` does.
+pub fn format_instruction(pc: usize, instr: &Instruction) -> String {
+  let (op, operand) = match Opcode::from_raw(instr.opcode) {
+    Some(decoded) => (Mnemonic::from(decoded).as_str(), format_operand(pc, decoded, instr)),
+    None => ("unimp", format!("{:#x}", instr.opcode)),
+  };
+  if instr.class() == CLASS_JMP && instr.op() != OP_JA {
+    format!(
+      "{:<8} {:<16} jt {}\tjf {}",
+      op,
+      operand,
+      pc + 1 + instr.jt as usize,
+      pc + 1 + instr.jf as usize
+    )
+  } else {
+    format!("{:<8} {}", op, operand).trim_end().to_string()
+  }
+}
+
+/// Renders the operand of `instr`, which decodes to `decoded`, as if it were at index `pc`.
+fn format_operand(pc: usize, decoded: Opcode, instr: &Instruction) -> String {
+  let k = instr.k;
+  match decoded {
+    Opcode::Ldi | Opcode::Ldxi => format!("#{:#x}", k),
+    Opcode::Ldw | Opcode::Ldh | Opcode::Ldb | Opcode::Ldxw => format!("[{}]", k),
+    Opcode::Ldwi | Opcode::Ldhi | Opcode::Ldbi => format!("[x + {}]", k),
+    Opcode::Ldwm | Opcode::Ldhm | Opcode::Ldbm | Opcode::Ldxm | Opcode::St | Opcode::Stx => format!("M[{}]", k),
+    Opcode::Sti | Opcode::Stxi => format!("M[x + {}]", k),
+    Opcode::Ldl | Opcode::Ldxl => "#pktlen".to_string(),
+    Opcode::Ldxb => format!("4*([{}]&0xf)", k),
+    Opcode::Tax | Opcode::Txa | Opcode::Neg => String::new(),
+    Opcode::Reta => "a".to_string(),
+    Opcode::Retk => format!("#{}", k),
+    Opcode::Jmp => format!("{}", pc as u64 + 1 + k as u64),
+    _ if instr.source() == Source::X => "x".to_string(),
+    // Bitwise operands and jump comparisons read better in hex.
+    Opcode::Ork | Opcode::Andk | Opcode::Xork => format!("#{:#x}", k),
+    _ if instr.class() == CLASS_JMP => format!("#{:#x}", k),
+    _ => format!("#{}", k),
+  }
+}
+
+/// Renders a program one instruction per line in the style of `tcpdump -d`.
+pub fn disassemble(prog: &[Instruction]) -> String {
+  let mut out = String::new();
+  for (pc, instr) in prog.iter().enumerate() {
+    out.push_str(&format!("({:03}) {}\n", pc, format_instruction(pc, instr)));
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn disassemble_tcp_filter() {
+    let prog = vec![
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 3, 0x800),
+      Instruction::new(LDB, 0, 0, 23),
+      Instruction::new(JMPEQ, 0, 1, 0x6),
+      Instruction::new(RETK, 0, 0, 262144),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    let expected = "(000) ldh      [12]\n\
+                    (001) jeq      #0x800           jt 2\tjf 5\n\
+                    (002) ldb      [23]\n\
+                    (003) jeq      #0x6             jt 4\tjf 5\n\
+                    (004) ret      #262144\n\
+                    (005) ret      #0\n";
+    assert_eq!(disassemble(&prog), expected);
+  }
+
+  #[test]
+  fn format_operands() {
+    assert_eq!(
+      format_instruction(0, &Instruction::new(LDXB, 0, 0, 14)),
+      "ldxb     4*([14]&0xf)"
+    );
+    assert_eq!(
+      format_instruction(0, &Instruction::new(LDHI, 0, 0, 16)),
+      "ldh      [x + 16]"
+    );
+    assert_eq!(format_instruction(0, &Instruction::new(ST, 0, 0, 3)), "st       M[3]");
+    assert_eq!(format_instruction(0, &Instruction::new(TAX, 0, 0, 0)), "tax");
+    assert_eq!(format_instruction(3, &Instruction::new(JMP, 0, 0, 2)), "ja       6");
+    assert_eq!(
+      format_instruction(0, &Instruction::new(JMPSETX, 1, 0, 0)),
+      "jset     x                jt 2\tjf 1"
+    );
+    assert_eq!(
+      format_instruction(0, &Instruction::new(0xFFFF, 0, 0, 0)),
+      "unimp    0xffff"
+    );
+  }
+}
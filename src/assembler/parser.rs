@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use regex::Regex;
+
+use common::instruction::*;
+use common::mnemonic::Mnemonic;
+
+const INSTR_REGEX: &str = r"^\s*(?:(\w+):)?\s*(?:(\w+)\s*([^,]*?)\s*(?:,\s*(\w+)\s*)?(?:,\s*(\w+)\s*)?)?$";
+const NUM_REGEX: &str = r"(0x[0-9a-fA-F]+|\d+)";
+const DD_REGEX: &str = r"^\s*\{\s*(\w+)\s*,\s*(\w+)\s*,\s*(\w+)\s*,\s*(\w+)\s*\}\s*,?\s*$";
+
+/// An error encountered while assembling a program.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssembleError {
+  /// The 1-based line the error was found on.
+  pub line: usize,
+  /// What went wrong.
+  pub message: String,
+}
+
+impl AssembleError {
+  fn new(line: usize, message: String) -> AssembleError {
+    AssembleError { line, message }
+  }
+}
+
+impl fmt::Display for AssembleError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "line {}: {}", self.line, self.message)
+  }
+}
+
+impl Error for AssembleError {}
+
+/// A line of assembly split into its label, mnemonic, operand and branch targets.
+#[derive(Clone, Copy, Debug, Default)]
+struct AsmInstr<'a> {
+  pub lbl: Option<&'a str>,
+  pub op: Option<&'a str>,
+  pub arg: Option<&'a str>,
+  pub lt: Option<&'a str>,
+  pub lf: Option<&'a str>,
+}
+
+impl<'a> AsmInstr<'a> {
+  /// Splits a line into its fields. Returns None if the line is malformed.
+  pub fn from_str(src: &'a str) -> Option<AsmInstr<'a>> {
+    lazy_static! {
+      static ref INSTR_RE: Regex = Regex::new(INSTR_REGEX).unwrap();
+    }
+    let captures = INSTR_RE.captures(src)?;
+    let field = |i| captures.get(i).map(|c| c.as_str()).filter(|s| !s.is_empty());
+    Some(AsmInstr {
+      lbl: field(1),
+      op: field(2),
+      arg: field(3),
+      lt: field(4),
+      lf: field(5),
+    })
+  }
+}
+
+///  Addressing mode  Syntax               Description
+///
+///   0               x/%x                 Register X
+///   1               [k]                  BHW at byte offset k in the packet
+///   2               [x + k]              BHW at the offset X + k in the packet
+///   3               M[k]                 Word at offset k in M[]
+///   4               #k                   Literal value stored in k
+///   5               4*([k]&0xf)          Lower nibble * 4 at byte offset k in the packet
+///   6               L                    Jump label L
+///   7               a/%a                 Accumulator A
+///   8               #len/#pktlen         Length of the packet
+///   9               M[x + k]             Word at offset X + k in M[], only for stores
+#[derive(Clone, Copy, Debug)]
+enum AddrMode<'a> {
+  Index,
+  Packet(u32),
+  PacketIndirect(u32),
+  ScratchMem(u32),
+  ScratchMemIndirect(u32),
+  Literal(u32),
+  PacketNibble(u32),
+  JumpLabel(&'a str),
+  Accumulator,
+  Length,
+}
+
+/// Parses a decimal or 0x-prefixed hexadecimal number.
+fn parse_num(s: &str) -> Option<u32> {
+  if s.starts_with("0x") || s.starts_with("0X") {
+    u32::from_str_radix(&s[2..], 16).ok()
+  } else {
+    s.parse().ok()
+  }
+}
+
+impl<'a> AddrMode<'a> {
+  /// Parses an operand. Returns None if it isn't in any of the supported addressing modes.
+  pub fn from_str(s: &'a str) -> Option<AddrMode<'a>> {
+    lazy_static! {
+      static ref MODE_RE_OFFSET: Regex = Regex::new(&format!(r"^(M)?\[\s*(%?x\s*\+\s*)?{}\s*\]$", NUM_REGEX)).unwrap();
+      static ref MODE_RE_IMM: Regex = Regex::new(&format!(r"^#(-)?{}$", NUM_REGEX)).unwrap();
+      static ref MODE_RE_NIBBLE: Regex =
+        Regex::new(&format!(r"^4\s*\*\s*\(\s*\[\s*{}\s*\]\s*&\s*0xf\s*\)$", NUM_REGEX)).unwrap();
+      static ref MODE_RE_LABEL: Regex = Regex::new(r"^\w+$").unwrap();
+    }
+    match s {
+      "a" | "%a" => return Some(AddrMode::Accumulator),
+      "x" | "%x" => return Some(AddrMode::Index),
+      "#len" | "#pktlen" => return Some(AddrMode::Length),
+      _ => (),
+    }
+
+    if let Some(caps) = MODE_RE_OFFSET.captures(s) {
+      let k = parse_num(caps.get(3).unwrap().as_str())?;
+      return match (caps.get(1), caps.get(2)) {
+        (Some(_), Some(_)) => Some(AddrMode::ScratchMemIndirect(k)),
+        (Some(_), None) => Some(AddrMode::ScratchMem(k)),
+        (None, Some(_)) => Some(AddrMode::PacketIndirect(k)),
+        (None, None) => Some(AddrMode::Packet(k)),
+      };
+    }
+
+    if let Some(caps) = MODE_RE_IMM.captures(s) {
+      let k = parse_num(caps.get(2).unwrap().as_str())?;
+      return match caps.get(1) {
+        Some(_) => Some(AddrMode::Literal(k.wrapping_neg())),
+        _ => Some(AddrMode::Literal(k)),
+      };
+    }
+
+    if let Some(caps) = MODE_RE_NIBBLE.captures(s) {
+      return parse_num(caps.get(1).unwrap().as_str()).map(AddrMode::PacketNibble);
+    }
+
+    if MODE_RE_LABEL.is_match(s) {
+      return Some(AddrMode::JumpLabel(s));
+    }
+    None
+  }
+}
+
+/// Resolves a jump from the instruction at `pc` to label `lbl` into the number of instructions
+/// to skip.
+fn jump_offset(labels: &HashMap<&str, usize>, lbl: &str, pc: usize, line: usize) -> Result<u32, AssembleError> {
+  match labels.get(lbl) {
+    None => Err(AssembleError::new(line, format!("undefined label `{}`", lbl))),
+    Some(&target) if target <= pc => Err(AssembleError::new(line, format!("backward jump to `{}`", lbl))),
+    Some(&target) => Ok((target - pc - 1) as u32),
+  }
+}
+
+/// Resolves a conditional branch target, which must fit in the 8-bit jt/jf fields.
+fn branch_offset(labels: &HashMap<&str, usize>, lbl: &str, pc: usize, line: usize) -> Result<u8, AssembleError> {
+  let offset = jump_offset(labels, lbl, pc, line)?;
+  if offset > u8::MAX as u32 {
+    return Err(AssembleError::new(
+      line,
+      format!("`{}` is too far away for a conditional jump", lbl),
+    ));
+  }
+  Ok(offset as u8)
+}
+
+/// Encodes a single line of assembly as the instruction at index `pc`.
+fn encode(
+  instr: &AsmInstr,
+  labels: &HashMap<&str, usize>,
+  pc: usize,
+  line: usize,
+) -> Result<Instruction, AssembleError> {
+  let op = instr.op.unwrap();
+  let mnemonic: Mnemonic = match op.parse() {
+    Ok(mnemonic) => mnemonic,
+    Err(_) => return Err(AssembleError::new(line, format!("unknown instruction `{}`", op))),
+  };
+  let mode = match instr.arg {
+    Some(arg) => match AddrMode::from_str(arg) {
+      Some(mode) => Some(mode),
+      None => return Err(AssembleError::new(line, format!("malformed operand `{}`", arg))),
+    },
+    None => None,
+  };
+
+  let base = mnemonic.opcode();
+  let (opcode, k) = match (mnemonic, mode) {
+    (Mnemonic::Ld, Some(AddrMode::Literal(k))) | (Mnemonic::Ldi, Some(AddrMode::Literal(k))) => (LDI, k),
+    (Mnemonic::Ld, Some(AddrMode::Length)) => (LDL, 0),
+    (Mnemonic::Ld, Some(AddrMode::Packet(k)))
+    | (Mnemonic::Ldh, Some(AddrMode::Packet(k)))
+    | (Mnemonic::Ldb, Some(AddrMode::Packet(k))) => (base | MODE_ABS, k),
+    (Mnemonic::Ld, Some(AddrMode::PacketIndirect(k)))
+    | (Mnemonic::Ldh, Some(AddrMode::PacketIndirect(k)))
+    | (Mnemonic::Ldb, Some(AddrMode::PacketIndirect(k))) => (base | MODE_IND, k),
+    (Mnemonic::Ld, Some(AddrMode::ScratchMem(k)))
+    | (Mnemonic::Ldh, Some(AddrMode::ScratchMem(k)))
+    | (Mnemonic::Ldb, Some(AddrMode::ScratchMem(k))) => (base | MODE_MEM, k),
+    (Mnemonic::Ldx, Some(AddrMode::Literal(k))) | (Mnemonic::Ldxi, Some(AddrMode::Literal(k))) => (LDXI, k),
+    (Mnemonic::Ldx, Some(AddrMode::Packet(k))) => (LDXW, k),
+    (Mnemonic::Ldx, Some(AddrMode::ScratchMem(k))) => (LDXM, k),
+    (Mnemonic::Ldx, Some(AddrMode::Length)) => (LDXL, 0),
+    (Mnemonic::Ldx, Some(AddrMode::PacketNibble(k))) | (Mnemonic::Ldxb, Some(AddrMode::PacketNibble(k))) => (LDXB, k),
+    (Mnemonic::St, Some(AddrMode::ScratchMem(k))) | (Mnemonic::Stx, Some(AddrMode::ScratchMem(k))) => (base, k),
+    (Mnemonic::St, Some(AddrMode::ScratchMemIndirect(k))) => (STI, k),
+    (Mnemonic::Stx, Some(AddrMode::ScratchMemIndirect(k))) => (STXI, k),
+    (Mnemonic::Tax, None) | (Mnemonic::Txa, None) | (Mnemonic::Neg, None) => (base, 0),
+    (Mnemonic::Ret, Some(AddrMode::Literal(k))) => (RETK, k),
+    (Mnemonic::Ret, Some(AddrMode::Accumulator)) => (RETA, 0),
+    (Mnemonic::Ja, Some(AddrMode::JumpLabel(lbl))) => (JMP, jump_offset(labels, lbl, pc, line)?),
+    (m, Some(AddrMode::Literal(k))) if m.takes_source() => (base | SRC_K, k),
+    (m, Some(AddrMode::Index)) if m.takes_source() => (base | SRC_X, 0),
+    (_, Some(_)) => {
+      return Err(AssembleError::new(
+        line,
+        format!("invalid operand `{}` for `{}`", instr.arg.unwrap(), op),
+      ))
+    },
+    (_, None) => return Err(AssembleError::new(line, format!("`{}` is missing its operand", op))),
+  };
+
+  let conditional = opcode & MASK_CLASS == CLASS_JMP && opcode != JMP;
+  let (jt, jf) = match (conditional, instr.lt, instr.lf) {
+    (true, Some(lt), lf) => (
+      branch_offset(labels, lt, pc, line)?,
+      match lf {
+        Some(lf) => branch_offset(labels, lf, pc, line)?,
+        None => 0,
+      },
+    ),
+    (true, None, _) => return Err(AssembleError::new(line, format!("`{}` is missing a branch target", op))),
+    (false, None, None) => (0, 0),
+    (false, _, _) => {
+      return Err(AssembleError::new(
+        line,
+        format!("`{}` doesn't take branch targets", op),
+      ))
+    },
+  };
+  Instruction::try_new(opcode, jt, jf, k).map_err(|err| AssembleError::new(line, err.to_string()))
+}
+
+/// Assembles a program written one instruction per line, e.g. `jeq #0x800, ip, drop`.
+/// Lines may start with a `label:` that jump instructions can refer to, and anything after a `;`
+/// is a comment.
+pub fn assemble(src: &str) -> Result<Vec<Instruction>, AssembleError> {
+  let mut labels = HashMap::new();
+  let mut prog = Vec::new();
+  for (n, line) in src.lines().enumerate() {
+    let code = match line.find(';') {
+      Some(comment) => &line[..comment],
+      None => line,
+    };
+    let instr = match AsmInstr::from_str(code) {
+      Some(instr) => instr,
+      None => return Err(AssembleError::new(n + 1, format!("malformed line `{}`", code.trim()))),
+    };
+    if let Some(lbl) = instr.lbl {
+      if labels.insert(lbl, prog.len()).is_some() {
+        return Err(AssembleError::new(n + 1, format!("label `{}` is already defined", lbl)));
+      }
+    }
+    if instr.op.is_some() {
+      prog.push((n + 1, instr));
+    }
+  }
+
+  prog
+    .iter()
+    .enumerate()
+    .map(|(pc, &(line, ref instr))| encode(instr, &labels, pc, line))
+    .collect()
+}
+
+/// Parses a program printed as a C array of `struct sock_filter` initializers, one per line, the
+/// way `tcpdump -dd` and the `asm` binary print them, e.g. `{ 0x28, 0, 0, 0x0000000c },`.
+pub fn parse_dd(src: &str) -> Result<Vec<Instruction>, AssembleError> {
+  lazy_static! {
+    static ref DD_RE: Regex = Regex::new(DD_REGEX).unwrap();
+  }
+  let mut prog = Vec::new();
+  for (n, line) in src.lines().enumerate() {
+    if line.trim().is_empty() {
+      continue;
+    }
+    let malformed = || AssembleError::new(n + 1, format!("malformed line `{}`", line.trim()));
+    let caps = DD_RE.captures(line).ok_or_else(malformed)?;
+    let mut fields = [0; 4];
+    for (i, field) in fields.iter_mut().enumerate() {
+      *field = parse_num(caps.get(i + 1).unwrap().as_str()).ok_or_else(malformed)?;
+    }
+    if fields[0] > u16::MAX as u32 || fields[1] > u8::MAX as u32 || fields[2] > u8::MAX as u32 {
+      return Err(malformed());
+    }
+    prog.push(Instruction::new(
+      fields[0] as u16,
+      fields[1] as u8,
+      fields[2] as u8,
+      fields[3],
+    ));
+  }
+  Ok(prog)
+}
+
+/// Parses a program in either `tcpdump -dd` format or assembly, going by whether the first
+/// non-blank line starts with a `{`.
+pub fn parse_filter(src: &str) -> Result<Vec<Instruction>, AssembleError> {
+  match src.lines().map(str::trim).find(|line| !line.is_empty()) {
+    Some(line) if line.starts_with('{') => parse_dd(src),
+    _ => assemble(src),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use assembler::disassembler::disassemble;
+
+  const TCP_FILTER: &str = "
+        ldh [12]
+        jeq #0x800, ip, drop
+    ip: ldb [23]
+        jeq #6, accept, drop
+    accept:
+        ret #262144
+    drop:
+        ret #0
+  ";
+
+  #[test]
+  fn assemble_filter() {
+    let prog = assemble(TCP_FILTER).unwrap();
+    assert_eq!(
+      prog,
+      vec![
+        Instruction::new(LDH, 0, 0, 12),
+        Instruction::new(JMPEQ, 0, 3, 0x800),
+        Instruction::new(LDB, 0, 0, 23),
+        Instruction::new(JMPEQ, 0, 1, 6),
+        Instruction::new(RETK, 0, 0, 262144),
+        Instruction::new(RETK, 0, 0, 0),
+      ]
+    );
+  }
+
+  #[test]
+  fn assemble_operands() {
+    let src = "
+      ld #-1
+      ldx 4*([14]&0xf)
+      ld [x + 0x10]
+      ldh M[2]
+      ld #len
+      st M[15]
+      stx M[x + 2]
+      add x
+      and #0x1fff
+      tax
+      ja done
+      jset x, done
+      done: ret a
+    ";
+    let prog = assemble(src).unwrap();
+    assert_eq!(prog[0], Instruction::new(LDI, 0, 0, 0xFFFFFFFF));
+    assert_eq!(prog[1], Instruction::new(LDXB, 0, 0, 14));
+    assert_eq!(prog[2], Instruction::new(LDWI, 0, 0, 16));
+    assert_eq!(prog[3], Instruction::new(LDHM, 0, 0, 2));
+    assert_eq!(prog[4], Instruction::new(LDL, 0, 0, 0));
+    assert_eq!(prog[5], Instruction::new(ST, 0, 0, 15));
+    assert_eq!(prog[6], Instruction::new(STXI, 0, 0, 2));
+    assert_eq!(prog[7], Instruction::new(ADDX, 0, 0, 0));
+    assert_eq!(prog[8], Instruction::new(ANDK, 0, 0, 0x1FFF));
+    assert_eq!(prog[9], Instruction::new(TAX, 0, 0, 0));
+    assert_eq!(prog[10], Instruction::new(JMP, 0, 0, 1));
+    assert_eq!(prog[11], Instruction::new(JMPSETX, 0, 0, 0));
+    assert_eq!(prog[12], Instruction::new(RETA, 0, 0, 0));
+  }
+
+  #[test]
+  fn assemble_disassemble_round_trip() {
+    let prog = assemble(TCP_FILTER).unwrap();
+    let expected = "(000) ldh      [12]\n\
+                    (001) jeq      #0x800           jt 2\tjf 5\n\
+                    (002) ldb      [23]\n\
+                    (003) jeq      #0x6             jt 4\tjf 5\n\
+                    (004) ret      #262144\n\
+                    (005) ret      #0\n";
+    assert_eq!(disassemble(&prog), expected);
+  }
+
+  #[test]
+  fn assemble_errors() {
+    let err = assemble("ld #1\nfoo #1\nret a").unwrap_err();
+    assert_eq!(err.line, 2);
+    assert!(assemble("st #1").is_err());
+    assert!(assemble("ld [x + 1], a, b").is_err());
+    assert!(assemble("jeq #1\nret a").is_err());
+    assert!(assemble("l: ld #1\nja l\nret a").is_err());
+    assert!(assemble("ld M[x + 1]").is_err());
+  }
+
+  #[test]
+  fn labels_and_comments() {
+    let src = "
+      ; Accept IPv4 TCP and UDP.
+      start:  ldh [12]            ; EtherType
+              jeq #0x800, ip, drop
+      ip:     ldb [23]
+              jeq #6, accept      ; falls through to `udp` when not TCP
+      udp:    jeq #17, accept, drop
+      accept: ret #-1
+      drop:   ret #0
+    ";
+    let prog = assemble(src).unwrap();
+    assert_eq!(
+      prog,
+      vec![
+        Instruction::new(LDH, 0, 0, 12),
+        Instruction::new(JMPEQ, 0, 4, 0x800),
+        Instruction::new(LDB, 0, 0, 23),
+        Instruction::new(JMPEQ, 1, 0, 6),
+        Instruction::new(JMPEQ, 0, 1, 17),
+        Instruction::new(RETK, 0, 0, 0xFFFFFFFF),
+        Instruction::new(RETK, 0, 0, 0),
+      ]
+    );
+
+    let backward = "start: ldh [12]\n; loop forever\nja start\nret #0";
+    assert_eq!(
+      assemble(backward),
+      Err(AssembleError::new(3, "backward jump to `start`".to_string()))
+    );
+  }
+
+  #[test]
+  fn label_errors() {
+    let undefined = "
+      ldh [12]
+      jeq #0x800, accept, drop
+      accept: ret #-1
+      ret #0
+    ";
+    assert_eq!(
+      assemble(undefined),
+      Err(AssembleError::new(3, "undefined label `drop`".to_string()))
+    );
+    let duplicate = "ld #1\nout: ret a\nout: ret #0";
+    assert_eq!(
+      assemble(duplicate),
+      Err(AssembleError::new(3, "label `out` is already defined".to_string()))
+    );
+  }
+
+  #[test]
+  fn parse_dd_format() {
+    let src = "
+      { 0x28, 0, 0, 0x0000000c },
+      { 0x15, 0, 3, 0x00000800 },
+      { 0x30, 0, 0, 0x00000017 },
+      { 0x15, 0, 1, 0x00000006 },
+      { 0x6, 0, 0, 0x00040000 },
+      { 0x6, 0, 0, 0x00000000 },
+    ";
+    let prog = assemble(TCP_FILTER).unwrap();
+    assert_eq!(parse_dd(src), Ok(prog.clone()));
+    assert_eq!(parse_filter(src), Ok(prog.clone()));
+    assert_eq!(parse_filter(TCP_FILTER), Ok(prog));
+    assert_eq!(parse_dd("{ 0x6, 0, 0, 0 },\n{ 0x6, 0, 0 },").unwrap_err().line, 2);
+    assert!(parse_dd("{ 0x6, 256, 0, 0 }").is_err());
+  }
+}
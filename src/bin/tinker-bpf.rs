@@ -0,0 +1,43 @@
+extern crate rust_bpf;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use rust_bpf::assembler::parser::parse_filter;
+use rust_bpf::pcap::reader::{accepted_packets, PcapReader};
+use rust_bpf::vm::verifier::verify;
+
+/// Prints an error and exits.
+fn fail(path: &str, err: &dyn std::fmt::Display) -> ! {
+  eprintln!("{}: {}", path, err);
+  process::exit(1);
+}
+
+/// Runs the filter in the first file named on the command line, either assembly or `tcpdump -dd`
+/// output, over the capture in the second and prints the numbers of the packets it accepts.
+fn main() {
+  let args: Vec<String> = env::args().collect();
+  if args.len() != 3 {
+    eprintln!("usage: tinker-bpf <filter> <pcap>");
+    process::exit(1);
+  }
+  let (filter_path, pcap_path) = (&args[1], &args[2]);
+
+  let src = fs::read_to_string(filter_path).unwrap_or_else(|e| fail(filter_path, &e));
+  let prog = parse_filter(&src).unwrap_or_else(|e| fail(filter_path, &e));
+  if let Err(e) = verify(&prog) {
+    fail(filter_path, &e);
+  }
+
+  let capture = fs::read(pcap_path).unwrap_or_else(|e| fail(pcap_path, &e));
+  let reader = PcapReader::new(&capture).unwrap_or_else(|e| fail(pcap_path, &e));
+  match accepted_packets(&prog, reader) {
+    Ok(accepted) => {
+      for n in accepted {
+        println!("{}", n);
+      }
+    },
+    Err(e) => fail(pcap_path, &e),
+  }
+}
@@ -0,0 +1,75 @@
+#[cfg(feature = "std")]
+use std::error::Error;
+use std::fmt;
+
+/// Reasons a program can fail to execute.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VmError {
+  /// A packet load at `offset` ran past the end of a `len` byte packet.
+  OutOfBounds { offset: u64, len: usize },
+  /// A DIV or MOD instruction with a zero divisor.
+  DivideByZero,
+  /// An ADD, SUB or MUL whose result doesn't fit in 32 bits, under `ArithmeticMode::Checked`.
+  ArithmeticOverflow,
+  /// An opcode the machine doesn't know how to execute.
+  InvalidOpcode(u16),
+  /// The frame pointer left the program.
+  ProgramCounterOutOfRange(usize),
+  /// A scratch memory access outside of the available slots.
+  MemorySlotOutOfRange(usize),
+  /// The program executed more instructions than the configured limit.
+  InstructionLimitExceeded(u64),
+  /// A byte buffer that isn't a whole number of instructions long.
+  InvalidProgramLength(usize),
+  /// The instruction at this index jumps past the end of the program.
+  JumpOutOfRange(usize),
+  /// The program doesn't end with a RET; carries the index of its last instruction.
+  MissingReturn(usize),
+  /// The instruction at this index addresses a scratch memory slot that doesn't exist.
+  ScratchSlotOutOfRange(usize),
+  /// The program is longer than the maximum number of instructions; carries its length.
+  ProgramTooLong(usize),
+  /// The instruction at this index is an extension the kernel doesn't support.
+  UnsupportedInstruction(usize),
+  /// The instruction at this index divides by a constant zero.
+  ConstantDivideByZero(usize),
+  /// The instruction at this index shifts by a constant 32 or more.
+  ShiftOutOfRange(usize),
+  /// The instruction at this index reads a scratch memory slot that isn't stored to on every path
+  /// leading to it.
+  UninitializedScratchSlot(usize),
+}
+
+impl fmt::Display for VmError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      VmError::OutOfBounds { offset, len } => write!(
+        f,
+        "load at offset {} is out of bounds for a {} byte packet",
+        offset, len
+      ),
+      VmError::DivideByZero => write!(f, "division by zero"),
+      VmError::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+      VmError::InvalidOpcode(opcode) => write!(f, "invalid opcode {:#06x}", opcode),
+      VmError::ProgramCounterOutOfRange(frame) => write!(f, "frame pointer {} is outside of the program", frame),
+      VmError::MemorySlotOutOfRange(slot) => write!(f, "scratch memory slot {} is out of range", slot),
+      VmError::InstructionLimitExceeded(limit) => write!(f, "exceeded the limit of {} instructions", limit),
+      VmError::InvalidProgramLength(len) => write!(f, "{} bytes is not a whole number of instructions", len),
+      VmError::JumpOutOfRange(pc) => write!(f, "instruction {} jumps past the end of the program", pc),
+      VmError::MissingReturn(pc) => write!(f, "program ends at instruction {} without a return", pc),
+      VmError::ScratchSlotOutOfRange(pc) => write!(f, "instruction {} addresses a missing scratch memory slot", pc),
+      VmError::ProgramTooLong(len) => write!(f, "program of {} instructions is too long", len),
+      VmError::UnsupportedInstruction(pc) => write!(f, "instruction {} isn't supported by the kernel", pc),
+      VmError::ConstantDivideByZero(pc) => write!(f, "instruction {} divides by zero", pc),
+      VmError::ShiftOutOfRange(pc) => write!(f, "instruction {} shifts by 32 or more", pc),
+      VmError::UninitializedScratchSlot(pc) => write!(
+        f,
+        "instruction {} reads a scratch memory slot that may not have been stored to",
+        pc
+      ),
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl Error for VmError {}
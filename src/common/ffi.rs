@@ -0,0 +1,131 @@
+use std::slice;
+
+use common::error::VmError;
+use common::instruction::Instruction;
+
+/// The kernel's `struct sock_filter`, libpcap's `struct bpf_insn`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct SockFilter {
+  pub code: u16,
+  pub jt: u8,
+  pub jf: u8,
+  pub k: u32,
+}
+
+/// The kernel's `struct sock_fprog`, which `setsockopt(SO_ATTACH_FILTER)` takes.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct SockFprog {
+  pub len: u16,
+  pub filter: *mut SockFilter,
+}
+
+/// libpcap's `struct bpf_program`, as filled in by `pcap_compile`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct BpfProgram {
+  pub bf_len: u32,
+  pub bf_insns: *mut SockFilter,
+}
+
+impl From<Instruction> for SockFilter {
+  fn from(instr: Instruction) -> SockFilter {
+    SockFilter {
+      code: instr.opcode,
+      jt: instr.jt,
+      jf: instr.jf,
+      k: instr.k,
+    }
+  }
+}
+
+impl From<SockFilter> for Instruction {
+  fn from(filter: SockFilter) -> Instruction {
+    Instruction::new(filter.code, filter.jt, filter.jf, filter.k)
+  }
+}
+
+/// Returns the `(len, filter)` pair of a `struct sock_fprog` pointing at `prog`, ready to hand to
+/// `setsockopt(SO_ATTACH_FILTER)`. `Instruction` has the same layout as `struct sock_filter`, so
+/// nothing is copied; the result is only valid while `prog` is.
+/// Returns Err if `prog` is too long for the 16-bit length field.
+pub fn sock_fprog(prog: &mut [Instruction]) -> Result<SockFprog, VmError> {
+  if prog.len() > u16::MAX as usize {
+    return Err(VmError::ProgramTooLong(prog.len()));
+  }
+  Ok(SockFprog {
+    len: prog.len() as u16,
+    filter: prog.as_mut_ptr() as *mut SockFilter,
+  })
+}
+
+/// Borrows the instructions of a program handed over from C, e.g. by `pcap_compile`.
+///
+/// # Safety
+/// `bf_insns` must point at `bf_len` valid `struct bpf_insn`s that outlive the returned slice, or
+/// be null with `bf_len` zero.
+pub unsafe fn from_bpf_program<'a>(prog: &BpfProgram) -> &'a [Instruction] {
+  if prog.bf_insns.is_null() {
+    return &[];
+  }
+  slice::from_raw_parts(prog.bf_insns as *const Instruction, prog.bf_len as usize)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use common::instruction::*;
+  use std::mem;
+
+  #[test]
+  fn struct_sizes() {
+    assert_eq!(mem::size_of::<SockFilter>(), 8);
+    assert_eq!(mem::size_of::<Instruction>(), mem::size_of::<SockFilter>());
+    assert_eq!(mem::align_of::<Instruction>(), mem::align_of::<SockFilter>());
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn kernel_layout() {
+    let mut prog = vec![
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 1, 0x800),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    let expected = Instruction::program_to_sock_filter_bytes(&prog);
+    let fprog = sock_fprog(&mut prog).unwrap();
+    assert_eq!(fprog.len, 4);
+    let bytes = unsafe { slice::from_raw_parts(fprog.filter as *const u8, fprog.len as usize * 8) };
+    assert_eq!(bytes, &expected[..]);
+    // struct sock_filter { __u16 code; __u8 jt; __u8 jf; __u32 k; }
+    if cfg!(target_endian = "little") {
+      assert_eq!(&bytes[8..16], &[0x15, 0x00, 0x00, 0x01, 0x00, 0x08, 0x00, 0x00]);
+    }
+
+    let filter = SockFilter::from(prog[1]);
+    assert_eq!((filter.code, filter.jt, filter.jf, filter.k), (JMPEQ, 0, 1, 0x800));
+    assert_eq!(Instruction::from(filter), prog[1]);
+
+    let bpf = BpfProgram {
+      bf_len: fprog.len as u32,
+      bf_insns: fprog.filter,
+    };
+    assert_eq!(unsafe { from_bpf_program(&bpf) }, &prog[..]);
+    let empty = BpfProgram {
+      bf_len: 0,
+      bf_insns: ::std::ptr::null_mut(),
+    };
+    assert!(unsafe { from_bpf_program(&empty) }.is_empty());
+  }
+
+  #[test]
+  fn too_long_for_sock_fprog() {
+    let mut prog = vec![Instruction::new(RETK, 0, 0, 0); u16::MAX as usize + 1];
+    assert_eq!(
+      sock_fprog(&mut prog).err(),
+      Some(VmError::ProgramTooLong(u16::MAX as usize + 1))
+    );
+  }
+}
@@ -1,11 +1,22 @@
 #![allow(dead_code)]
+#[cfg(feature = "std")]
 extern crate byteorder;
 
+use std::fmt;
+#[cfg(feature = "std")]
 use std::io::Cursor;
 use std::mem;
 
+#[cfg(feature = "std")]
 use self::byteorder::{NativeEndian, ReadBytesExt};
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "std")]
+use assembler::disassembler::format_instruction;
+use common::error::VmError;
+
 // Class
 pub const CLASS_LD: u16 = 0x00;
 pub const CLASS_LDX: u16 = 0x01;
@@ -48,6 +59,10 @@ pub const OP_JEQ: u16 = 0x10;
 pub const OP_JGT: u16 = 0x20;
 pub const OP_JGE: u16 = 0x30;
 pub const OP_JSET: u16 = 0x40;
+/// Signed greater than. An extension, classic BPF only compares unsigned.
+pub const OP_JSGT: u16 = 0x60;
+/// Signed greater than or equal. An extension, classic BPF only compares unsigned.
+pub const OP_JSGE: u16 = 0x70;
 
 // Misc
 pub const OP_TAX: u16 = 0x00;
@@ -95,17 +110,35 @@ pub const LDBI: u16 = MODE_IND | SIZE_B | CLASS_LD;
 /// Load a byte into the accumulator from scratch memory
 pub const LDBM: u16 = MODE_MEM | SIZE_B | CLASS_LD;
 
+/// Load the packet length into the accumulator
+pub const LDL: u16 = MODE_LEN | SIZE_W | CLASS_LD;
+
 /// Load an immediate into the index register
 pub const LDXI: u16 = MODE_IMM | SIZE_W | CLASS_LDX;
 
+/// Load a word into the index register
+pub const LDXW: u16 = MODE_ABS | SIZE_W | CLASS_LDX;
+
+/// Load a word into the index register from scratch memory
+pub const LDXM: u16 = MODE_MEM | SIZE_W | CLASS_LDX;
+
+/// Load the packet length into the index register
+pub const LDXL: u16 = MODE_LEN | SIZE_W | CLASS_LDX;
+
 /// Load 4 * (pkt[k] & 0x0F) into the index register
-pub const LDXL: u16 = MODE_LEN | SIZE_W | SIZE_B | CLASS_LDX;
+pub const LDXB: u16 = MODE_MSH | SIZE_B | CLASS_LDX;
 
-/// Copy the contents of the accumulator to a scratch memory slot
-pub const ST: u16 = MODE_MEM | CLASS_ST;
+/// Copy the contents of the accumulator to a scratch memory slot.
+/// Stores have no mode bits; the kernel and libpcap reject `BPF_MEM | BPF_ST`.
+pub const ST: u16 = CLASS_ST;
 
 /// Copy the contents of the index register to a scratch memory slot
-pub const STX: u16 = MODE_MEM | CLASS_STX;
+pub const STX: u16 = CLASS_STX;
+
+/// Copy the contents of the accumulator or index register to scratch memory slot X + k.
+/// An extension, classic BPF can only store to a constant slot.
+pub const STI: u16 = MODE_IND | CLASS_ST;
+pub const STXI: u16 = MODE_IND | CLASS_STX;
 
 /// Copy the contents of the index register to the accumulator
 pub const TXA: u16 = CLASS_MISC | OP_TXA;
@@ -142,10 +175,10 @@ pub const RSHK: u16 = CLASS_ALU | SRC_K | OP_RSH;
 pub const MODK: u16 = CLASS_ALU | SRC_K | OP_MOD;
 pub const XORK: u16 = CLASS_ALU | SRC_K | OP_XOR;
 
-/// Store the bitwise negation of the accumulator's value in the accumulator
+/// Store the two's complement negation of the accumulator's value in the accumulator
 pub const NEG: u16 = CLASS_ALU | OP_NEG;
 
-/// Set the frame pointer to an immediate
+/// Unconditionally skip the next k instructions
 pub const JMP: u16 = CLASS_JMP | OP_JA;
 
 /// Conditional jumps
@@ -154,8 +187,269 @@ pub const JMPGT: u16 = CLASS_JMP | OP_JGT;
 pub const JMPGE: u16 = CLASS_JMP | OP_JGE;
 pub const JMPSET: u16 = CLASS_JMP | OP_JSET;
 
-/// A BPF psuedo-machine instruction.
+/// Conditional jumps with index register as operand
+pub const JMPEQX: u16 = CLASS_JMP | SRC_X | OP_JEQ;
+pub const JMPGTX: u16 = CLASS_JMP | SRC_X | OP_JGT;
+pub const JMPGEX: u16 = CLASS_JMP | SRC_X | OP_JGE;
+pub const JMPSETX: u16 = CLASS_JMP | SRC_X | OP_JSET;
+
+/// Signed conditional jumps, which compare the operands as i32. These are an extension beyond
+/// classic BPF, using the same operator values as eBPF.
+pub const JMPSGT: u16 = CLASS_JMP | OP_JSGT;
+pub const JMPSGE: u16 = CLASS_JMP | OP_JSGE;
+pub const JMPSGTX: u16 = CLASS_JMP | SRC_X | OP_JSGT;
+pub const JMPSGEX: u16 = CLASS_JMP | SRC_X | OP_JSGE;
+
+/// Every opcode the machine knows how to execute, decoded so a `match` over it can be checked
+/// for exhaustiveness. Each variant's value is the raw opcode of the constant with the same name.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u16)]
+pub enum Opcode {
+  Ldi = LDI,
+  Ldw = LDW,
+  Ldwi = LDWI,
+  Ldwm = LDWM,
+  Ldh = LDH,
+  Ldhi = LDHI,
+  Ldhm = LDHM,
+  Ldb = LDB,
+  Ldbi = LDBI,
+  Ldbm = LDBM,
+  Ldl = LDL,
+  Ldxi = LDXI,
+  Ldxw = LDXW,
+  Ldxm = LDXM,
+  Ldxl = LDXL,
+  Ldxb = LDXB,
+  St = ST,
+  Stx = STX,
+  Sti = STI,
+  Stxi = STXI,
+  Txa = TXA,
+  Tax = TAX,
+  Reta = RETA,
+  Retk = RETK,
+  Addx = ADDX,
+  Subx = SUBX,
+  Mulx = MULX,
+  Divx = DIVX,
+  Orx = ORX,
+  Andx = ANDX,
+  Lshx = LSHX,
+  Rshx = RSHX,
+  Modx = MODX,
+  Xorx = XORX,
+  Addk = ADDK,
+  Subk = SUBK,
+  Mulk = MULK,
+  Divk = DIVK,
+  Ork = ORK,
+  Andk = ANDK,
+  Lshk = LSHK,
+  Rshk = RSHK,
+  Modk = MODK,
+  Xork = XORK,
+  Neg = NEG,
+  Jmp = JMP,
+  Jmpeq = JMPEQ,
+  Jmpgt = JMPGT,
+  Jmpge = JMPGE,
+  Jmpset = JMPSET,
+  Jmpeqx = JMPEQX,
+  Jmpgtx = JMPGTX,
+  Jmpgex = JMPGEX,
+  Jmpsetx = JMPSETX,
+  Jmpsgt = JMPSGT,
+  Jmpsge = JMPSGE,
+  Jmpsgtx = JMPSGTX,
+  Jmpsgex = JMPSGEX,
+}
+
+impl Opcode {
+  /// Every variant, in the order they're declared.
+  pub const ALL: [Opcode; 58] = [
+    Opcode::Ldi,
+    Opcode::Ldw,
+    Opcode::Ldwi,
+    Opcode::Ldwm,
+    Opcode::Ldh,
+    Opcode::Ldhi,
+    Opcode::Ldhm,
+    Opcode::Ldb,
+    Opcode::Ldbi,
+    Opcode::Ldbm,
+    Opcode::Ldl,
+    Opcode::Ldxi,
+    Opcode::Ldxw,
+    Opcode::Ldxm,
+    Opcode::Ldxl,
+    Opcode::Ldxb,
+    Opcode::St,
+    Opcode::Stx,
+    Opcode::Sti,
+    Opcode::Stxi,
+    Opcode::Txa,
+    Opcode::Tax,
+    Opcode::Reta,
+    Opcode::Retk,
+    Opcode::Addx,
+    Opcode::Subx,
+    Opcode::Mulx,
+    Opcode::Divx,
+    Opcode::Orx,
+    Opcode::Andx,
+    Opcode::Lshx,
+    Opcode::Rshx,
+    Opcode::Modx,
+    Opcode::Xorx,
+    Opcode::Addk,
+    Opcode::Subk,
+    Opcode::Mulk,
+    Opcode::Divk,
+    Opcode::Ork,
+    Opcode::Andk,
+    Opcode::Lshk,
+    Opcode::Rshk,
+    Opcode::Modk,
+    Opcode::Xork,
+    Opcode::Neg,
+    Opcode::Jmp,
+    Opcode::Jmpeq,
+    Opcode::Jmpgt,
+    Opcode::Jmpge,
+    Opcode::Jmpset,
+    Opcode::Jmpeqx,
+    Opcode::Jmpgtx,
+    Opcode::Jmpgex,
+    Opcode::Jmpsetx,
+    Opcode::Jmpsgt,
+    Opcode::Jmpsge,
+    Opcode::Jmpsgtx,
+    Opcode::Jmpsgex,
+  ];
+
+  /// Decodes a raw opcode. Returns None if the machine doesn't know how to execute it.
+  pub fn from_raw(opcode: u16) -> Option<Opcode> {
+    let decoded = match opcode {
+      LDI => Opcode::Ldi,
+      LDW => Opcode::Ldw,
+      LDWI => Opcode::Ldwi,
+      LDWM => Opcode::Ldwm,
+      LDH => Opcode::Ldh,
+      LDHI => Opcode::Ldhi,
+      LDHM => Opcode::Ldhm,
+      LDB => Opcode::Ldb,
+      LDBI => Opcode::Ldbi,
+      LDBM => Opcode::Ldbm,
+      LDL => Opcode::Ldl,
+      LDXI => Opcode::Ldxi,
+      LDXW => Opcode::Ldxw,
+      LDXM => Opcode::Ldxm,
+      LDXL => Opcode::Ldxl,
+      LDXB => Opcode::Ldxb,
+      ST => Opcode::St,
+      STX => Opcode::Stx,
+      STI => Opcode::Sti,
+      STXI => Opcode::Stxi,
+      TXA => Opcode::Txa,
+      TAX => Opcode::Tax,
+      RETA => Opcode::Reta,
+      RETK => Opcode::Retk,
+      ADDX => Opcode::Addx,
+      SUBX => Opcode::Subx,
+      MULX => Opcode::Mulx,
+      DIVX => Opcode::Divx,
+      ORX => Opcode::Orx,
+      ANDX => Opcode::Andx,
+      LSHX => Opcode::Lshx,
+      RSHX => Opcode::Rshx,
+      MODX => Opcode::Modx,
+      XORX => Opcode::Xorx,
+      ADDK => Opcode::Addk,
+      SUBK => Opcode::Subk,
+      MULK => Opcode::Mulk,
+      DIVK => Opcode::Divk,
+      ORK => Opcode::Ork,
+      ANDK => Opcode::Andk,
+      LSHK => Opcode::Lshk,
+      RSHK => Opcode::Rshk,
+      MODK => Opcode::Modk,
+      XORK => Opcode::Xork,
+      NEG => Opcode::Neg,
+      JMP => Opcode::Jmp,
+      JMPEQ => Opcode::Jmpeq,
+      JMPGT => Opcode::Jmpgt,
+      JMPGE => Opcode::Jmpge,
+      JMPSET => Opcode::Jmpset,
+      JMPEQX => Opcode::Jmpeqx,
+      JMPGTX => Opcode::Jmpgtx,
+      JMPGEX => Opcode::Jmpgex,
+      JMPSETX => Opcode::Jmpsetx,
+      JMPSGT => Opcode::Jmpsgt,
+      JMPSGE => Opcode::Jmpsge,
+      JMPSGTX => Opcode::Jmpsgtx,
+      JMPSGEX => Opcode::Jmpsgex,
+      _ => return None,
+    };
+    Some(decoded)
+  }
+
+  /// Returns the raw opcode.
+  pub fn raw(self) -> u16 {
+    self as u16
+  }
+}
+
+/// Returns whether the machine knows how to execute `opcode`.
+pub fn is_valid_opcode(opcode: u16) -> bool {
+  Opcode::from_raw(opcode).is_some()
+}
+
+/// Where an ALU or jump instruction takes its operand from.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Source {
+  /// The immediate `k`.
+  K,
+  /// The index register.
+  X,
+}
+
+/// How many bytes a load reads, decoded from its size bits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReadSize {
+  /// A single byte, `SIZE_B`.
+  Byte,
+  /// A half-word, `SIZE_H`.
+  Half,
+  /// A word, `SIZE_W`.
+  Word,
+}
+
+impl ReadSize {
+  /// Returns the number of bytes read.
+  pub fn bytes(self) -> usize {
+    match self {
+      ReadSize::Byte => 1,
+      ReadSize::Half => 2,
+      ReadSize::Word => 4,
+    }
+  }
+
+  /// Returns the mask keeping the low `bytes()` bytes of a word.
+  pub fn mask(self) -> u32 {
+    match self {
+      ReadSize::Byte => 0xFF,
+      ReadSize::Half => 0xFFFF,
+      ReadSize::Word => 0xFFFF_FFFF,
+    }
+  }
+}
+
+/// A BPF psuedo-machine instruction. Laid out like the kernel's `struct sock_filter`.
+/// With the `serde` feature it serializes as its four raw fields.
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(C)]
 pub struct Instruction {
   /// The opcode. Layout:
   /// +--------+--------+-------------------+
@@ -174,16 +468,24 @@ pub struct Instruction {
 impl Instruction {
   /// Constructor for convenience
   pub fn new(opcode: u16, jt: u8, jf: u8, k: u32) -> Instruction {
-    Instruction {
-      opcode: opcode,
-      jt: jt,
-      jf: jf,
-      k: k,
+    Instruction { opcode, jt, jf, k }
+  }
+
+  /// Like `new`, but returns Err if `opcode` isn't a class/mode/size combination the machine
+  /// can execute. This accepts the crate's extensions to classic BPF, such as `LDXW`, `LDHM`,
+  /// `STI` and the signed jumps, which the kernel rejects; use `vm::verifier::verify_linux_compat`
+  /// to check a program only uses what the kernel supports.
+  pub fn try_new(opcode: u16, jt: u8, jf: u8, k: u32) -> Result<Instruction, VmError> {
+    if !is_valid_opcode(opcode) {
+      return Err(VmError::InvalidOpcode(opcode));
     }
+    Ok(Instruction::new(opcode, jt, jf, k))
   }
 
-  /// Decodes an instruction from a byte buffer.
+  /// Decodes an instruction from a byte buffer holding a `struct sock_filter` in host byte
+  /// order, the way the kernel and libpcap store programs.
   /// Returns None if the instruction is ilformed.
+  #[cfg(feature = "std")]
   pub fn from_bytes(buf: &[u8]) -> Option<Instruction> {
     let mut cur = Cursor::new(buf);
 
@@ -207,14 +509,59 @@ impl Instruction {
     }
     let k = k_res.unwrap();
 
+    Some(Instruction { opcode, jt, jf, k })
+  }
+
+  /// Decodes an instruction from a byte buffer.
+  /// Returns None if the instruction is ilformed.
+  #[cfg(not(feature = "std"))]
+  pub fn from_bytes(buf: &[u8]) -> Option<Instruction> {
+    if buf.len() < 8 {
+      return None;
+    }
     Some(Instruction {
-      opcode: opcode,
-      jt: jt,
-      jf: jf,
-      k: k,
+      opcode: u16::from_ne_bytes([buf[0], buf[1]]),
+      jt: buf[2],
+      jf: buf[3],
+      k: u32::from_ne_bytes([buf[4], buf[5], buf[6], buf[7]]),
     })
   }
 
+  /// Decodes a program laid out as an array of `struct sock_filter` records, as produced by
+  /// `tcpdump -dd`. Each record is 8 bytes in host byte order: a u16 opcode, u8 jt, u8 jf and
+  /// u32 k. Returns Err if `buf` isn't a whole number of records long.
+  #[cfg(feature = "std")]
+  pub fn from_sock_filter_bytes(buf: &[u8]) -> Result<Vec<Instruction>, VmError> {
+    if !buf.len().is_multiple_of(8) {
+      return Err(VmError::InvalidProgramLength(buf.len()));
+    }
+    buf
+      .chunks(8)
+      .map(Instruction::from_bytes)
+      .collect::<Option<Vec<Instruction>>>()
+      .ok_or(VmError::InvalidProgramLength(buf.len()))
+  }
+
+  /// Appends the instruction to `buf` as a `struct sock_filter` record in host byte order.
+  #[cfg(feature = "std")]
+  pub fn to_sock_filter_bytes(&self, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&self.opcode.to_ne_bytes());
+    buf.push(self.jt);
+    buf.push(self.jf);
+    buf.extend_from_slice(&self.k.to_ne_bytes());
+  }
+
+  /// Encodes a program as an array of `struct sock_filter` records, suitable for
+  /// `SO_ATTACH_FILTER`.
+  #[cfg(feature = "std")]
+  pub fn program_to_sock_filter_bytes(prog: &[Instruction]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(prog.len() * 8);
+    for instr in prog {
+      instr.to_sock_filter_bytes(&mut buf);
+    }
+    buf
+  }
+
   /// Decodes an instruction from a u64 in host byte order.
   #[inline]
   pub fn from_u64(val: u64) -> Instruction {
@@ -233,6 +580,17 @@ impl Instruction {
     self.opcode & MASK_SIZE
   }
 
+  /// Returns the size bits decoded, or None for the double word size only eBPF has.
+  #[inline]
+  pub fn read_size(&self) -> Option<ReadSize> {
+    match self.size() {
+      SIZE_B => Some(ReadSize::Byte),
+      SIZE_H => Some(ReadSize::Half),
+      SIZE_W => Some(ReadSize::Word),
+      _ => None,
+    }
+  }
+
   /// Returns the operator's addressing mode.
   #[inline]
   pub fn mode(&self) -> u16 {
@@ -251,6 +609,15 @@ impl Instruction {
     self.opcode & MASK_SRC
   }
 
+  /// Returns the source bit decoded, i.e. whether the operand is `k` or the index register.
+  #[inline]
+  pub fn source(&self) -> Source {
+    match self.src() {
+      SRC_X => Source::X,
+      _ => Source::K,
+    }
+  }
+
   /// Returns where the return value comes from.
   #[inline]
   pub fn rval(&self) -> u16 {
@@ -263,3 +630,176 @@ impl Instruction {
     self.opcode & MASK_MISCOP
   }
 }
+
+impl fmt::Debug for Instruction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "Instruction {{ opcode: {:#06x}, jt: {}, jf: {}, k: {:#x} }}",
+      self.opcode, self.jt, self.jf, self.k
+    )
+  }
+}
+
+/// Formats the instruction like the disassembler does, as if it were the first instruction of a
+/// program.
+#[cfg(feature = "std")]
+impl fmt::Display for Instruction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(&format_instruction(0, self))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn accessors() {
+    let ldh = Instruction::new(LDH, 0, 0, 12);
+    assert!(ldh.class() == CLASS_LD);
+    assert!(ldh.mode() == MODE_ABS);
+    assert!(ldh.size() == SIZE_H);
+    let ldxb = Instruction::new(LDXB, 0, 0, 14);
+    assert!(ldxb.class() == CLASS_LDX);
+    assert!(ldxb.mode() == MODE_MSH);
+    assert!(ldxb.size() == SIZE_B);
+    let ldwi = Instruction::new(LDWI, 0, 0, 0);
+    assert!(ldwi.class() == CLASS_LD);
+    assert!(ldwi.mode() == MODE_IND);
+    assert!(ldwi.size() == SIZE_W);
+    let addx = Instruction::new(ADDX, 0, 0, 0);
+    assert!(addx.class() == CLASS_ALU);
+    assert!(addx.op() == OP_ADD);
+    assert!(addx.src() == SRC_X);
+    let reta = Instruction::new(RETA, 0, 0, 0);
+    assert!(reta.class() == CLASS_RET);
+    assert!(reta.rval() == RVAL_A);
+    let txa = Instruction::new(TXA, 0, 0, 0);
+    assert!(txa.class() == CLASS_MISC);
+    assert!(txa.miscop() == OP_TXA);
+  }
+
+  #[test]
+  fn read_size() {
+    assert!(Instruction::new(LDB, 0, 0, 0).read_size() == Some(ReadSize::Byte));
+    assert!(Instruction::new(LDHI, 0, 0, 0).read_size() == Some(ReadSize::Half));
+    assert!(Instruction::new(LDWM, 0, 0, 0).read_size() == Some(ReadSize::Word));
+    assert!(Instruction::new(LDXB, 0, 0, 0).read_size() == Some(ReadSize::Byte));
+    assert!(Instruction::new(MODE_ABS | 0x18 | CLASS_LD, 0, 0, 0)
+      .read_size()
+      .is_none());
+    assert!((ReadSize::Byte.bytes(), ReadSize::Byte.mask()) == (1, 0xFF));
+    assert!((ReadSize::Half.bytes(), ReadSize::Half.mask()) == (2, 0xFFFF));
+    assert!((ReadSize::Word.bytes(), ReadSize::Word.mask()) == (4, u32::MAX));
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn from_sock_filter_bytes() {
+    // { 0x28, 0, 0, 0x0000000c }, { 0x15, 0, 1, 0x00000800 }
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&0x28u16.to_ne_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    buf.extend_from_slice(&0x0000000Cu32.to_ne_bytes());
+    buf.extend_from_slice(&0x15u16.to_ne_bytes());
+    buf.extend_from_slice(&[0, 1]);
+    buf.extend_from_slice(&0x00000800u32.to_ne_bytes());
+    let prog = Instruction::from_sock_filter_bytes(&buf).unwrap();
+    assert_eq!(
+      prog,
+      vec![Instruction::new(LDH, 0, 0, 12), Instruction::new(JMPEQ, 0, 1, 0x800)]
+    );
+    let err = Err(VmError::InvalidProgramLength(12));
+    assert!(Instruction::from_sock_filter_bytes(&buf[..12]) == err);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn sock_filter_round_trip() {
+    let mut buf = Vec::new();
+    for &(opcode, jt, jf, k) in [(0x28u16, 0u8, 0u8, 12u32), (0x15, 0, 1, 0x800), (0x06, 0, 0, 0xFFFF)].iter() {
+      buf.extend_from_slice(&opcode.to_ne_bytes());
+      buf.push(jt);
+      buf.push(jf);
+      buf.extend_from_slice(&k.to_ne_bytes());
+    }
+    let prog = Instruction::from_sock_filter_bytes(&buf).unwrap();
+    assert!(Instruction::program_to_sock_filter_bytes(&prog) == buf);
+    let mut one = Vec::new();
+    prog[2].to_sock_filter_bytes(&mut one);
+    assert!(one[..] == buf[16..]);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn debug_display() {
+    let instr = Instruction::new(JMPEQ, 0, 3, 0x800);
+    assert_eq!(
+      format!("{:?}", instr),
+      "Instruction { opcode: 0x0015, jt: 0, jf: 3, k: 0x800 }"
+    );
+    assert_eq!(instr.to_string(), "jeq      #0x800           jt 1\tjf 4");
+    assert_eq!(Instruction::new(RETK, 0, 0, 0).to_string(), "ret      #0");
+  }
+
+  #[test]
+  fn hash() {
+    use std::collections::HashSet;
+
+    let prog = [
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 1, 0x800),
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 1, 0, 0x800),
+    ];
+    let unique: HashSet<Instruction> = prog.iter().cloned().collect();
+    assert_eq!(unique.len(), 3);
+    assert!(unique.contains(&Instruction::new(JMPEQ, 1, 0, 0x800)));
+    assert!(!unique.contains(&Instruction::new(LDH, 0, 0, 14)));
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn serde_round_trip() {
+    let prog = vec![
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 1, 0x800),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    let json = ::serde_json::to_string(&prog).unwrap();
+    assert!(json.starts_with(r#"[{"opcode":40,"jt":0,"jf":0,"k":12},"#));
+    let decoded: Vec<Instruction> = ::serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, prog);
+  }
+
+  #[test]
+  fn opcode_round_trip() {
+    for &opcode in Opcode::ALL.iter() {
+      assert_eq!(Opcode::from_raw(opcode.raw()), Some(opcode));
+    }
+    assert_eq!(Opcode::from_raw(LDXB), Some(Opcode::Ldxb));
+    assert_eq!(Opcode::Jmpsetx.raw(), JMPSETX);
+    let valid = (0..=u16::MAX).filter(|&raw| Opcode::from_raw(raw).is_some()).count();
+    assert_eq!(valid, Opcode::ALL.len());
+  }
+
+  #[test]
+  fn try_new() {
+    assert_eq!(Instruction::try_new(LDH, 0, 0, 12), Ok(Instruction::new(LDH, 0, 0, 12)));
+    assert_eq!(
+      Instruction::try_new(JMPSETX, 1, 2, 0),
+      Ok(Instruction::new(JMPSETX, 1, 2, 0))
+    );
+    assert_eq!(
+      Instruction::try_new(0xFFFF, 0, 0, 0),
+      Err(VmError::InvalidOpcode(0xFFFF))
+    );
+    // LD with the MSH mode only exists for LDX.
+    assert_eq!(
+      Instruction::try_new(MODE_MSH | SIZE_B | CLASS_LD, 0, 0, 14),
+      Err(VmError::InvalidOpcode(0xB0))
+    );
+  }
+}
@@ -0,0 +1,256 @@
+#[cfg(feature = "std")]
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use common::instruction::*;
+
+/// The name of an instruction in assembly, e.g. `ldh` or `jeq`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Mnemonic {
+  Ld,
+  Ldi,
+  Ldh,
+  Ldb,
+  Ldx,
+  Ldxi,
+  Ldxb,
+  St,
+  Stx,
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Mod,
+  And,
+  Or,
+  Xor,
+  Lsh,
+  Rsh,
+  Neg,
+  Ja,
+  Jeq,
+  Jgt,
+  Jge,
+  Jset,
+  Jsgt,
+  Jsge,
+  Tax,
+  Txa,
+  Ret,
+}
+
+impl Mnemonic {
+  /// Returns the class, size and operator bits the mnemonic fixes. The addressing mode and
+  /// source bits depend on the operand, so they're left zero.
+  pub fn opcode(self) -> u16 {
+    match self {
+      Mnemonic::Ld => CLASS_LD | SIZE_W,
+      Mnemonic::Ldi => LDI,
+      Mnemonic::Ldh => CLASS_LD | SIZE_H,
+      Mnemonic::Ldb => CLASS_LD | SIZE_B,
+      Mnemonic::Ldx => CLASS_LDX | SIZE_W,
+      Mnemonic::Ldxi => LDXI,
+      Mnemonic::Ldxb => LDXB,
+      Mnemonic::St => ST,
+      Mnemonic::Stx => STX,
+      Mnemonic::Add => CLASS_ALU | OP_ADD,
+      Mnemonic::Sub => CLASS_ALU | OP_SUB,
+      Mnemonic::Mul => CLASS_ALU | OP_MUL,
+      Mnemonic::Div => CLASS_ALU | OP_DIV,
+      Mnemonic::Mod => CLASS_ALU | OP_MOD,
+      Mnemonic::And => CLASS_ALU | OP_AND,
+      Mnemonic::Or => CLASS_ALU | OP_OR,
+      Mnemonic::Xor => CLASS_ALU | OP_XOR,
+      Mnemonic::Lsh => CLASS_ALU | OP_LSH,
+      Mnemonic::Rsh => CLASS_ALU | OP_RSH,
+      Mnemonic::Neg => NEG,
+      Mnemonic::Ja => JMP,
+      Mnemonic::Jeq => JMPEQ,
+      Mnemonic::Jgt => JMPGT,
+      Mnemonic::Jge => JMPGE,
+      Mnemonic::Jset => JMPSET,
+      Mnemonic::Jsgt => JMPSGT,
+      Mnemonic::Jsge => JMPSGE,
+      Mnemonic::Tax => TAX,
+      Mnemonic::Txa => TXA,
+      Mnemonic::Ret => CLASS_RET,
+    }
+  }
+
+  /// Returns whether the operand can be either an immediate or the index register, e.g. `add #1`
+  /// and `add x`.
+  pub fn takes_source(self) -> bool {
+    match self.opcode() & MASK_CLASS {
+      CLASS_ALU => self != Mnemonic::Neg,
+      CLASS_JMP => self != Mnemonic::Ja,
+      _ => false,
+    }
+  }
+  /// Returns the name the assembler and disassembler use for the mnemonic.
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Mnemonic::Ld => "ld",
+      Mnemonic::Ldi => "ldi",
+      Mnemonic::Ldh => "ldh",
+      Mnemonic::Ldb => "ldb",
+      Mnemonic::Ldx => "ldx",
+      Mnemonic::Ldxi => "ldxi",
+      Mnemonic::Ldxb => "ldxb",
+      Mnemonic::St => "st",
+      Mnemonic::Stx => "stx",
+      Mnemonic::Add => "add",
+      Mnemonic::Sub => "sub",
+      Mnemonic::Mul => "mul",
+      Mnemonic::Div => "div",
+      Mnemonic::Mod => "mod",
+      Mnemonic::And => "and",
+      Mnemonic::Or => "or",
+      Mnemonic::Xor => "xor",
+      Mnemonic::Lsh => "lsh",
+      Mnemonic::Rsh => "rsh",
+      Mnemonic::Neg => "neg",
+      Mnemonic::Ja => "ja",
+      Mnemonic::Jeq => "jeq",
+      Mnemonic::Jgt => "jgt",
+      Mnemonic::Jge => "jge",
+      Mnemonic::Jset => "jset",
+      Mnemonic::Jsgt => "jsgt",
+      Mnemonic::Jsge => "jsge",
+      Mnemonic::Tax => "tax",
+      Mnemonic::Txa => "txa",
+      Mnemonic::Ret => "ret",
+    }
+  }
+}
+
+impl From<Opcode> for Mnemonic {
+  /// Returns the mnemonic the disassembler prints for `opcode`. Immediate loads come out as `ld`
+  /// and `ldx`, like `tcpdump -d` prints them.
+  fn from(opcode: Opcode) -> Mnemonic {
+    match opcode {
+      Opcode::Ldi | Opcode::Ldw | Opcode::Ldwi | Opcode::Ldwm | Opcode::Ldl => Mnemonic::Ld,
+      Opcode::Ldh | Opcode::Ldhi | Opcode::Ldhm => Mnemonic::Ldh,
+      Opcode::Ldb | Opcode::Ldbi | Opcode::Ldbm => Mnemonic::Ldb,
+      Opcode::Ldxi | Opcode::Ldxw | Opcode::Ldxm | Opcode::Ldxl => Mnemonic::Ldx,
+      Opcode::Ldxb => Mnemonic::Ldxb,
+      Opcode::St | Opcode::Sti => Mnemonic::St,
+      Opcode::Stx | Opcode::Stxi => Mnemonic::Stx,
+      Opcode::Txa => Mnemonic::Txa,
+      Opcode::Tax => Mnemonic::Tax,
+      Opcode::Reta | Opcode::Retk => Mnemonic::Ret,
+      Opcode::Addx | Opcode::Addk => Mnemonic::Add,
+      Opcode::Subx | Opcode::Subk => Mnemonic::Sub,
+      Opcode::Mulx | Opcode::Mulk => Mnemonic::Mul,
+      Opcode::Divx | Opcode::Divk => Mnemonic::Div,
+      Opcode::Orx | Opcode::Ork => Mnemonic::Or,
+      Opcode::Andx | Opcode::Andk => Mnemonic::And,
+      Opcode::Lshx | Opcode::Lshk => Mnemonic::Lsh,
+      Opcode::Rshx | Opcode::Rshk => Mnemonic::Rsh,
+      Opcode::Modx | Opcode::Modk => Mnemonic::Mod,
+      Opcode::Xorx | Opcode::Xork => Mnemonic::Xor,
+      Opcode::Neg => Mnemonic::Neg,
+      Opcode::Jmp => Mnemonic::Ja,
+      Opcode::Jmpeq | Opcode::Jmpeqx => Mnemonic::Jeq,
+      Opcode::Jmpgt | Opcode::Jmpgtx => Mnemonic::Jgt,
+      Opcode::Jmpge | Opcode::Jmpgex => Mnemonic::Jge,
+      Opcode::Jmpset | Opcode::Jmpsetx => Mnemonic::Jset,
+      Opcode::Jmpsgt | Opcode::Jmpsgtx => Mnemonic::Jsgt,
+      Opcode::Jmpsge | Opcode::Jmpsgex => Mnemonic::Jsge,
+    }
+  }
+}
+
+impl fmt::Display for Mnemonic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.pad(self.as_str())
+  }
+}
+
+/// The error returned when parsing a string that isn't a known mnemonic.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UnknownMnemonic;
+
+impl fmt::Display for UnknownMnemonic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "unknown mnemonic")
+  }
+}
+
+#[cfg(feature = "std")]
+impl Error for UnknownMnemonic {}
+
+impl FromStr for Mnemonic {
+  type Err = UnknownMnemonic;
+
+  fn from_str(s: &str) -> Result<Mnemonic, UnknownMnemonic> {
+    let mnemonic = match s {
+      "ld" => Mnemonic::Ld,
+      "ldi" => Mnemonic::Ldi,
+      "ldh" => Mnemonic::Ldh,
+      "ldb" => Mnemonic::Ldb,
+      "ldx" => Mnemonic::Ldx,
+      "ldxi" => Mnemonic::Ldxi,
+      "ldxb" => Mnemonic::Ldxb,
+      "st" => Mnemonic::St,
+      "stx" => Mnemonic::Stx,
+      "add" => Mnemonic::Add,
+      "sub" => Mnemonic::Sub,
+      "mul" => Mnemonic::Mul,
+      "div" => Mnemonic::Div,
+      "mod" => Mnemonic::Mod,
+      "and" => Mnemonic::And,
+      "or" => Mnemonic::Or,
+      "xor" => Mnemonic::Xor,
+      "lsh" => Mnemonic::Lsh,
+      "rsh" => Mnemonic::Rsh,
+      "neg" => Mnemonic::Neg,
+      "ja" | "jmp" => Mnemonic::Ja,
+      "jeq" => Mnemonic::Jeq,
+      "jgt" => Mnemonic::Jgt,
+      "jge" => Mnemonic::Jge,
+      "jset" => Mnemonic::Jset,
+      "jsgt" => Mnemonic::Jsgt,
+      "jsge" => Mnemonic::Jsge,
+      "tax" => Mnemonic::Tax,
+      "txa" => Mnemonic::Txa,
+      "ret" => Mnemonic::Ret,
+      _ => return Err(UnknownMnemonic),
+    };
+    Ok(mnemonic)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_mnemonics() {
+    assert_eq!("ld".parse(), Ok(Mnemonic::Ld));
+    assert_eq!("jmp".parse(), Ok(Mnemonic::Ja));
+    assert_eq!("ld".parse::<Mnemonic>().unwrap().opcode(), 0x00);
+    assert_eq!("ldh".parse::<Mnemonic>().unwrap().opcode(), 0x08);
+    assert_eq!("ldb".parse::<Mnemonic>().unwrap().opcode() | MODE_ABS, LDB);
+    assert_eq!("jeq".parse::<Mnemonic>().unwrap().opcode(), 0x15);
+    assert_eq!("ret".parse::<Mnemonic>().unwrap().opcode(), 0x06);
+    assert_eq!("txa".parse::<Mnemonic>().unwrap().opcode(), 0x87);
+    assert!(Mnemonic::Jset.takes_source());
+    assert!(!Mnemonic::Neg.takes_source());
+    assert_eq!("ldw".parse::<Mnemonic>(), Err(UnknownMnemonic));
+    assert_eq!("LD".parse::<Mnemonic>(), Err(UnknownMnemonic));
+  }
+
+  #[test]
+  fn from_opcode() {
+    assert_eq!(Mnemonic::from(Opcode::Ldi), Mnemonic::Ld);
+    assert_eq!(Mnemonic::from(Opcode::Ldxb), Mnemonic::Ldxb);
+    assert_eq!(Mnemonic::from(Opcode::Jmpsetx), Mnemonic::Jset);
+    assert_eq!(Mnemonic::from(Opcode::Retk).as_str(), "ret");
+    for &opcode in Opcode::ALL.iter() {
+      let mnemonic = Mnemonic::from(opcode);
+      assert_eq!(mnemonic.as_str().parse(), Ok(mnemonic));
+      assert_eq!(mnemonic.opcode() & MASK_CLASS, opcode.raw() & MASK_CLASS);
+    }
+  }
+}
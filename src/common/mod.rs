@@ -1 +1,4 @@
+pub mod error;
+pub mod ffi;
 pub mod instruction;
+pub mod mnemonic;
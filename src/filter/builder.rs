@@ -0,0 +1,230 @@
+use common::error::VmError;
+use common::instruction::*;
+use filter::headers::*;
+use vm::verifier::verify;
+
+/// Snapshot length accepted packets are truncated to by default, same as tcpdump.
+pub const DEFAULT_SNAPLEN: u32 = 262144;
+
+/// Which branch of a conditional jump leaves the filter.
+#[derive(Clone, Copy)]
+enum Branch {
+  True,
+  False,
+}
+
+/// Builds a filter that accepts Ethernet frames matching every added condition and drops
+/// everything else.
+///
+/// The IP address and protocol conditions assume an IPv4 packet, so they should follow
+/// `ether_type(0x0800)`. The TCP port conditions check for IPv4 and TCP themselves.
+pub struct FilterBuilder {
+  /// The checks added so far, without the trailing RETs.
+  prog: Vec<Instruction>,
+  /// Jumps that need to be pointed at the drop RET once the program length is known.
+  drops: Vec<(usize, Branch)>,
+  /// How many bytes of an accepted packet to keep.
+  snaplen: u32,
+}
+
+impl Default for FilterBuilder {
+  fn default() -> FilterBuilder {
+    FilterBuilder::new()
+  }
+}
+
+impl FilterBuilder {
+  /// Returns a builder for a filter that accepts every packet.
+  pub fn new() -> FilterBuilder {
+    FilterBuilder {
+      prog: Vec::new(),
+      drops: Vec::new(),
+      snaplen: DEFAULT_SNAPLEN,
+    }
+  }
+
+  /// Sets how many bytes of an accepted packet to keep.
+  pub fn snaplen(mut self, snaplen: u32) -> FilterBuilder {
+    self.snaplen = snaplen;
+    self
+  }
+
+  /// Only accept frames with this EtherType.
+  pub fn ether_type(self, ether_type: u16) -> FilterBuilder {
+    self
+      .load(Instruction::new(LDH, 0, 0, ETHERTYPE_OFFSET))
+      .expect(ether_type as u32)
+  }
+
+  /// Only accept IPv4 packets carrying this protocol.
+  pub fn ip_protocol(self, protocol: u8) -> FilterBuilder {
+    self
+      .load(Instruction::new(LDB, 0, 0, IP_PROTO_OFFSET))
+      .expect(protocol as u32)
+  }
+
+  /// Only accept IPv4 packets from this address.
+  pub fn ip_src(self, addr: u32) -> FilterBuilder {
+    self.load(Instruction::new(LDW, 0, 0, IP_SRC_OFFSET)).expect(addr)
+  }
+
+  /// Only accept IPv4 packets to this address.
+  pub fn ip_dst(self, addr: u32) -> FilterBuilder {
+    self.load(Instruction::new(LDW, 0, 0, IP_DST_OFFSET)).expect(addr)
+  }
+
+  /// Only accept unfragmented, or first fragment, IPv4 TCP segments from this port.
+  pub fn tcp_src_port(self, port: u16) -> FilterBuilder {
+    self.transport_port(SRC_PORT_OFFSET, port)
+  }
+
+  /// Only accept unfragmented, or first fragment, IPv4 TCP segments to this port.
+  pub fn tcp_dst_port(self, port: u16) -> FilterBuilder {
+    self.transport_port(DST_PORT_OFFSET, port)
+  }
+
+  /// Returns the finished program, checked by the verifier.
+  /// Returns Err if the program grew too long to jump over with the 8-bit jump offsets.
+  pub fn build(self) -> Result<Vec<Instruction>, VmError> {
+    let mut prog = self.prog;
+    prog.push(Instruction::new(RETK, 0, 0, self.snaplen));
+    prog.push(Instruction::new(RETK, 0, 0, 0));
+    let drop = prog.len() - 1;
+    for (pc, branch) in self.drops {
+      let offset = drop - pc - 1;
+      if offset > u8::MAX as usize {
+        return Err(VmError::JumpOutOfRange(pc));
+      }
+      match branch {
+        Branch::True => prog[pc].jt = offset as u8,
+        Branch::False => prog[pc].jf = offset as u8,
+      }
+    }
+    verify(&prog)?;
+    Ok(prog)
+  }
+
+  /// Appends `instr` as is.
+  fn load(mut self, instr: Instruction) -> FilterBuilder {
+    self.prog.push(instr);
+    self
+  }
+
+  /// Drops the packet unless the accumulator equals `k`.
+  fn expect(mut self, k: u32) -> FilterBuilder {
+    self.drops.push((self.prog.len(), Branch::False));
+    self.prog.push(Instruction::new(JMPEQ, 0, 0, k));
+    self
+  }
+
+  /// Drops anything but IPv4 TCP segments, then non-first fragments, then the packet unless the
+  /// half-word at `offset` into the TCP header equals `port`.
+  fn transport_port(self, offset: u32, port: u16) -> FilterBuilder {
+    let mut this = self.ether_type(ETHERTYPE_IP).ip_protocol(IPPROTO_TCP);
+    this.prog.push(Instruction::new(LDH, 0, 0, IP_FRAG_OFFSET));
+    this.drops.push((this.prog.len(), Branch::True));
+    this.prog.push(Instruction::new(JMPSET, 0, 0, IP_FRAG_MASK));
+    this.prog.push(ip_header_len());
+    this.load(transport_half(offset)).expect(port as u32)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use vm::machine::{FilterResult, PsuedoMachine};
+
+  /// Returns an Ethernet frame holding an IPv4 TCP segment from 10.0.0.1:1234 to 10.0.0.2:80.
+  fn tcp_packet() -> Vec<u8> {
+    let mut pkt = vec![0u8; 54];
+    pkt[12] = 0x08;
+    pkt[14] = 0x45;
+    pkt[23] = 6;
+    pkt[26..30].copy_from_slice(&[10, 0, 0, 1]);
+    pkt[30..34].copy_from_slice(&[10, 0, 0, 2]);
+    pkt[34..36].copy_from_slice(&1234u16.to_be_bytes());
+    pkt[36..38].copy_from_slice(&80u16.to_be_bytes());
+    pkt
+  }
+
+  fn run(prog: &[Instruction], pkt: &[u8]) -> FilterResult {
+    PsuedoMachine::new().run_filter(prog, pkt).unwrap()
+  }
+
+  #[test]
+  fn tcp_dst_port() {
+    let prog = FilterBuilder::new()
+      .ether_type(0x0800)
+      .ip_protocol(6)
+      .tcp_dst_port(80)
+      .build()
+      .unwrap();
+    let mut pkt = tcp_packet();
+    assert_eq!(run(&prog, &pkt), FilterResult::Accept(DEFAULT_SNAPLEN));
+
+    pkt[37] = 81;
+    assert_eq!(run(&prog, &pkt), FilterResult::Drop);
+
+    let mut pkt = tcp_packet();
+    pkt[20] = 0x20;
+    pkt[21] = 0x01;
+    assert_eq!(run(&prog, &pkt), FilterResult::Drop);
+
+    let mut pkt = tcp_packet();
+    pkt[12] = 0x86;
+    pkt[13] = 0xDD;
+    assert_eq!(run(&prog, &pkt), FilterResult::Drop);
+  }
+
+  #[test]
+  fn tcp_port_checks_the_protocol() {
+    let prog = FilterBuilder::new().tcp_dst_port(80).build().unwrap();
+    let mut pkt = tcp_packet();
+    assert_eq!(run(&prog, &pkt), FilterResult::Accept(DEFAULT_SNAPLEN));
+
+    pkt[23] = IPPROTO_UDP;
+    assert_eq!(run(&prog, &pkt), FilterResult::Drop);
+
+    let mut pkt = tcp_packet();
+    pkt[12] = 0x86;
+    pkt[13] = 0xDD;
+    assert_eq!(run(&prog, &pkt), FilterResult::Drop);
+  }
+
+  #[test]
+  fn ip_addrs_and_options() {
+    let prog = FilterBuilder::new()
+      .snaplen(96)
+      .ether_type(0x0800)
+      .ip_src(0x0A000001)
+      .ip_dst(0x0A000002)
+      .tcp_src_port(1234)
+      .build()
+      .unwrap();
+    let mut pkt = tcp_packet();
+    assert_eq!(run(&prog, &pkt), FilterResult::Accept(96));
+
+    // Four bytes of IP options move the TCP header along.
+    pkt[14] = 0x46;
+    pkt.insert(34, 0);
+    pkt.insert(34, 0);
+    pkt.insert(34, 0);
+    pkt.insert(34, 0);
+    assert_eq!(run(&prog, &pkt), FilterResult::Accept(96));
+
+    pkt[30] = 192;
+    assert_eq!(run(&prog, &pkt), FilterResult::Drop);
+  }
+
+  #[test]
+  fn empty_filter_accepts_everything() {
+    let prog = FilterBuilder::new().build().unwrap();
+    assert_eq!(
+      prog,
+      vec![
+        Instruction::new(RETK, 0, 0, DEFAULT_SNAPLEN),
+        Instruction::new(RETK, 0, 0, 0)
+      ]
+    );
+  }
+}
@@ -0,0 +1,90 @@
+use common::instruction::*;
+
+/// Length of an Ethernet header without VLAN tags.
+pub const ETHER_HEADER_LEN: u32 = 14;
+/// Offset of the EtherType in an Ethernet frame.
+pub const ETHERTYPE_OFFSET: u32 = 12;
+
+/// EtherType of IPv4.
+pub const ETHERTYPE_IP: u16 = 0x0800;
+/// EtherType of ARP.
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+/// EtherType of IPv6.
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+/// Offset of the IPv4 flags and fragment offset half-word in an Ethernet frame.
+pub const IP_FRAG_OFFSET: u32 = ETHER_HEADER_LEN + 6;
+/// Mask of the fragment offset bits in the IPv4 flags and fragment offset half-word.
+pub const IP_FRAG_MASK: u32 = 0x1FFF;
+/// Offset of the IPv4 protocol in an Ethernet frame.
+pub const IP_PROTO_OFFSET: u32 = ETHER_HEADER_LEN + 9;
+/// Offset of the IPv4 source address in an Ethernet frame.
+pub const IP_SRC_OFFSET: u32 = ETHER_HEADER_LEN + 12;
+/// Offset of the IPv4 destination address in an Ethernet frame.
+pub const IP_DST_OFFSET: u32 = ETHER_HEADER_LEN + 16;
+
+/// IP protocol number of ICMP.
+pub const IPPROTO_ICMP: u8 = 1;
+/// IP protocol number of TCP.
+pub const IPPROTO_TCP: u8 = 6;
+/// IP protocol number of UDP.
+pub const IPPROTO_UDP: u8 = 17;
+
+/// Offset of the source port in a TCP or UDP header.
+pub const SRC_PORT_OFFSET: u32 = 0;
+/// Offset of the destination port in a TCP or UDP header.
+pub const DST_PORT_OFFSET: u32 = 2;
+
+/// Returns the instruction that loads the length of an Ethernet frame's IPv4 header into X, so
+/// the transport header starts at `ETHER_HEADER_LEN + X`.
+pub fn ip_header_len() -> Instruction {
+  Instruction::new(LDXB, 0, 0, ETHER_HEADER_LEN)
+}
+
+/// Returns the instruction that loads the half-word `offset` bytes into the transport header,
+/// given `ip_header_len()` has already run.
+pub fn transport_half(offset: u32) -> Instruction {
+  Instruction::new(LDHI, 0, 0, ETHER_HEADER_LEN + offset)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use vm::machine::PsuedoMachine;
+
+  #[test]
+  fn locate_tcp_header() {
+    // Ethernet, then IPv4 with 8 bytes of options, then TCP from port 1234 to 80.
+    let mut pkt = [0u8; 62];
+    pkt[12] = 0x08;
+    pkt[14] = 0x47;
+    pkt[23] = IPPROTO_TCP;
+    pkt[42..44].copy_from_slice(&1234u16.to_be_bytes());
+    pkt[44..46].copy_from_slice(&80u16.to_be_bytes());
+    let prog = [
+      Instruction::new(LDH, 0, 0, ETHERTYPE_OFFSET),
+      Instruction::new(JMPEQ, 0, 7, ETHERTYPE_IP as u32),
+      Instruction::new(LDB, 0, 0, IP_PROTO_OFFSET),
+      Instruction::new(JMPEQ, 0, 5, IPPROTO_TCP as u32),
+      ip_header_len(),
+      transport_half(SRC_PORT_OFFSET),
+      Instruction::new(JMPEQ, 0, 2, 1234),
+      transport_half(DST_PORT_OFFSET),
+      Instruction::new(RETA, 0, 0, 0),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    let mut pm = PsuedoMachine::new();
+    assert_eq!(pm.run_program(&prog, &pkt), Ok(80));
+    let mut udp = pkt;
+    udp[23] = IPPROTO_UDP;
+    pm.reset();
+    assert_eq!(pm.run_program(&prog, &udp), Ok(0));
+    let mut wrong_port = pkt;
+    wrong_port[42..44].copy_from_slice(&4321u16.to_be_bytes());
+    pm.reset();
+    assert_eq!(pm.run_program(&prog, &wrong_port), Ok(0));
+    pm.reset();
+    pm.execute(&ip_header_len(), &pkt).unwrap();
+    assert_eq!(pm.snapshot().index, 28);
+  }
+}
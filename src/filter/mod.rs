@@ -0,0 +1,3 @@
+#[cfg(feature = "std")]
+pub mod builder;
+pub mod headers;
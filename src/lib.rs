@@ -1 +1,31 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(not(any(feature = "std", test)))]
+extern crate core as std;
+
+#[cfg(feature = "std")]
+#[macro_use]
+extern crate lazy_static;
+#[cfg(feature = "socket")]
+extern crate libc;
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+#[cfg(test)]
+extern crate proptest;
+#[cfg(feature = "std")]
+extern crate regex;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
+#[cfg(feature = "std")]
+pub mod assembler;
 pub mod common;
+pub mod filter;
+#[cfg(feature = "pcap")]
+pub mod pcap;
+#[cfg(all(feature = "socket", target_os = "linux"))]
+pub mod socket;
+pub mod vm;
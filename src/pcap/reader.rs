@@ -0,0 +1,266 @@
+use std::error::Error;
+use std::fmt;
+
+use common::instruction::Instruction;
+use vm::machine::{FilterResult, PsuedoMachine};
+
+/// Magic number of a pcap file with microsecond timestamps.
+pub const MAGIC_MICROS: u32 = 0xA1B2C3D4;
+/// Magic number of a pcap file with nanosecond timestamps.
+pub const MAGIC_NANOS: u32 = 0xA1B23C4D;
+
+/// Length of the file header.
+const FILE_HEADER_LEN: usize = 24;
+/// Length of the header in front of each packet.
+const RECORD_HEADER_LEN: usize = 16;
+
+/// Reasons a pcap file can fail to parse.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PcapError {
+  /// The file doesn't start with a pcap magic number, in either byte order.
+  BadMagic(u32),
+  /// The file ends in the middle of the header or record starting at this offset.
+  Truncated(usize),
+}
+
+impl fmt::Display for PcapError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      PcapError::BadMagic(magic) => write!(f, "bad pcap magic number {:#010x}", magic),
+      PcapError::Truncated(offset) => write!(f, "pcap file truncated in record at offset {}", offset),
+    }
+  }
+}
+
+impl Error for PcapError {}
+
+/// The fields of a pcap file header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileHeader {
+  /// Whether the file was written on a big endian host.
+  pub big_endian: bool,
+  /// Whether timestamps have nanosecond, rather than microsecond, resolution.
+  pub nanos: bool,
+  /// Major version of the file format.
+  pub version_major: u16,
+  /// Minor version of the file format.
+  pub version_minor: u16,
+  /// The most bytes of any packet the file holds.
+  pub snaplen: u32,
+  /// The link layer header type, e.g. 1 for Ethernet.
+  pub linktype: u32,
+}
+
+/// A captured packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Record<'a> {
+  /// Seconds since the epoch.
+  pub ts_sec: u32,
+  /// Microseconds or nanoseconds past `ts_sec`, depending on the file header.
+  pub ts_frac: u32,
+  /// Length of the packet on the wire.
+  pub orig_len: u32,
+  /// The captured bytes, at most the file's snaplen.
+  pub data: &'a [u8],
+}
+
+/// Reads the packets out of an in-memory pcap file.
+pub struct PcapReader<'a> {
+  buf: &'a [u8],
+  pos: usize,
+  header: FileHeader,
+}
+
+impl<'a> PcapReader<'a> {
+  /// Parses the file header.
+  /// Returns Err if `buf` doesn't start with a pcap file header.
+  pub fn new(buf: &'a [u8]) -> Result<PcapReader<'a>, PcapError> {
+    if buf.len() < FILE_HEADER_LEN {
+      return Err(PcapError::Truncated(0));
+    }
+    let le = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let (big_endian, nanos) = match (le, le.swap_bytes()) {
+      (MAGIC_MICROS, _) => (false, false),
+      (MAGIC_NANOS, _) => (false, true),
+      (_, MAGIC_MICROS) => (true, false),
+      (_, MAGIC_NANOS) => (true, true),
+      _ => return Err(PcapError::BadMagic(le)),
+    };
+    let mut reader = PcapReader {
+      buf,
+      pos: FILE_HEADER_LEN,
+      header: FileHeader {
+        big_endian,
+        nanos,
+        version_major: 0,
+        version_minor: 0,
+        snaplen: 0,
+        linktype: 0,
+      },
+    };
+    reader.header.version_major = reader.u16_at(4);
+    reader.header.version_minor = reader.u16_at(6);
+    reader.header.snaplen = reader.u32_at(16);
+    reader.header.linktype = reader.u32_at(20);
+    Ok(reader)
+  }
+
+  /// Returns the file header.
+  pub fn header(&self) -> &FileHeader {
+    &self.header
+  }
+
+  /// Reads the half-word at `offset` in the file's byte order.
+  fn u16_at(&self, offset: usize) -> u16 {
+    let bytes = [self.buf[offset], self.buf[offset + 1]];
+    if self.header.big_endian {
+      u16::from_be_bytes(bytes)
+    } else {
+      u16::from_le_bytes(bytes)
+    }
+  }
+
+  /// Reads the word at `offset` in the file's byte order.
+  fn u32_at(&self, offset: usize) -> u32 {
+    let bytes = [
+      self.buf[offset],
+      self.buf[offset + 1],
+      self.buf[offset + 2],
+      self.buf[offset + 3],
+    ];
+    if self.header.big_endian {
+      u32::from_be_bytes(bytes)
+    } else {
+      u32::from_le_bytes(bytes)
+    }
+  }
+}
+
+impl<'a> Iterator for PcapReader<'a> {
+  type Item = Result<Record<'a>, PcapError>;
+
+  fn next(&mut self) -> Option<Result<Record<'a>, PcapError>> {
+    let start = self.pos;
+    if start == self.buf.len() {
+      return None;
+    }
+    if self.buf.len() - start < RECORD_HEADER_LEN {
+      self.pos = self.buf.len();
+      return Some(Err(PcapError::Truncated(start)));
+    }
+    let incl_len = self.u32_at(start + 8) as usize;
+    let data_start = start + RECORD_HEADER_LEN;
+    if self.buf.len() - data_start < incl_len {
+      self.pos = self.buf.len();
+      return Some(Err(PcapError::Truncated(start)));
+    }
+    self.pos = data_start + incl_len;
+    let len = incl_len.min(self.header.snaplen as usize);
+    Some(Ok(Record {
+      ts_sec: self.u32_at(start),
+      ts_frac: self.u32_at(start + 4),
+      orig_len: self.u32_at(start + 12),
+      data: &self.buf[data_start..data_start + len],
+    }))
+  }
+}
+
+/// Runs `prog` over every packet in `reader`.
+/// Returns the 1-based numbers of the packets it accepts, counting a packet the program fails on
+/// as dropped like the kernel does, or Err if the file is malformed.
+pub fn accepted_packets(prog: &[Instruction], reader: PcapReader) -> Result<Vec<usize>, PcapError> {
+  let mut accepted = Vec::new();
+  for (n, record) in reader.enumerate() {
+    if let Ok(FilterResult::Accept(_)) = PsuedoMachine::new().run_filter(prog, record?.data) {
+      accepted.push(n + 1);
+    }
+  }
+  Ok(accepted)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use filter::builder::FilterBuilder;
+
+  /// A little endian capture of three 16 byte Ethernet frames: IPv4, ARP and IPv4 again.
+  const CAPTURE: [u8; 120] = [
+    // File header: magic, version 2.4, zone, sigfigs, snaplen 65535, Ethernet.
+    0xD4, 0xC3, 0xB2, 0xA1, 0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0x00,
+    0x00, 0x01, 0x00, 0x00, 0x00, //
+    // Record 1 at t = 1.5s.
+    0x01, 0x00, 0x00, 0x00, 0x20, 0xA1, 0x07, 0x00, 0x10, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, //
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x08, 0x00, 0x45, 0x00, //
+    // Record 2.
+    0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, //
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x08, 0x06, 0x00, 0x01, //
+    // Record 3, captured from a 60 byte frame.
+    0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x00, 0x00, //
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x08, 0x00, 0x45, 0x00, //
+  ];
+
+  #[test]
+  fn filter_capture() {
+    let reader = PcapReader::new(&CAPTURE).unwrap();
+    assert_eq!(
+      *reader.header(),
+      FileHeader {
+        big_endian: false,
+        nanos: false,
+        version_major: 2,
+        version_minor: 4,
+        snaplen: 65535,
+        linktype: 1,
+      }
+    );
+    let prog = FilterBuilder::new().ether_type(0x0800).build().unwrap();
+    let records: Vec<Record> = reader.map(|record| record.unwrap()).collect();
+    assert_eq!(records.len(), 3);
+    assert_eq!((records[0].ts_sec, records[0].ts_frac), (1, 500000));
+    assert_eq!(records[2].orig_len, 60);
+    let accepted: Vec<u32> = records
+      .iter()
+      .filter(|record| PsuedoMachine::new().run_filter(&prog, record.data) != Ok(FilterResult::Drop))
+      .map(|record| record.ts_sec)
+      .collect();
+    assert_eq!(accepted, vec![1, 3]);
+    assert_eq!(
+      accepted_packets(&prog, PcapReader::new(&CAPTURE).unwrap()),
+      Ok(vec![1, 3])
+    );
+  }
+
+  #[test]
+  fn big_endian_and_snaplen() {
+    let mut capture = CAPTURE.to_vec();
+    // Swap every header field to big endian and shrink the snaplen to 14.
+    for &(offset, len) in [(0, 4), (4, 2), (6, 2), (16, 4), (20, 4)].iter() {
+      capture[offset..offset + len].reverse();
+    }
+    capture[16..20].copy_from_slice(&14u32.to_be_bytes());
+    for record in 0..3 {
+      let start = 24 + record * 32;
+      for field in 0..4 {
+        capture[start + field * 4..start + field * 4 + 4].reverse();
+      }
+    }
+    let reader = PcapReader::new(&capture).unwrap();
+    assert!(reader.header().big_endian);
+    assert_eq!(reader.header().snaplen, 14);
+    let records: Vec<Record> = reader.map(|record| record.unwrap()).collect();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[1].data, &CAPTURE[72..86]);
+    assert_eq!(records[2].orig_len, 60);
+  }
+
+  #[test]
+  fn errors() {
+    assert_eq!(PcapReader::new(&CAPTURE[..10]).err(), Some(PcapError::Truncated(0)));
+    assert_eq!(PcapReader::new(&[0u8; 24]).err(), Some(PcapError::BadMagic(0)));
+    let mut reader = PcapReader::new(&CAPTURE[..100]).unwrap();
+    assert!(reader.next().unwrap().is_ok());
+    assert!(reader.next().unwrap().is_ok());
+    assert_eq!(reader.next(), Some(Err(PcapError::Truncated(88))));
+    assert_eq!(reader.next(), None);
+  }
+}
@@ -0,0 +1,43 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use libc::{c_int, c_void, socklen_t};
+
+use common::ffi::{sock_fprog, SockFprog};
+use common::instruction::Instruction;
+
+/// Attaches `prog` to the socket `fd` with `setsockopt(SO_ATTACH_FILTER)`, replacing any filter
+/// already attached. The kernel keeps its own copy, so `prog` can be dropped afterwards.
+/// Returns Err if the program is too long for a `struct sock_fprog` or the kernel rejects it.
+pub fn attach_filter(fd: RawFd, prog: &[Instruction]) -> io::Result<()> {
+  let mut instrs = prog.to_vec();
+  let fprog = sock_fprog(&mut instrs).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+  setsockopt(
+    fd,
+    libc::SO_ATTACH_FILTER,
+    &fprog as *const SockFprog as *const c_void,
+    mem::size_of::<SockFprog>(),
+  )
+}
+
+/// Removes the filter attached to the socket `fd`.
+/// Returns Err if no filter is attached.
+pub fn detach_filter(fd: RawFd) -> io::Result<()> {
+  let unused: c_int = 0;
+  setsockopt(
+    fd,
+    libc::SO_DETACH_FILTER,
+    &unused as *const c_int as *const c_void,
+    mem::size_of::<c_int>(),
+  )
+}
+
+/// Sets the `SOL_SOCKET` level option `name` to the `len` bytes at `value`.
+fn setsockopt(fd: RawFd, name: c_int, value: *const c_void, len: usize) -> io::Result<()> {
+  let ret = unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, name, value, len as socklen_t) };
+  if ret < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
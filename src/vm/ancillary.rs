@@ -0,0 +1,92 @@
+/// Absolute loads at or above this offset read packet metadata instead of packet data.
+pub const SKF_AD_OFF: u32 = -0x1000i32 as u32;
+/// The link layer protocol, in network byte order.
+pub const SKF_AD_PROTOCOL: u32 = 0;
+/// The packet type, e.g. PACKET_HOST or PACKET_BROADCAST.
+pub const SKF_AD_PKTTYPE: u32 = 4;
+/// The index of the interface the packet arrived on.
+pub const SKF_AD_IFINDEX: u32 = 8;
+/// The packet's mark.
+pub const SKF_AD_MARK: u32 = 20;
+/// The receive queue the packet arrived on.
+pub const SKF_AD_QUEUE: u32 = 24;
+/// The hardware type of the interface the packet arrived on.
+pub const SKF_AD_HATYPE: u32 = 28;
+/// The receive hash of the packet.
+pub const SKF_AD_RXHASH: u32 = 32;
+/// The CPU processing the packet.
+pub const SKF_AD_CPU: u32 = 36;
+/// The packet's VLAN tag.
+pub const SKF_AD_VLAN_TAG: u32 = 44;
+/// Whether the packet has a VLAN tag.
+pub const SKF_AD_VLAN_TAG_PRESENT: u32 = 48;
+/// One past the last ancillary data offset the kernel knows about.
+pub const SKF_AD_MAX: u32 = 64;
+
+/// Packet metadata exposed to filters through the Linux ancillary data extensions.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AuxData {
+  /// Read by `ld #proto`.
+  pub protocol: u16,
+  /// Read by `ld #type`.
+  pub pkt_type: u32,
+  /// Read by `ld #ifidx`.
+  pub ifindex: u32,
+  /// Read by `ld #mark`.
+  pub mark: u32,
+  /// Read by `ld #queue`.
+  pub queue: u32,
+  /// Read by `ld #hatype`.
+  pub hatype: u32,
+  /// Read by `ld #rxhash`.
+  pub rxhash: u32,
+  /// Read by `ld #cpu`.
+  pub cpu: u32,
+  /// Read by `ld #vlan_tci`, and `ld #vlan_avail` if present.
+  pub vlan_tag: Option<u16>,
+}
+
+impl AuxData {
+  /// Returns the value of the extension at absolute offset `k`, or None if `k` doesn't name a
+  /// supported extension.
+  pub fn load(&self, k: u32) -> Option<u32> {
+    if k < SKF_AD_OFF {
+      return None;
+    }
+    match k - SKF_AD_OFF {
+      SKF_AD_PROTOCOL => Some(self.protocol as u32),
+      SKF_AD_PKTTYPE => Some(self.pkt_type),
+      SKF_AD_IFINDEX => Some(self.ifindex),
+      SKF_AD_MARK => Some(self.mark),
+      SKF_AD_QUEUE => Some(self.queue),
+      SKF_AD_HATYPE => Some(self.hatype),
+      SKF_AD_RXHASH => Some(self.rxhash),
+      SKF_AD_CPU => Some(self.cpu),
+      SKF_AD_VLAN_TAG => Some(self.vlan_tag.unwrap_or(0) as u32),
+      SKF_AD_VLAN_TAG_PRESENT => Some(self.vlan_tag.is_some() as u32),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn load() {
+    let aux = AuxData {
+      protocol: 0x0800,
+      ifindex: 3,
+      vlan_tag: Some(42),
+      ..AuxData::default()
+    };
+    assert_eq!(aux.load(SKF_AD_OFF + SKF_AD_PROTOCOL), Some(0x0800));
+    assert_eq!(aux.load(SKF_AD_OFF + SKF_AD_IFINDEX), Some(3));
+    assert_eq!(aux.load(SKF_AD_OFF + SKF_AD_VLAN_TAG), Some(42));
+    assert_eq!(aux.load(SKF_AD_OFF + SKF_AD_VLAN_TAG_PRESENT), Some(1));
+    assert_eq!(AuxData::default().load(SKF_AD_OFF + SKF_AD_VLAN_TAG_PRESENT), Some(0));
+    assert!(aux.load(SKF_AD_OFF + 2).is_none());
+    assert!(aux.load(12).is_none());
+  }
+}
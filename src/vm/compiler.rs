@@ -0,0 +1,300 @@
+use common::error::VmError;
+use common::instruction::*;
+use vm::machine::{MachineState, DEFAULT_INSN_LIMIT, SCRATCH_MEM_SLOTS};
+use vm::packet::Packet;
+
+/// Where execution goes after a compiled instruction.
+enum Flow {
+  /// On to the next instruction.
+  Next,
+  /// Past the next instruction and this many more.
+  Skip(u32),
+  /// Nowhere, the program returned this value.
+  Return(u32),
+}
+
+/// An instruction lowered to a closure that already knows its operands.
+type Op = Box<dyn Fn(&mut MachineState, &[u8]) -> Result<Flow, VmError> + Send + Sync>;
+
+/// A program that has been decoded once up front, so running it skips the opcode dispatch.
+/// Runs behave exactly like `PsuedoMachine::run_program` on a fresh machine.
+pub struct CompiledFilter {
+  ops: Vec<Op>,
+}
+
+impl CompiledFilter {
+  /// Runs the program over `pkt` from a zeroed state.
+  /// Returns Ok with accept/reject if the program completes, Err otherwise.
+  pub fn run(&self, pkt: &[u8]) -> Result<u32, VmError> {
+    let mut state = MachineState::default();
+    let mut pc = 0usize;
+    for _ in 0..DEFAULT_INSN_LIMIT {
+      let op = self.ops.get(pc).ok_or(VmError::ProgramCounterOutOfRange(pc))?;
+      let offset = match op(&mut state, pkt)? {
+        Flow::Next => 0,
+        Flow::Skip(offset) => offset,
+        Flow::Return(ret) => return Ok(ret),
+      };
+      pc = pc
+        .checked_add(1)
+        .and_then(|next| next.checked_add(offset as usize))
+        .ok_or(VmError::ProgramCounterOutOfRange(usize::MAX))?;
+    }
+    Err(VmError::InstructionLimitExceeded(DEFAULT_INSN_LIMIT))
+  }
+}
+
+/// Lowers every instruction in `prog` to a closure.
+pub fn compile(prog: &[Instruction]) -> CompiledFilter {
+  CompiledFilter {
+    ops: prog.iter().map(lower).collect(),
+  }
+}
+
+/// Builds the error for a load at `offset` that doesn't fit in `pkt`.
+fn out_of_bounds(offset: u64, pkt: &[u8]) -> VmError {
+  VmError::OutOfBounds { offset, len: pkt.len() }
+}
+
+/// Reads `size` bytes at `offset` in network byte order.
+fn load(pkt: &[u8], offset: u32, size: u16) -> Result<u32, VmError> {
+  let offset_usize = offset as usize;
+  let val = match size {
+    SIZE_W => pkt.read_u32(offset_usize),
+    SIZE_H => pkt.read_u16(offset_usize).map(|val| val as u32),
+    _ => pkt.read_u8(offset_usize).map(|val| val as u32),
+  };
+  val.ok_or_else(|| out_of_bounds(offset as u64, pkt))
+}
+
+/// Computes `index + k` for indirect loads.
+fn ind_offset(index: u32, k: u32, pkt: &[u8]) -> Result<u32, VmError> {
+  index
+    .checked_add(k)
+    .ok_or_else(|| out_of_bounds(index as u64 + k as u64, pkt))
+}
+
+/// Reads scratch memory slot `k`.
+fn mem(state: &MachineState, k: u32) -> Result<u32, VmError> {
+  if k as usize >= SCRATCH_MEM_SLOTS {
+    return Err(VmError::MemorySlotOutOfRange(k as usize));
+  }
+  Ok(state.memory[k as usize])
+}
+
+/// Computes `A op operand` for an ALU operator.
+type AluFn = fn(u32, u32) -> Result<u32, VmError>;
+
+/// Returns the function computing `A op operand` for an ALU operator, or None for an unknown one.
+fn alu_op(op: u16) -> Option<AluFn> {
+  let f: AluFn = match op {
+    OP_ADD => |a, b| Ok(a.wrapping_add(b)),
+    OP_SUB => |a, b| Ok(a.wrapping_sub(b)),
+    OP_MUL => |a, b| Ok(a.wrapping_mul(b)),
+    OP_DIV => |a, b| a.checked_div(b).ok_or(VmError::DivideByZero),
+    OP_MOD => |a, b| a.checked_rem(b).ok_or(VmError::DivideByZero),
+    OP_OR => |a, b| Ok(a | b),
+    OP_AND => |a, b| Ok(a & b),
+    OP_XOR => |a, b| Ok(a ^ b),
+    OP_LSH => |a, b| Ok(a.wrapping_shl(b)),
+    OP_RSH => |a, b| Ok(a.wrapping_shr(b)),
+    _ => return None,
+  };
+  Some(f)
+}
+
+/// Returns the comparison made by a conditional jump, or None for an unknown one.
+fn jmp_op(op: u16) -> Option<fn(u32, u32) -> bool> {
+  let f: fn(u32, u32) -> bool = match op {
+    OP_JEQ => |a, b| a == b,
+    OP_JGT => |a, b| a > b,
+    OP_JGE => |a, b| a >= b,
+    OP_JSET => |a, b| a & b > 0,
+    OP_JSGT => |a, b| a as i32 > b as i32,
+    OP_JSGE => |a, b| a as i32 >= b as i32,
+    _ => return None,
+  };
+  Some(f)
+}
+
+/// Lowers an instruction to a closure.
+fn lower(instr: &Instruction) -> Op {
+  let k = instr.k;
+  let opcode = instr.opcode;
+  let size = instr.size();
+  let invalid: Op = Box::new(move |_, _| Err(VmError::InvalidOpcode(opcode)));
+  match opcode {
+    LDI => Box::new(move |s, _| {
+      s.accumulator = k;
+      Ok(Flow::Next)
+    }),
+    LDW | LDH | LDB => Box::new(move |s, pkt| {
+      s.accumulator = load(pkt, k, size)?;
+      Ok(Flow::Next)
+    }),
+    LDWI | LDHI | LDBI => Box::new(move |s, pkt| {
+      s.accumulator = load(pkt, ind_offset(s.index, k, pkt)?, size)?;
+      Ok(Flow::Next)
+    }),
+    LDWM => Box::new(move |s, _| {
+      s.accumulator = mem(s, k)?;
+      Ok(Flow::Next)
+    }),
+    LDHM => Box::new(move |s, _| {
+      s.accumulator = mem(s, k)? & 0x0000FFFF;
+      Ok(Flow::Next)
+    }),
+    LDBM => Box::new(move |s, _| {
+      s.accumulator = mem(s, k)? & 0x000000FF;
+      Ok(Flow::Next)
+    }),
+    LDL => Box::new(|s, pkt| {
+      s.accumulator = pkt.len() as u32;
+      Ok(Flow::Next)
+    }),
+    LDXI => Box::new(move |s, _| {
+      s.index = k;
+      Ok(Flow::Next)
+    }),
+    LDXW => Box::new(move |s, pkt| {
+      s.index = load(pkt, k, SIZE_W)?;
+      Ok(Flow::Next)
+    }),
+    LDXM => Box::new(move |s, _| {
+      s.index = mem(s, k)?;
+      Ok(Flow::Next)
+    }),
+    LDXL => Box::new(|s, pkt| {
+      s.index = pkt.len() as u32;
+      Ok(Flow::Next)
+    }),
+    LDXB => Box::new(move |s, pkt| {
+      s.index = 4 * (load(pkt, k, SIZE_B)? & 0x0F);
+      Ok(Flow::Next)
+    }),
+    ST => Box::new(move |s, _| {
+      mem(s, k)?;
+      s.memory[k as usize] = s.accumulator;
+      Ok(Flow::Next)
+    }),
+    STX => Box::new(move |s, _| {
+      mem(s, k)?;
+      s.memory[k as usize] = s.index;
+      Ok(Flow::Next)
+    }),
+    STI | STXI => Box::new(move |s, _| {
+      let slot = s.index as u64 + k as u64;
+      if slot >= SCRATCH_MEM_SLOTS as u64 {
+        return Err(VmError::MemorySlotOutOfRange(slot as usize));
+      }
+      s.memory[slot as usize] = if opcode == STI { s.accumulator } else { s.index };
+      Ok(Flow::Next)
+    }),
+    TAX => Box::new(|s, _| {
+      s.index = s.accumulator;
+      Ok(Flow::Next)
+    }),
+    TXA => Box::new(|s, _| {
+      s.accumulator = s.index;
+      Ok(Flow::Next)
+    }),
+    RETA => Box::new(|s, _| Ok(Flow::Return(s.accumulator))),
+    RETK => Box::new(move |_, _| Ok(Flow::Return(k))),
+    NEG => Box::new(|s, _| {
+      s.accumulator = s.accumulator.wrapping_neg();
+      Ok(Flow::Next)
+    }),
+    JMP => Box::new(move |_, _| Ok(Flow::Skip(k))),
+    _ if instr.class() == CLASS_ALU => match alu_op(instr.op()) {
+      Some(f) if opcode == CLASS_ALU | SRC_X | instr.op() => Box::new(move |s, _| {
+        s.accumulator = f(s.accumulator, s.index)?;
+        Ok(Flow::Next)
+      }),
+      Some(f) if opcode == CLASS_ALU | SRC_K | instr.op() => Box::new(move |s, _| {
+        s.accumulator = f(s.accumulator, k)?;
+        Ok(Flow::Next)
+      }),
+      _ => invalid,
+    },
+    _ if instr.class() == CLASS_JMP => {
+      let (jt, jf) = (instr.jt as u32, instr.jf as u32);
+      let target = move |taken: bool| Ok(Flow::Skip(if taken { jt } else { jf }));
+      match jmp_op(instr.op()) {
+        Some(f) if opcode == CLASS_JMP | SRC_X | instr.op() => Box::new(move |s, _| target(f(s.accumulator, s.index))),
+        Some(f) if opcode == CLASS_JMP | SRC_K | instr.op() => Box::new(move |s, _| target(f(s.accumulator, k))),
+        _ => invalid,
+      }
+    },
+    _ => invalid,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use filter::builder::FilterBuilder;
+  use vm::machine::PsuedoMachine;
+
+  fn assert_same(prog: &[Instruction], pkts: &[&[u8]]) {
+    let compiled = compile(prog);
+    for pkt in pkts {
+      assert_eq!(compiled.run(pkt), PsuedoMachine::new().run_program(prog, pkt));
+    }
+  }
+
+  #[test]
+  fn matches_interpreter() {
+    let tcp = FilterBuilder::new()
+      .ether_type(0x0800)
+      .ip_protocol(6)
+      .tcp_dst_port(80)
+      .build()
+      .unwrap();
+    let mut pkt = [0u8; 54];
+    pkt[12] = 0x08;
+    pkt[14] = 0x45;
+    pkt[23] = 6;
+    pkt[37] = 80;
+    let mut other = pkt;
+    other[37] = 81;
+    assert_same(&tcp, &[&pkt, &other, &pkt[..20], &[]]);
+
+    let alu = vec![
+      Instruction::new(LDB, 0, 0, 37),
+      Instruction::new(ST, 0, 0, 3),
+      Instruction::new(LDXM, 0, 0, 3),
+      Instruction::new(MULK, 0, 0, 3),
+      Instruction::new(SUBX, 0, 0, 0),
+      Instruction::new(LSHK, 0, 0, 33),
+      Instruction::new(XORK, 0, 0, 0xF0F0),
+      Instruction::new(JMPGTX, 1, 0, 0),
+      Instruction::new(NEG, 0, 0, 0),
+      Instruction::new(DIVX, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert_same(&alu, &[&pkt, &other, &[0u8; 38], &[]]);
+  }
+
+  #[test]
+  fn matches_interpreter_errors() {
+    let progs = vec![
+      vec![Instruction::new(LDI, 0, 0, 1)],
+      vec![Instruction::new(JMP, 0, 0, 0xFFFFFFFF)],
+      vec![Instruction::new(STX, 0, 0, 16), Instruction::new(RETA, 0, 0, 0)],
+      vec![
+        Instruction::new(LDXI, 0, 0, 15),
+        Instruction::new(STI, 0, 0, 1),
+        Instruction::new(RETA, 0, 0, 0),
+      ],
+      vec![Instruction::new(0xFFFF, 0, 0, 0)],
+      vec![Instruction::new(ADDK | 0x100, 0, 0, 0)],
+      vec![
+        Instruction::new(LDXI, 0, 0, 0xFFFFFFFF),
+        Instruction::new(LDBI, 0, 0, 2),
+      ],
+    ];
+    for prog in progs {
+      assert_same(&prog, &[&[0u8; 64]]);
+    }
+  }
+}
@@ -0,0 +1,36 @@
+/// Records which instructions of a program have been executed, across any number of runs.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Coverage {
+  covered: Vec<bool>,
+}
+
+impl Coverage {
+  /// Returns a recorder for a `len` instruction program with nothing covered yet.
+  pub fn new(len: usize) -> Coverage {
+    Coverage {
+      covered: vec![false; len],
+    }
+  }
+
+  /// Marks the instruction at index `frame` as executed.
+  pub fn record(&mut self, frame: usize) {
+    if let Some(covered) = self.covered.get_mut(frame) {
+      *covered = true;
+    }
+  }
+
+  /// Returns whether each instruction has been executed, indexed like the program.
+  pub fn covered(&self) -> &[bool] {
+    &self.covered
+  }
+
+  /// Returns how many distinct instructions have been executed.
+  pub fn count(&self) -> usize {
+    self.covered.iter().filter(|&&covered| covered).count()
+  }
+
+  /// Returns whether every instruction has been executed.
+  pub fn is_complete(&self) -> bool {
+    self.covered.iter().all(|&covered| covered)
+  }
+}
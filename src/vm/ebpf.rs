@@ -0,0 +1,416 @@
+use common::error::VmError;
+use common::instruction::*;
+use vm::ancillary::SKF_AD_OFF;
+use vm::verifier::verify_linux_compat;
+
+/// eBPF instruction classes, operators and sources beyond the ones classic BPF shares.
+pub const EBPF_JMP: u8 = 0x05;
+pub const EBPF_ALU64: u8 = 0x07;
+pub const EBPF_MOV: u8 = 0xB0;
+pub const EBPF_EXIT: u8 = 0x90;
+pub const EBPF_JNE: u8 = 0x50;
+pub const EBPF_JLT: u8 = 0xA0;
+pub const EBPF_JLE: u8 = 0xB0;
+pub const EBPF_MEM: u8 = 0x60;
+pub const EBPF_K: u8 = 0x00;
+pub const EBPF_X: u8 = 0x08;
+
+/// The eBPF registers classic programs are mapped onto, as in the kernel's `bpf_convert_filter`.
+pub const REG_A: u8 = 0;
+pub const REG_ARG1: u8 = 1;
+pub const REG_TMP: u8 = 2;
+pub const REG_CTX: u8 = 6;
+pub const REG_X: u8 = 7;
+pub const REG_FP: u8 = 10;
+/// Holds A across the packet load of `ldxb`. The kernel uses `REG_TMP`, but a real `LD_ABS`
+/// clobbers R1 to R5, so this uses a callee-saved register the mapping leaves free.
+pub const REG_SAVED_A: u8 = 8;
+
+/// The kernel's `struct bpf_insn`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct EbpfInsn {
+  pub code: u8,
+  /// The destination register in the low nibble and the source register in the high one.
+  pub regs: u8,
+  pub off: i16,
+  pub imm: i32,
+}
+
+impl EbpfInsn {
+  /// Returns an instruction with the given fields.
+  pub fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> EbpfInsn {
+    EbpfInsn {
+      code,
+      regs: src << 4 | dst & 0x0F,
+      off,
+      imm,
+    }
+  }
+
+  /// Returns the destination register.
+  pub fn dst_reg(&self) -> u8 {
+    self.regs & 0x0F
+  }
+
+  /// Returns the source register.
+  pub fn src_reg(&self) -> u8 {
+    self.regs >> 4
+  }
+}
+
+/// An eBPF instruction whose jump offset isn't known until every classic instruction is lowered.
+enum Lowered {
+  Insn(EbpfInsn),
+  /// A jump to the first eBPF instruction of this classic instruction.
+  Jump(EbpfInsn, usize),
+}
+
+fn alu32_reg(op: u16, dst: u8, src: u8) -> Lowered {
+  Lowered::Insn(EbpfInsn::new(CLASS_ALU as u8 | op as u8 | EBPF_X, dst, src, 0, 0))
+}
+
+fn alu32_imm(op: u16, dst: u8, imm: u32) -> Lowered {
+  Lowered::Insn(EbpfInsn::new(CLASS_ALU as u8 | op as u8, dst, 0, 0, imm as i32))
+}
+
+fn mov32_imm(dst: u8, imm: u32) -> Lowered {
+  Lowered::Insn(EbpfInsn::new(
+    CLASS_ALU as u8 | EBPF_MOV | EBPF_K,
+    dst,
+    0,
+    0,
+    imm as i32,
+  ))
+}
+
+fn mov64_reg(dst: u8, src: u8) -> Lowered {
+  Lowered::Insn(EbpfInsn::new(EBPF_ALU64 | EBPF_MOV | EBPF_X, dst, src, 0, 0))
+}
+
+fn exit() -> Lowered {
+  Lowered::Insn(EbpfInsn::new(EBPF_JMP | EBPF_EXIT, 0, 0, 0, 0))
+}
+
+/// Loads the scratch memory slot `k`, which lives on the stack, into `dst`.
+fn load_slot(dst: u8, k: u32) -> Lowered {
+  Lowered::Insn(EbpfInsn::new(
+    CLASS_LDX as u8 | EBPF_MEM | SIZE_W as u8,
+    dst,
+    REG_FP,
+    stack_off(k),
+    0,
+  ))
+}
+
+/// Stores `src` to the scratch memory slot `k`.
+fn store_slot(src: u8, k: u32) -> Lowered {
+  Lowered::Insn(EbpfInsn::new(
+    CLASS_STX as u8 | EBPF_MEM | SIZE_W as u8,
+    REG_FP,
+    src,
+    stack_off(k),
+    0,
+  ))
+}
+
+/// Returns the frame pointer offset of scratch memory slot `k`.
+fn stack_off(k: u32) -> i16 {
+  -(k as i16 * 4 + 4)
+}
+
+/// Loads `struct __sk_buff`'s `len`, its first field, into `dst`.
+fn load_len(dst: u8) -> Lowered {
+  Lowered::Insn(EbpfInsn::new(
+    CLASS_LDX as u8 | EBPF_MEM | SIZE_W as u8,
+    dst,
+    REG_CTX,
+    0,
+    0,
+  ))
+}
+
+/// Lowers a conditional jump, like `bpf_convert_filter` does: a single eBPF jump when one of the
+/// branches falls through, otherwise a jump for the true branch followed by a JA for the false
+/// one.
+fn lower_branch(pc: usize, instr: &Instruction, out: &mut Vec<Lowered>) {
+  let (mut src, mut src_reg, imm) = match instr.source() {
+    Source::X => (EBPF_X, REG_X, 0),
+    Source::K => (EBPF_K, 0, instr.k as i32),
+  };
+  // eBPF immediates are sign extended to 64 bits, so compare against a register holding the
+  // zero extended value instead.
+  if src == EBPF_K && imm < 0 {
+    out.push(mov32_imm(REG_TMP, instr.k));
+    src = EBPF_X;
+    src_reg = REG_TMP;
+  }
+  let branch = |op: u8, target: usize| {
+    Lowered::Jump(
+      EbpfInsn::new(
+        EBPF_JMP | op | src,
+        REG_A,
+        src_reg,
+        0,
+        if src == EBPF_K { imm } else { 0 },
+      ),
+      target,
+    )
+  };
+  let op = instr.op() as u8;
+  let jt = pc + 1 + instr.jt as usize;
+  let jf = pc + 1 + instr.jf as usize;
+  if instr.jf == 0 {
+    out.push(branch(op, jt));
+    return;
+  }
+  if instr.jt == 0 {
+    let inverted = match instr.op() {
+      OP_JEQ => Some(EBPF_JNE),
+      OP_JGT => Some(EBPF_JLE),
+      OP_JGE => Some(EBPF_JLT),
+      _ => None,
+    };
+    if let Some(inverted) = inverted {
+      out.push(branch(inverted, jf));
+      return;
+    }
+  }
+  out.push(branch(op, jt));
+  out.push(Lowered::Jump(EbpfInsn::new(EBPF_JMP | OP_JA as u8, 0, 0, 0, 0), jf));
+}
+
+/// Lowers the classic instruction at `pc` to the eBPF instructions doing the same thing.
+fn lower(pc: usize, instr: &Instruction, out: &mut Vec<Lowered>) -> Result<(), VmError> {
+  let k = instr.k;
+  let decoded = Opcode::from_raw(instr.opcode).ok_or(VmError::InvalidOpcode(instr.opcode))?;
+  match decoded {
+    Opcode::Ldi => out.push(mov32_imm(REG_A, k)),
+    Opcode::Ldw | Opcode::Ldh | Opcode::Ldb if k >= SKF_AD_OFF => return Err(VmError::UnsupportedInstruction(pc)),
+    Opcode::Ldw | Opcode::Ldh | Opcode::Ldb => {
+      out.push(Lowered::Insn(EbpfInsn::new(instr.opcode as u8, 0, 0, 0, k as i32)))
+    },
+    Opcode::Ldwi | Opcode::Ldhi | Opcode::Ldbi => {
+      out.push(Lowered::Insn(EbpfInsn::new(instr.opcode as u8, 0, REG_X, 0, k as i32)))
+    },
+    Opcode::Ldwm => out.push(load_slot(REG_A, k)),
+    Opcode::Ldl => out.push(load_len(REG_A)),
+    Opcode::Ldxi => out.push(mov32_imm(REG_X, k)),
+    Opcode::Ldxm => out.push(load_slot(REG_X, k)),
+    Opcode::Ldxl => out.push(load_len(REG_X)),
+    Opcode::Ldxb => {
+      out.push(mov64_reg(REG_SAVED_A, REG_A));
+      out.push(Lowered::Insn(EbpfInsn::new(LDB as u8, 0, 0, 0, k as i32)));
+      out.push(alu32_imm(OP_AND, REG_A, 0x0F));
+      out.push(alu32_imm(OP_LSH, REG_A, 2));
+      out.push(mov64_reg(REG_X, REG_A));
+      out.push(mov64_reg(REG_A, REG_SAVED_A));
+    },
+    Opcode::St => out.push(store_slot(REG_A, k)),
+    Opcode::Stx => out.push(store_slot(REG_X, k)),
+    Opcode::Tax => out.push(mov64_reg(REG_X, REG_A)),
+    Opcode::Txa => out.push(mov64_reg(REG_A, REG_X)),
+    Opcode::Retk => {
+      out.push(mov32_imm(REG_A, k));
+      out.push(exit());
+    },
+    Opcode::Reta => out.push(exit()),
+    Opcode::Neg => out.push(alu32_imm(OP_NEG, REG_A, 0)),
+    Opcode::Divx | Opcode::Modx => {
+      // Classic BPF drops the packet on a division by zero, which eBPF would let through.
+      out.push(Lowered::Insn(EbpfInsn::new(
+        CLASS_ALU as u8 | EBPF_MOV | EBPF_X,
+        REG_X,
+        REG_X,
+        0,
+        0,
+      )));
+      out.push(Lowered::Insn(EbpfInsn::new(
+        EBPF_JMP | EBPF_JNE | EBPF_K,
+        REG_X,
+        0,
+        2,
+        0,
+      )));
+      out.push(alu32_reg(OP_XOR, REG_A, REG_A));
+      out.push(exit());
+      out.push(alu32_reg(instr.op(), REG_A, REG_X));
+    },
+    Opcode::Addx
+    | Opcode::Subx
+    | Opcode::Mulx
+    | Opcode::Orx
+    | Opcode::Andx
+    | Opcode::Lshx
+    | Opcode::Rshx
+    | Opcode::Xorx => out.push(alu32_reg(instr.op(), REG_A, REG_X)),
+    Opcode::Addk
+    | Opcode::Subk
+    | Opcode::Mulk
+    | Opcode::Divk
+    | Opcode::Ork
+    | Opcode::Andk
+    | Opcode::Lshk
+    | Opcode::Rshk
+    | Opcode::Modk
+    | Opcode::Xork => out.push(alu32_imm(instr.op(), REG_A, k)),
+    Opcode::Jmp => out.push(Lowered::Jump(
+      EbpfInsn::new(EBPF_JMP | OP_JA as u8, 0, 0, 0, 0),
+      pc + 1 + k as usize,
+    )),
+    Opcode::Jmpeq
+    | Opcode::Jmpgt
+    | Opcode::Jmpge
+    | Opcode::Jmpset
+    | Opcode::Jmpeqx
+    | Opcode::Jmpgtx
+    | Opcode::Jmpgex
+    | Opcode::Jmpsetx => lower_branch(pc, instr, out),
+    Opcode::Ldhm
+    | Opcode::Ldbm
+    | Opcode::Ldxw
+    | Opcode::Sti
+    | Opcode::Stxi
+    | Opcode::Jmpsgt
+    | Opcode::Jmpsge
+    | Opcode::Jmpsgtx
+    | Opcode::Jmpsgex => return Err(VmError::UnsupportedInstruction(pc)),
+  }
+  Ok(())
+}
+
+/// Experimental: translates a classic program into eBPF that computes the same result, following
+/// the mapping of the kernel's `bpf_convert_filter`. A lives in R0, X in R7, the context in R6
+/// and scratch memory on the stack. The program length comes from `struct __sk_buff` and packet
+/// data is read with the legacy `LD_ABS` and `LD_IND` instructions, so the result is meant for a
+/// socket filter.
+/// Returns Err if the program fails `verify_linux_compat`, which also rules out the constant
+/// divisions, shifts and scratch memory loads the eBPF verifier refuses, or uses ancillary data
+/// loads, identifying the first such instruction.
+pub fn to_ebpf(prog: &[Instruction]) -> Result<Vec<EbpfInsn>, VmError> {
+  verify_linux_compat(prog)?;
+  // The kernel clears A and X by XORing them with themselves, which the eBPF verifier rejects as
+  // a read of an uninitialized register, so move zero into them instead.
+  let mut lowered = vec![mov32_imm(REG_A, 0), mov32_imm(REG_X, 0), mov64_reg(REG_CTX, REG_ARG1)];
+  let mut starts = Vec::with_capacity(prog.len());
+  for (pc, instr) in prog.iter().enumerate() {
+    starts.push(lowered.len());
+    lower(pc, instr, &mut lowered)?;
+  }
+  lowered
+    .into_iter()
+    .enumerate()
+    .map(|(at, insn)| match insn {
+      Lowered::Insn(insn) => Ok(insn),
+      Lowered::Jump(mut insn, target) => {
+        let off = starts[target] as i64 - at as i64 - 1;
+        if off > i16::MAX as i64 {
+          let pc = starts.iter().rposition(|&start| start <= at).unwrap_or(0);
+          return Err(VmError::JumpOutOfRange(pc));
+        }
+        insn.off = off as i16;
+        Ok(insn)
+      },
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use filter::builder::FilterBuilder;
+  use std::mem;
+
+  #[test]
+  fn port_filter() {
+    let prog = FilterBuilder::new().tcp_dst_port(80).build().unwrap();
+    let ebpf = to_ebpf(&prog).unwrap();
+    assert_eq!(mem::size_of::<EbpfInsn>(), 8);
+    // Prologue: clear A and X, keep the context in R6.
+    assert_eq!(ebpf[0], EbpfInsn::new(0xB4, REG_A, 0, 0, 0));
+    assert_eq!(ebpf[1], EbpfInsn::new(0xB4, REG_X, 0, 0, 0));
+    assert_eq!(ebpf[2], EbpfInsn::new(0xBF, REG_CTX, REG_ARG1, 0, 0));
+    assert_eq!(ebpf[2].dst_reg(), 6);
+    assert_eq!(ebpf[2].src_reg(), 1);
+    // Packet loads stay legacy packet loads with the same opcode.
+    assert_eq!(prog[0].opcode, LDH);
+    assert_eq!(ebpf[3], EbpfInsn::new(0x28, 0, 0, 0, prog[0].k as i32));
+
+    // Every RET #k becomes a move into R0 and an exit, and every jump lands on one.
+    let exits = ebpf.iter().filter(|insn| insn.code == 0x95).count();
+    let rets = prog.iter().filter(|instr| instr.class() == CLASS_RET).count();
+    assert_eq!(exits, rets);
+    assert_eq!(ebpf[ebpf.len() - 2], EbpfInsn::new(0xB4, REG_A, 0, 0, 0));
+    for (at, insn) in ebpf.iter().enumerate() {
+      if insn.code & 0x07 == EBPF_JMP && insn.code != 0x95 {
+        let target = at as i64 + 1 + insn.off as i64;
+        assert!(target > at as i64 && (target as usize) < ebpf.len());
+      }
+    }
+  }
+
+  #[test]
+  fn branches() {
+    let prog = [
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 2, 6),
+      Instruction::new(JMPGT, 1, 0, 0xFFFFFFFF),
+      Instruction::new(JMPSET, 1, 2, 1),
+      Instruction::new(RETA, 0, 0, 0),
+      Instruction::new(RETK, 0, 0, 1),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    let ebpf = to_ebpf(&prog).unwrap();
+    let body = &ebpf[3..];
+    assert_eq!(body[0], EbpfInsn::new(0x30, 0, 0, 0, 0));
+    // The true branch falls through, so JEQ becomes a JNE to the false branch.
+    assert_eq!(body[1], EbpfInsn::new(0x55, REG_A, 0, 4, 6));
+    // A negative immediate is compared through a register.
+    assert_eq!(body[2], EbpfInsn::new(0xB4, REG_TMP, 0, 0, -1));
+    assert_eq!(body[3], EbpfInsn::new(0x2D, REG_A, REG_TMP, 2, 0));
+    // JSET can't be inverted, so it takes a JA too.
+    assert_eq!(body[4], EbpfInsn::new(0x45, REG_A, 0, 2, 1));
+    assert_eq!(body[5], EbpfInsn::new(0x05, 0, 0, 3, 0));
+    assert_eq!(body[6], EbpfInsn::new(0x95, 0, 0, 0, 0));
+  }
+
+  #[test]
+  fn memory_and_division() {
+    let prog = [
+      Instruction::new(ST, 0, 0, 3),
+      Instruction::new(LDXM, 0, 0, 3),
+      Instruction::new(DIVX, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    let ebpf = to_ebpf(&prog).unwrap();
+    assert_eq!(ebpf[3], EbpfInsn::new(0x63, REG_FP, REG_A, -16, 0));
+    assert_eq!(ebpf[4], EbpfInsn::new(0x61, REG_X, REG_FP, -16, 0));
+    assert_eq!(ebpf[6], EbpfInsn::new(0x55, REG_X, 0, 2, 0));
+    assert_eq!(ebpf[9], EbpfInsn::new(0x3C, REG_A, REG_X, 0, 0));
+  }
+
+  #[test]
+  fn unsupported() {
+    let ret = Instruction::new(RETA, 0, 0, 0);
+    assert_eq!(
+      to_ebpf(&[
+        Instruction::new(LDB, 0, 0, 0),
+        Instruction::new(LDW, 0, 0, SKF_AD_OFF),
+        ret
+      ]),
+      Err(VmError::UnsupportedInstruction(1))
+    );
+    assert_eq!(
+      to_ebpf(&[Instruction::new(STI, 0, 0, 0), ret]),
+      Err(VmError::UnsupportedInstruction(0))
+    );
+    assert_eq!(
+      to_ebpf(&[Instruction::new(LDI, 0, 0, 0)]),
+      Err(VmError::MissingReturn(0))
+    );
+    assert_eq!(
+      to_ebpf(&[Instruction::new(DIVK, 0, 0, 0), ret]),
+      Err(VmError::ConstantDivideByZero(0))
+    );
+  }
+}
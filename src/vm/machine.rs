@@ -1,57 +1,155 @@
 #![allow(dead_code)]
-extern crate rust_bpf;
-extern crate byteorder;
 
-use std::io::Cursor;
-use std::slice;
+use common::error::VmError;
+use common::instruction::*;
+#[cfg(feature = "log")]
+use common::mnemonic::Mnemonic;
+use vm::ancillary::{AuxData, SKF_AD_OFF};
+#[cfg(feature = "std")]
+use vm::coverage::Coverage;
+use vm::packet::Packet;
+#[cfg(feature = "std")]
+use vm::verifier::verify;
 
-use self::byteorder::{BigEndian, NativeEndian, ReadBytesExt};
+/// Number of scratch memory slots.
+pub const SCRATCH_MEM_SLOTS: usize = 16;
 
-use self::rust_bpf::common::instruction::*;
+/// Default cap on the number of instructions a single run may execute.
+pub const DEFAULT_INSN_LIMIT: u64 = 1 << 16;
 
-const SCRATCH_MEM_SLOTS: usize = 16;
+/// The largest frame pointer `frame` and `MachineState` can report, and so the furthest an
+/// instruction run on its own by `execute` may jump.
+const MAX_FRAME: usize = u32::MAX as usize;
 
-pub struct PsuedoMachine {
+/// What a socket filter decided to do with a packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FilterResult {
+  /// Keep up to this many bytes of the packet.
+  Accept(u32),
+  /// Discard the packet.
+  Drop,
+}
+
+impl FilterResult {
+  /// Interprets the return value of a filter program.
+  pub fn from_ret(ret: u32) -> FilterResult {
+    match ret {
+      0 => FilterResult::Drop,
+      n => FilterResult::Accept(n),
+    }
+  }
+
+  /// Returns the part of `pkt` the filter keeps: the first `n` bytes, or all of it if it's
+  /// shorter, for `Accept(n)`, and None for `Drop`.
+  pub fn truncate(self, pkt: &[u8]) -> Option<&[u8]> {
+    match self {
+      FilterResult::Accept(n) => Some(&pkt[..pkt.len().min(n as usize)]),
+      FilterResult::Drop => None,
+    }
+  }
+}
+
+/// The outcome of executing a single instruction with `PsuedoMachine::step`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StepResult {
+  /// The program hasn't returned yet.
+  Running,
+  /// The program returned this value.
+  Returned(u32),
+}
+
+/// Byte order used to interpret multi-byte packet loads.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Endianness {
+  /// Network byte order.
+  #[default]
+  Big,
+  /// Little endian, e.g. host order on x86.
+  Little,
+}
+
+/// What ADD, SUB and MUL do when the result doesn't fit in 32 bits.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ArithmeticMode {
+  /// Wrap around, like the kernel does.
+  #[default]
+  Wrapping,
+  /// Fail with `VmError::ArithmeticOverflow`, for analysing programs.
+  Checked,
+}
+
+/// What `run_program` does when execution runs past the last instruction without a RET.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum EndOfProgramPolicy {
+  /// Fail with `VmError::ProgramCounterOutOfRange`.
+  #[default]
+  Error,
+  /// Return 0, dropping the packet.
+  ImplicitDrop,
+}
+
+/// A copy of a machine's registers and scratch memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MachineState<const N: usize = SCRATCH_MEM_SLOTS> {
   /// The frame pointer.
-  frame: u32,
+  pub frame: u32,
+  /// The accumulator.
+  pub accumulator: u32,
+  /// The index register.
+  pub index: u32,
+  /// Scratch memory.
+  pub memory: [u32; N],
+}
+
+impl<const N: usize> Default for MachineState<N> {
+  fn default() -> MachineState<N> {
+    MachineState {
+      frame: 0,
+      accumulator: 0,
+      index: 0,
+      memory: [0; N],
+    }
+  }
+}
+
+/// A classic BPF interpreter with `N` scratch memory slots.
+///
+/// Half-word and word packet loads read network byte order unless `set_endianness` says
+/// otherwise. The registers and scratch memory hold plain `u32`s with no byte order of their
+/// own; `memory_ne_bytes` and `set_memory_ne_bytes` move slots in and out as host order bytes.
+#[derive(Clone)]
+pub struct PsuedoMachine<const N: usize = SCRATCH_MEM_SLOTS> {
+  /// The frame pointer. Kept as an index so it's never cast to address the program; it only
+  /// leaves the program by landing exactly past its end.
+  frame: usize,
   /// The accumulator.
   accumulator: u32,
   /// The index register.
   index: u32,
   /// Scratch memory.
-  memory: [u32; SCRATCH_MEM_SLOTS],
+  memory: [u32; N],
+  /// Maximum number of instructions `run_program` may execute.
+  insn_limit: u64,
+  /// Packet metadata for ancillary data loads.
+  aux: Option<AuxData>,
+  /// Byte order of half-word and word packet loads.
+  endianness: Endianness,
+  /// Overflow behaviour of ADD, SUB and MUL.
+  arithmetic: ArithmeticMode,
+  /// What happens when execution falls off the end of the program.
+  end_of_program: EndOfProgramPolicy,
 }
 
 trait Testing {
-  fn frame(&self) -> u32;
-  fn accumulator(&self) -> u32;
-  fn index(&self) -> u32;
-  fn memory(&self) -> &[u32];
   fn set_frame(&mut self, frame: u32);
   fn set_accumulator(&mut self, acc: u32);
   fn set_index(&mut self, index: u32);
   fn set_memory(&mut self, idx: usize, val: u32);
 }
 
-impl Testing for PsuedoMachine {
-  fn frame(&self) -> u32 {
-    self.frame
-  }
-
-  fn accumulator(&self) -> u32 {
-    self.accumulator
-  }
-
-  fn index(&self) -> u32 {
-    self.index
-  }
-
-  fn memory(&self) -> &[u32] {
-    &self.memory
-  }
-
+impl<const N: usize> Testing for PsuedoMachine<N> {
   fn set_frame(&mut self, frame: u32) {
-    self.frame = frame;
+    self.frame = frame as usize;
   }
 
   fn set_accumulator(&mut self, acc: u32) {
@@ -67,429 +165,720 @@ impl Testing for PsuedoMachine {
   }
 }
 
+impl<const N: usize> Default for PsuedoMachine<N> {
+  fn default() -> PsuedoMachine<N> {
+    PsuedoMachine::with_scratch_slots()
+  }
+}
+
 impl PsuedoMachine {
-  /// Returns a zero-initialized PsuedoMachine.
+  /// Returns a zero-initialized PsuedoMachine with the classic 16 scratch memory slots.
   pub fn new() -> PsuedoMachine {
+    PsuedoMachine::with_scratch_slots()
+  }
+}
+
+impl<const N: usize> PsuedoMachine<N> {
+  /// Returns a zero-initialized PsuedoMachine with `N` scratch memory slots.
+  pub fn with_scratch_slots() -> PsuedoMachine<N> {
     PsuedoMachine {
       frame: 0,
       accumulator: 0,
       index: 0,
-      memory: [0; 16],
+      memory: [0; N],
+      insn_limit: DEFAULT_INSN_LIMIT,
+      aux: None,
+      endianness: Endianness::Big,
+      arithmetic: ArithmeticMode::Wrapping,
+      end_of_program: EndOfProgramPolicy::Error,
     }
   }
 
-  /// Resets all fields to zero.
+  /// Returns a PsuedoMachine whose registers and scratch memory start out as `state`.
+  pub fn with_state(state: MachineState<N>) -> PsuedoMachine<N> {
+    let mut pm = PsuedoMachine::with_scratch_slots();
+    pm.restore(&state);
+    pm
+  }
+
+  /// Sets the maximum number of instructions `run_program` may execute before giving up.
+  pub fn set_insn_limit(&mut self, limit: u64) {
+    self.insn_limit = limit;
+  }
+
+  /// Sets the packet metadata returned by ancillary data loads (`k >= SKF_AD_OFF`).
+  /// Without it those loads fail as out of bounds packet loads.
+  pub fn set_aux_data(&mut self, aux: Option<AuxData>) {
+    self.aux = aux;
+  }
+
+  /// Sets the byte order half-word and word packet loads are read in, network order by default.
+  pub fn set_endianness(&mut self, endianness: Endianness) {
+    self.endianness = endianness;
+  }
+
+  /// Sets what ADD, SUB and MUL do on overflow.
+  pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+    self.arithmetic = mode;
+  }
+
+  /// Sets what happens when execution falls off the end of a program without a RET.
+  pub fn set_end_of_program_policy(&mut self, policy: EndOfProgramPolicy) {
+    self.end_of_program = policy;
+  }
+
+  /// Resets all registers and scratch memory to zero.
   pub fn reset(&mut self) {
     self.frame = 0;
     self.accumulator = 0;
     self.index = 0;
-    self.memory = [0; 16];
+    self.memory = [0; N];
+  }
+
+  /// Like `reset`, but to `state` instead of all zeroes.
+  pub fn reset_to(&mut self, state: MachineState<N>) {
+    self.restore(&state);
+  }
+
+  /// Returns a copy of the registers and scratch memory.
+  pub fn snapshot(&self) -> MachineState<N> {
+    MachineState {
+      frame: self.frame as u32,
+      accumulator: self.accumulator,
+      index: self.index,
+      memory: self.memory,
+    }
+  }
+
+  /// Overwrites the registers and scratch memory with a previously taken snapshot.
+  pub fn restore(&mut self, state: &MachineState<N>) {
+    self.frame = state.frame as usize;
+    self.accumulator = state.accumulator;
+    self.index = state.index;
+    self.memory = state.memory;
+  }
+
+  /// Returns the frame pointer.
+  pub fn frame(&self) -> u32 {
+    self.frame as u32
+  }
+
+  /// Returns the accumulator, e.g. to see what a program left in it after running.
+  ///
+  /// ```
+  /// use rust_bpf::common::instruction::*;
+  /// use rust_bpf::vm::machine::PsuedoMachine;
+  ///
+  /// let prog = [Instruction::new(LDB, 0, 0, 1), Instruction::new(RETK, 0, 0, 0)];
+  /// let mut pm = PsuedoMachine::new();
+  /// assert_eq!(pm.run_program(&prog, &[0x01, 0x02]), Ok(0));
+  /// assert_eq!(pm.accumulator(), 0x02);
+  /// ```
+  pub fn accumulator(&self) -> u32 {
+    self.accumulator
+  }
+
+  /// Returns the index register.
+  pub fn index(&self) -> u32 {
+    self.index
+  }
+
+  /// Returns the scratch memory.
+  pub fn memory(&self) -> &[u32] {
+    &self.memory
+  }
+
+  /// Returns scratch memory slot `slot` as bytes in host order, the way a C program sharing the
+  /// machine's memory would lay it out, or None if there's no such slot.
+  pub fn memory_ne_bytes(&self, slot: usize) -> Option<[u8; 4]> {
+    self.memory.get(slot).map(|val| val.to_ne_bytes())
+  }
+
+  /// Stores `bytes`, in host order, into scratch memory slot `slot`.
+  /// Returns Err if there's no such slot.
+  pub fn set_memory_ne_bytes(&mut self, slot: usize, bytes: [u8; 4]) -> Result<(), VmError> {
+    let val = self.memory.get_mut(slot).ok_or(VmError::MemorySlotOutOfRange(slot))?;
+    *val = u32::from_ne_bytes(bytes);
+    Ok(())
+  }
+
+  /// Renders the registers and scratch memory as a table of hex values, one per line, e.g. to
+  /// print between calls to `step`:
+  ///
+  /// ```text
+  /// frame  0x00000002
+  /// a      0xdeadbeef
+  /// x      0x00000000
+  /// m[0]   0x00000000
+  /// ...
+  /// ```
+  #[cfg(feature = "std")]
+  pub fn dump(&self) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    let registers = [("frame", self.frame as u32), ("a", self.accumulator), ("x", self.index)];
+    for &(name, value) in registers.iter() {
+      let _ = writeln!(out, "{:<6} {:#010x}", name, value);
+    }
+    for (slot, value) in self.memory.iter().enumerate() {
+      let _ = writeln!(out, "{:<6} {:#010x}", format!("m[{}]", slot), value);
+    }
+    out
+  }
+
+  /// Returns the operand of an ALU or jump instruction, `k` or the index register depending on
+  /// its source bit.
+  #[inline]
+  fn operand(&self, instr: &Instruction) -> u32 {
+    match instr.source() {
+      Source::K => instr.k,
+      Source::X => self.index,
+    }
   }
 
   /// Return the value in scratch memory slot `n`.
   fn mem(&self, n: usize) -> u32 {
-    assert!(n < SCRATCH_MEM_SLOTS);
+    assert!(n < N);
     self.memory[n]
   }
 
-  /// Helper for full word loads.
+  /// Helper for ancillary data loads.
   #[inline]
-  fn ld_u32(&mut self, offset: u32, buf: &[u8]) -> Result<u32, ()> {
-    if offset as usize >= buf.len() {
-      return Err(());
-    }
-    let mut cur = Cursor::new(&buf[offset as usize..]);
-    let ret = cur.read_u32::<BigEndian>();
-    if ret.is_err() {
-      return Err(());
-    }
-    Ok(ret.unwrap())
+  fn ld_aux<P: Packet + ?Sized>(&self, k: u32, buf: &P) -> Result<u32, VmError> {
+    self
+      .aux
+      .and_then(|aux| aux.load(k))
+      .ok_or_else(|| out_of_bounds(k as u64, buf))
   }
 
-  /// Helper for half-words loads.
+  /// Loads `size` bytes from `offset` in the packet, in the machine's byte order.
   #[inline]
-  fn ld_u16(&mut self, offset: u32, buf: &[u8]) -> Result<u32, ()> {
-    if offset as usize >= buf.len() {
-      return Err(());
-    }
-    let mut cur = Cursor::new(&buf[offset as usize..]);
-    let ret = cur.read_u16::<BigEndian>();
-    if ret.is_err() {
-      return Err(());
-    }
-    Ok(ret.unwrap() as u32)
+  fn load<P: Packet + ?Sized>(&self, size: ReadSize, offset: u32, buf: &P) -> Result<u32, VmError> {
+    let at = offset as usize;
+    let val = match size {
+      ReadSize::Byte => buf.read_u8(at).map(|val| val as u32),
+      ReadSize::Half => buf.read_u16(at).map(|val| match self.endianness {
+        Endianness::Big => val as u32,
+        Endianness::Little => val.swap_bytes() as u32,
+      }),
+      ReadSize::Word => buf.read_u32(at).map(|val| match self.endianness {
+        Endianness::Big => val,
+        Endianness::Little => val.swap_bytes(),
+      }),
+    };
+    val.ok_or_else(|| out_of_bounds(offset as u64, buf))
   }
 
-  /// Helper for single byte loads.
+  /// Computes `index + k` for indirect loads.
   #[inline]
-  fn ld_u8(&mut self, offset: u32, buf: &[u8]) -> Result<u32, ()> {
-    if offset as usize >= buf.len() {
-      return Err(());
+  fn ind_offset<P: Packet + ?Sized>(&self, k: u32, buf: &P) -> Result<u32, VmError> {
+    self
+      .index
+      .checked_add(k)
+      .ok_or_else(|| out_of_bounds(self.index as u64 + k as u64, buf))
+  }
+
+  /// Applies the arithmetic mode to the result of an overflowing ADD, SUB or MUL.
+  fn overflowing(&self, (result, overflowed): (u32, bool)) -> Result<u32, VmError> {
+    if overflowed && self.arithmetic == ArithmeticMode::Checked {
+      return Err(VmError::ArithmeticOverflow);
     }
-    Ok(buf[offset as usize] as u32)
+    Ok(result)
   }
 
   /// Execute an instruction and increments the frame pointer after successful execution.
   /// Returns Ok(Some) if `instr` is a return instruction.
-  /// Returns Err on bad instruction.
-  pub fn execute(&mut self, instr: &Instruction, pkt: &[u8]) -> Result<Option<u32>, ()> {
+  /// Returns Err on bad instruction, or a jump past the largest possible frame pointer. With no
+  /// program to check against, jumps that merely leave one fail on the next `step`.
+  /// With the `log` feature, each instruction that executes logs a trace record with its frame,
+  /// mnemonic and the accumulator it leaves behind.
+  pub fn execute<P: Packet + ?Sized>(&mut self, instr: &Instruction, pkt: &P) -> Result<Option<u32>, VmError> {
+    self.execute_within(instr, pkt, MAX_FRAME)
+  }
+
+  /// Like `execute`, but fails with `ProgramCounterOutOfRange` on jumps past `len`, the length of
+  /// the program `instr` came from.
+  fn execute_within<P: Packet + ?Sized>(
+    &mut self,
+    instr: &Instruction,
+    pkt: &P,
+    len: usize,
+  ) -> Result<Option<u32>, VmError> {
     let opcode = instr.opcode;
     let class = instr.class();
     let k = instr.k;
-    let idx = self.index;
 
     let mut jmp_case = false;
-    let ret = match opcode {
-      LDI => {
+    let decoded = Opcode::from_raw(opcode).ok_or(VmError::InvalidOpcode(opcode))?;
+    let size = instr.read_size().ok_or(VmError::InvalidOpcode(opcode));
+    let ret = match decoded {
+      Opcode::Ldi => {
         self.accumulator = k;
         Ok(None)
       },
-      LDW => {
-        self.accumulator = self.ld_u32(k, pkt)?;
-        Ok(None)
-      },
-      LDWI => {
-        self.accumulator = self.ld_u32(idx + k, pkt)?;
+      Opcode::Ldw | Opcode::Ldh | Opcode::Ldb if k >= SKF_AD_OFF => {
+        self.accumulator = self.ld_aux(k, pkt)?;
         Ok(None)
       },
-      LDWM => {
-        if k >= SCRATCH_MEM_SLOTS as u32 {
-          return Err(());
-        }
-        self.accumulator = self.memory[k as usize];
-        Ok(None)
-      },
-      LDH => {
-        self.accumulator = self.ld_u16(k, pkt)?;
+      Opcode::Ldw | Opcode::Ldh | Opcode::Ldb => {
+        self.accumulator = self.load(size?, k, pkt)?;
         Ok(None)
       },
-      LDHI => {
-        self.accumulator = self.ld_u16(idx + k, pkt)?;
+      Opcode::Ldwi | Opcode::Ldhi | Opcode::Ldbi => {
+        let offset = self.ind_offset(k, pkt)?;
+        self.accumulator = self.load(size?, offset, pkt)?;
         Ok(None)
       },
-      LDHM => {
-        if k >= SCRATCH_MEM_SLOTS as u32 {
-          return Err(());
+      Opcode::Ldwm | Opcode::Ldhm | Opcode::Ldbm => {
+        if k >= N as u32 {
+          return Err(VmError::MemorySlotOutOfRange(k as usize));
         }
-        let val = self.memory[k as usize] & 0x0000FFFF;
-        self.accumulator = val;
-        Ok(None)
-      },
-      LDB => {
-        self.accumulator = self.ld_u8(k, pkt)?;
-        Ok(None)
-      },
-      LDBI => {
-        self.accumulator = self.ld_u8(idx + k, pkt)?;
+        self.accumulator = self.memory[k as usize] & size?.mask();
         Ok(None)
       },
-      LDBM => {
-        if k >= SCRATCH_MEM_SLOTS as u32 {
-          return Err(());
-        }
-        let val = self.memory[k as usize] & 0x000000FF;
-        self.accumulator = val;
+      Opcode::Ldl => {
+        self.accumulator = pkt.len() as u32;
         Ok(None)
       },
-      LDXI => {
+      Opcode::Ldxi => {
         self.index = k;
         Ok(None)
       },
-      LDXL => {
-        if k >= pkt.len() as u32 {
-          Err(())
-        } else {
-          self.index = 4 * (pkt[k as usize] & 0x0F) as u32;
-          Ok(None)
-        }
-      },
-      ST => {
-        if k >= SCRATCH_MEM_SLOTS as u32 {
-          return Err(());
-        }
-        self.memory[k as usize] = self.accumulator;
+      Opcode::Ldxw => {
+        self.index = self.load(ReadSize::Word, k, pkt)?;
         Ok(None)
       },
-      STX => {
-        if k >= SCRATCH_MEM_SLOTS as u32 {
-          return Err(());
+      Opcode::Ldxm => {
+        if k >= N as u32 {
+          return Err(VmError::MemorySlotOutOfRange(k as usize));
         }
-        self.memory[k as usize] = self.index;
-        Ok(None)
-      },
-      TXA => {
-        self.accumulator = self.index;
-        Ok(None)
-      },
-      TAX => {
-        self.index = self.accumulator;
+        self.index = self.mem(k as usize);
         Ok(None)
       },
-      RETA => Ok(Some(self.accumulator)),
-      RETK => Ok(Some(k)),
-      ADDX => {
-        self.accumulator += self.index;
+      Opcode::Ldxl => {
+        self.index = pkt.len() as u32;
         Ok(None)
       },
-      SUBX => {
-        self.accumulator -= self.index;
+      Opcode::Ldxb => {
+        self.index = 4 * (self.load(ReadSize::Byte, k, pkt)? & 0x0F);
         Ok(None)
       },
-      MULX => {
-        if self.index == 0 {
-          Err(())
-        } else {
-          self.accumulator *= self.index;
-          Ok(None)
+      Opcode::St => {
+        if k >= N as u32 {
+          return Err(VmError::MemorySlotOutOfRange(k as usize));
         }
-      },
-      DIVX => {
-        self.accumulator /= self.index;
-        Ok(None)
-      },
-      ORX => {
-        self.accumulator |= self.index;
-        Ok(None)
-      },
-      ANDX => {
-        self.accumulator &= self.index;
-        Ok(None)
-      },
-      LSHX => {
-        self.accumulator <<= self.index;
-        Ok(None)
-      },
-      RSHX => {
-        self.accumulator >>= self.index;
+        self.memory[k as usize] = self.accumulator;
         Ok(None)
       },
-      MODX => {
-        if self.index == 0 {
-          Err(())
-        } else {
-          self.accumulator %= self.index;
-          Ok(None)
+      Opcode::Stx => {
+        if k >= N as u32 {
+          return Err(VmError::MemorySlotOutOfRange(k as usize));
         }
-      },
-      XORX => {
-        self.accumulator ^= self.index;
-        Ok(None)
-      },
-      ADDK => {
-        self.accumulator += k;
-        Ok(None)
-      },
-      SUBK => {
-        self.accumulator -= k;
-        Ok(None)
-      },
-      MULK => {
-        self.accumulator *= k;
+        self.memory[k as usize] = self.index;
         Ok(None)
       },
-      DIVK => {
-        if k == 0 {
-          Err(())
-        } else {
-          self.accumulator /= k;
-          Ok(None)
+      Opcode::Sti | Opcode::Stxi => {
+        let slot = self.index as u64 + k as u64;
+        if slot >= N as u64 {
+          return Err(VmError::MemorySlotOutOfRange(slot as usize));
         }
-      },
-      ORK => {
-        self.accumulator |= k;
-        Ok(None)
-      },
-      ANDK => {
-        self.accumulator &= k;
-        Ok(None)
-      },
-      LSHK => {
-        self.accumulator <<= k;
-        Ok(None)
-      },
-      RSHK => {
-        self.accumulator >>= k;
-        Ok(None)
-      },
-      MODK => {
-        if k == 0 {
-          Err(())
+        self.memory[slot as usize] = if decoded == Opcode::Sti {
+          self.accumulator
         } else {
-          self.accumulator %= k;
-          Ok(None)
-        }
-      },
-      XORK => {
-        self.accumulator ^= k;
+          self.index
+        };
         Ok(None)
       },
-      NEG => {
-        self.accumulator = !self.accumulator;
-        Ok(None)
-      },
-      JMP => {
-        self.frame = k;
+      Opcode::Txa => {
+        self.accumulator = self.index;
         Ok(None)
       },
-      JMPEQ => {
-        jmp_case = self.accumulator == k;
+      Opcode::Tax => {
+        self.index = self.accumulator;
         Ok(None)
       },
-      JMPGT => {
-        jmp_case = self.accumulator > k;
+      Opcode::Reta => Ok(Some(self.accumulator)),
+      Opcode::Retk => Ok(Some(k)),
+      Opcode::Neg => {
+        self.accumulator = self.accumulator.wrapping_neg();
         Ok(None)
       },
-      JMPGE => {
-        jmp_case = self.accumulator >= k;
+      Opcode::Jmp => Ok(None),
+      Opcode::Addx
+      | Opcode::Subx
+      | Opcode::Mulx
+      | Opcode::Divx
+      | Opcode::Orx
+      | Opcode::Andx
+      | Opcode::Lshx
+      | Opcode::Rshx
+      | Opcode::Modx
+      | Opcode::Xorx
+      | Opcode::Addk
+      | Opcode::Subk
+      | Opcode::Mulk
+      | Opcode::Divk
+      | Opcode::Ork
+      | Opcode::Andk
+      | Opcode::Lshk
+      | Opcode::Rshk
+      | Opcode::Modk
+      | Opcode::Xork => {
+        let operand = self.operand(instr);
+        self.accumulator = match instr.op() {
+          OP_ADD => self.overflowing(self.accumulator.overflowing_add(operand))?,
+          OP_SUB => self.overflowing(self.accumulator.overflowing_sub(operand))?,
+          OP_MUL => self.overflowing(self.accumulator.overflowing_mul(operand))?,
+          OP_DIV => self.accumulator.checked_div(operand).ok_or(VmError::DivideByZero)?,
+          OP_MOD => self.accumulator.checked_rem(operand).ok_or(VmError::DivideByZero)?,
+          OP_OR => self.accumulator | operand,
+          OP_AND => self.accumulator & operand,
+          OP_XOR => self.accumulator ^ operand,
+          // Shift amounts are masked to their low 5 bits, matching the in-kernel interpreter.
+          OP_LSH => self.accumulator.wrapping_shl(operand),
+          OP_RSH => self.accumulator.wrapping_shr(operand),
+          _ => return Err(VmError::InvalidOpcode(opcode)),
+        };
         Ok(None)
       },
-      JMPSET => {
-        jmp_case = (self.accumulator & k) > 0;
+      Opcode::Jmpeq
+      | Opcode::Jmpgt
+      | Opcode::Jmpge
+      | Opcode::Jmpset
+      | Opcode::Jmpeqx
+      | Opcode::Jmpgtx
+      | Opcode::Jmpgex
+      | Opcode::Jmpsetx
+      | Opcode::Jmpsgt
+      | Opcode::Jmpsge
+      | Opcode::Jmpsgtx
+      | Opcode::Jmpsgex => {
+        let operand = self.operand(instr);
+        jmp_case = match instr.op() {
+          OP_JEQ => self.accumulator == operand,
+          OP_JGT => self.accumulator > operand,
+          OP_JGE => self.accumulator >= operand,
+          OP_JSET => (self.accumulator & operand) > 0,
+          OP_JSGT => self.accumulator as i32 > operand as i32,
+          OP_JSGE => self.accumulator as i32 >= operand as i32,
+          _ => return Err(VmError::InvalidOpcode(opcode)),
+        };
         Ok(None)
       },
-      _ => Err(()),
     };
-    if ret.is_err() {
-      return ret;
-    }
-    self.frame += match class {
-      CLASS_JMP => {
-        if instr.op() != OP_JA && jmp_case {
-          instr.jt as u32
-        } else if instr.op() != OP_JA {
-          instr.jf as u32
-        } else {
-          0
-        }
-      },
-      _ => 1,
+    ret?;
+    #[cfg(feature = "log")]
+    trace!(
+      "({:03}) {:<4} a = {:#x}",
+      self.frame,
+      Mnemonic::from(decoded),
+      self.accumulator
+    );
+    let offset = match class {
+      CLASS_JMP if instr.op() == OP_JA => k,
+      CLASS_JMP if jmp_case => instr.jt as u32,
+      CLASS_JMP => instr.jf as u32,
+      _ => 0,
     };
+    self.advance(offset, len)?;
     ret
   }
 
+  /// Moves the frame pointer past the current instruction and `offset` more.
+  /// Returns Err(ProgramCounterOutOfRange), leaving the frame pointer alone, if that's past `len`.
+  /// Landing exactly on `len` is falling off the end, which the next `step` deals with.
+  fn advance(&mut self, offset: u32, len: usize) -> Result<(), VmError> {
+    let next = self
+      .frame
+      .checked_add(1)
+      .and_then(|next| next.checked_add(offset as usize));
+    match next {
+      Some(next) if next <= len => {
+        self.frame = next;
+        Ok(())
+      },
+      next => Err(VmError::ProgramCounterOutOfRange(next.unwrap_or(usize::MAX))),
+    }
+  }
+
+  /// Returns the instruction under the frame pointer.
+  /// Returns Err if the frame pointer has left `prog`.
+  fn fetch<'p>(&self, prog: &'p [Instruction]) -> Result<&'p Instruction, VmError> {
+    prog
+      .get(self.frame)
+      .ok_or(VmError::ProgramCounterOutOfRange(self.frame))
+  }
+
+  /// Returns whether execution has just fallen off the end of `prog` and the end of program
+  /// policy says to drop the packet rather than fail.
+  fn implicit_drop(&self, prog: &[Instruction]) -> bool {
+    self.end_of_program == EndOfProgramPolicy::ImplicitDrop && self.frame == prog.len()
+  }
+
+  /// Executes the instruction under the frame pointer, leaving the frame pointer at the next
+  /// instruction to run.
+  /// Returns Ok with whether the program is still running, Err otherwise.
+  pub fn step<P: Packet + ?Sized>(&mut self, prog: &[Instruction], pkt: &P) -> Result<StepResult, VmError> {
+    if self.implicit_drop(prog) {
+      return Ok(StepResult::Returned(0));
+    }
+    let instr = self.fetch(prog)?;
+    match self.execute_within(instr, pkt, prog.len())? {
+      Some(ret) => Ok(StepResult::Returned(ret)),
+      None => Ok(StepResult::Running),
+    }
+  }
+
   /// Runs the program stored as a slice of instructions.
   /// Returns Ok with accept/reject if the program completes, Err otherwise.
-  pub fn run_program(&mut self, prog: &[Instruction], pkt: &[u8]) -> Result<u32, ()> {
+  /// Returns Err if more than the instruction limit are executed.
+  pub fn run_program<P: Packet + ?Sized>(&mut self, prog: &[Instruction], pkt: &P) -> Result<u32, VmError> {
+    self.run_program_traced(prog, pkt, |_, _, _| {})
+  }
+
+  /// Installs `init` as the registers and scratch memory, then runs the program like
+  /// `run_program`. The run starts at `init.frame`.
+  pub fn run_with_state<P: Packet + ?Sized>(
+    &mut self,
+    prog: &[Instruction],
+    pkt: &P,
+    init: &MachineState<N>,
+  ) -> Result<u32, VmError> {
+    self.restore(init);
+    self.run_program(prog, pkt)
+  }
+
+  /// Runs the program like `run_program`, calling `trace` with the frame index, the instruction
+  /// and the machine before each instruction executes.
+  pub fn run_program_traced<P, F>(&mut self, prog: &[Instruction], pkt: &P, mut trace: F) -> Result<u32, VmError>
+  where
+    P: Packet + ?Sized,
+    F: FnMut(usize, &Instruction, &PsuedoMachine<N>),
+  {
+    let mut executed = 0;
     loop {
-      if self.frame as usize >= prog.len() {
-        return Err(());
+      if executed >= self.insn_limit {
+        return Err(VmError::InstructionLimitExceeded(self.insn_limit));
+      }
+      if self.implicit_drop(prog) {
+        return Ok(0);
       }
-      let ref instr = prog[self.frame as usize];
-      let res = self.execute(instr, pkt);
-      if res.is_err() {
-        return Err(());
+      executed += 1;
+      trace(self.frame, self.fetch(prog)?, self);
+      if let StepResult::Returned(ret) = self.step(prog, pkt)? {
+        return Ok(ret);
       }
-      match res.unwrap() {
-        Some(ret) => return Ok(ret),
-        _ => continue,
-      };
     }
   }
 
+  /// Runs the program like `run_program`.
+  /// Returns Ok with the return value and the number of instructions executed, including the
+  /// final RET, if the program completes, Err otherwise.
+  pub fn run_program_metered<P: Packet + ?Sized>(
+    &mut self,
+    prog: &[Instruction],
+    pkt: &P,
+  ) -> Result<(u32, u64), VmError> {
+    let mut executed = 0;
+    let ret = self.run_program_traced(prog, pkt, |_, _, _| executed += 1)?;
+    Ok((ret, executed))
+  }
+
+  /// Runs the program like `run_program`, marking each instruction it executes in `coverage`.
+  #[cfg(feature = "std")]
+  pub fn run_program_covered<P: Packet + ?Sized>(
+    &mut self,
+    prog: &[Instruction],
+    pkt: &P,
+    coverage: &mut Coverage,
+  ) -> Result<u32, VmError> {
+    self.run_program_traced(prog, pkt, |frame, _, _| coverage.record(frame))
+  }
+
+  /// Runs the program as a socket filter.
+  /// Returns Ok with whether the packet was accepted if the program completes, Err otherwise.
+  pub fn run_filter<P: Packet + ?Sized>(&mut self, prog: &[Instruction], pkt: &P) -> Result<FilterResult, VmError> {
+    self.run_program(prog, pkt).map(FilterResult::from_ret)
+  }
+
+  /// Runs the program over each packet in turn, resetting the machine before each one.
+  /// Returns the result of each run, in the same order as `pkts`.
+  #[cfg(feature = "std")]
+  pub fn run_batch<P: Packet>(&mut self, prog: &[Instruction], pkts: &[P]) -> Vec<Result<u32, VmError>> {
+    pkts
+      .iter()
+      .map(|pkt| {
+        self.reset();
+        self.run_program(prog, pkt)
+      })
+      .collect()
+  }
+
   /// Runs the program stored in a byte buffer.
   /// Returns Ok with accept/reject if the program completes, Err otherwise.
-  pub fn run_program_bytes(&mut self, prog: &[u8], pkt: &[u8]) -> Result<u32, ()> {
-    if prog.len() % 8 > 0 {
-      return Err(());
-    }
-    let instrs = unsafe { slice::from_raw_parts(prog.as_ptr() as *const Instruction, prog.len() / 8) };
-    self.run_program(instrs, pkt)
+  #[cfg(feature = "std")]
+  pub fn run_program_bytes<P: Packet + ?Sized>(&mut self, prog: &[u8], pkt: &P) -> Result<u32, VmError> {
+    let instrs = Instruction::from_sock_filter_bytes(prog)?;
+    self.run_program(&instrs, pkt)
+  }
+
+  /// Like `run_program_bytes`, but runs the verifier over the decoded program first, so bytes from
+  /// an untrusted source are rejected up front rather than failing partway through a run.
+  /// Returns Err with the verifier's complaint if the program isn't safe to run.
+  #[cfg(feature = "std")]
+  pub fn run_program_bytes_verified<P: Packet + ?Sized>(&mut self, prog: &[u8], pkt: &P) -> Result<u32, VmError> {
+    let instrs = Instruction::from_sock_filter_bytes(prog)?;
+    verify(&instrs)?;
+    self.run_program(&instrs, pkt)
   }
 }
 
+/// Builds the error for a load at `offset` that doesn't fit in `buf`.
+fn out_of_bounds<P: Packet + ?Sized>(offset: u64, buf: &P) -> VmError {
+  VmError::OutOfBounds { offset, len: buf.len() }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
+  #[cfg(feature = "std")]
+  use std::slice;
 
   #[test]
   fn ldi() {
     let mut pm = PsuedoMachine::new();
     let instr = Instruction::new(CLASS_LD | MODE_IMM | SIZE_W, 0, 0, 0xDEADBEEF);
-    let pkt = [0 as u8; 64];
+    let pkt = [0u8; 64];
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
     assert!(pm.accumulator() == 0xDEADBEEF);
   }
 
   #[test]
   fn ldw() {
     let mut pm = PsuedoMachine::new();
-    let mut pkt = [0 as u8; 64];
+    let mut pkt = [0u8; 64];
     pkt[3] = 0xDE;
     pkt[4] = 0xAD;
     pkt[5] = 0xBE;
     pkt[6] = 0xEF;
     let instr = Instruction::new(MODE_ABS | SIZE_W | CLASS_LD, 0, 0, 3);
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
+    assert!(pm.accumulator() == 0xDEADBEEF);
+  }
+
+  #[test]
+  fn ld_last_valid_offset() {
+    let mut pm = PsuedoMachine::new();
+    let mut pkt = [0u8; 64];
+    pkt[60] = 0xDE;
+    pkt[61] = 0xAD;
+    pkt[62] = 0xBE;
+    pkt[63] = 0xEF;
+    let ldw = |k| Instruction::new(MODE_ABS | SIZE_W | CLASS_LD, 0, 0, k);
+    let ldh = |k| Instruction::new(MODE_ABS | SIZE_H | CLASS_LD, 0, 0, k);
+    pm.execute(&ldw(60), &pkt).unwrap();
     assert!(pm.accumulator() == 0xDEADBEEF);
+    assert!(pm.execute(&ldw(61), &pkt).is_err());
+    pm.execute(&ldh(62), &pkt).unwrap();
+    assert!(pm.accumulator() == 0xBEEF);
+    assert!(pm.execute(&ldh(63), &pkt).is_err());
+  }
+
+  #[test]
+  fn out_of_bounds_error() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let instr = Instruction::new(MODE_ABS | SIZE_W | CLASS_LD, 0, 0, 62);
+    assert!(pm.execute(&instr, &pkt) == Err(VmError::OutOfBounds { offset: 62, len: 64 }));
+    pm.set_index(0xFFFFFFFF);
+    let instr = Instruction::new(MODE_IND | SIZE_H | CLASS_LD, 0, 0, 2);
+    assert!(
+      pm.execute(&instr, &pkt)
+        == Err(VmError::OutOfBounds {
+          offset: 0x100000001,
+          len: 64
+        })
+    );
+    let instr = Instruction::new(0xFFFF, 0, 0, 0);
+    assert!(pm.execute(&instr, &pkt) == Err(VmError::InvalidOpcode(0xFFFF)));
   }
 
   #[test]
   fn ldwm() {
     let mut pm = PsuedoMachine::new();
-    let pkt = [0 as u8; 64];
+    let pkt = [0u8; 64];
     pm.set_memory(5, 0xDEADBEEF);
     let instr = Instruction::new(MODE_MEM | SIZE_W | CLASS_LD, 0, 0, 5);
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
     assert!(pm.accumulator() == 0xDEADBEEF);
   }
 
   #[test]
   fn ldh() {
     let mut pm = PsuedoMachine::new();
-    let mut pkt = [0 as u8; 64];
+    let mut pkt = [0u8; 64];
     pkt[3] = 0xDE;
     pkt[4] = 0xAD;
     pkt[5] = 0xBE;
     pkt[6] = 0xEF;
     let instr = Instruction::new(MODE_ABS | SIZE_H | CLASS_LD, 0, 0, 3);
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
     assert!(pm.accumulator() == 0xDEAD);
   }
 
   #[test]
   fn ldhm() {
     let mut pm = PsuedoMachine::new();
-    let pkt = [0 as u8; 64];
+    let pkt = [0u8; 64];
     pm.set_memory(5, 0xDEADBEEF);
     let instr = Instruction::new(MODE_MEM | SIZE_H | CLASS_LD, 0, 0, 5);
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
     assert!(pm.accumulator() == 0xBEEF);
   }
 
   #[test]
   fn ldb() {
     let mut pm = PsuedoMachine::new();
-    let mut pkt = [0 as u8; 64];
+    let mut pkt = [0u8; 64];
     pkt[3] = 0xDE;
     pkt[4] = 0xAD;
     pkt[5] = 0xBE;
     pkt[6] = 0xEF;
     let instr = Instruction::new(MODE_ABS | SIZE_B | CLASS_LD, 0, 0, 3);
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
     assert!(pm.accumulator() == 0xDE);
   }
 
+  #[test]
+  fn ldb_out_of_bounds() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let instr = Instruction::new(MODE_ABS | SIZE_B | CLASS_LD, 0, 0, 64);
+    assert!(pm.execute(&instr, &pkt) == Err(VmError::OutOfBounds { offset: 64, len: 64 }));
+  }
+
   #[test]
   fn ldbm() {
     let mut pm = PsuedoMachine::new();
-    let pkt = [0 as u8; 64];
+    let pkt = [0u8; 64];
     pm.set_memory(5, 0xDEADBEEF);
     let instr = Instruction::new(MODE_MEM | SIZE_B | CLASS_LD, 0, 0, 5);
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
     assert!(pm.accumulator() == 0xEF);
   }
 
   #[test]
   fn ldwi() {
     let mut pm = PsuedoMachine::new();
-    let mut pkt = [0 as u8; 64];
+    let mut pkt = [0u8; 64];
     pkt[4] = 0xDE;
     pkt[5] = 0xAD;
     pkt[6] = 0xBE;
@@ -497,14 +886,14 @@ mod tests {
     pm.set_index(1);
     let instr = Instruction::new(MODE_IND | SIZE_W | CLASS_LD, 0, 0, 3);
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
     assert!(pm.accumulator() == 0xDEADBEEF);
   }
 
   #[test]
   fn ldhi() {
     let mut pm = PsuedoMachine::new();
-    let mut pkt = [0 as u8; 64];
+    let mut pkt = [0u8; 64];
     pkt[4] = 0xDE;
     pkt[5] = 0xAD;
     pkt[6] = 0xBE;
@@ -512,14 +901,14 @@ mod tests {
     pm.set_index(1);
     let instr = Instruction::new(MODE_IND | SIZE_H | CLASS_LD, 0, 0, 3);
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
     assert!(pm.accumulator() == 0xDEAD);
   }
 
   #[test]
   fn ldbi() {
     let mut pm = PsuedoMachine::new();
-    let mut pkt = [0 as u8; 64];
+    let mut pkt = [0u8; 64];
     pkt[4] = 0xDE;
     pkt[5] = 0xAD;
     pkt[6] = 0xBE;
@@ -527,102 +916,268 @@ mod tests {
     pm.set_index(1);
     let instr = Instruction::new(MODE_IND | SIZE_B | CLASS_LD, 0, 0, 3);
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
     assert!(pm.accumulator() == 0xDE);
   }
 
   #[test]
-  fn ldxi() {
+  fn ldbi_out_of_bounds() {
     let mut pm = PsuedoMachine::new();
-    let instr = Instruction::new(MODE_IMM | SIZE_W | CLASS_LDX, 0, 0, 14);
-    let pkt = [0 as u8; 64];
-    let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
-    assert!(pm.index() == 14);
+    let pkt = [0u8; 64];
+    pm.set_index(61);
+    let instr = Instruction::new(MODE_IND | SIZE_B | CLASS_LD, 0, 0, 3);
+    assert!(pm.execute(&instr, &pkt).is_err());
   }
 
   #[test]
-  fn ldxl() {
+  fn ldl() {
     let mut pm = PsuedoMachine::new();
-    let instr = Instruction::new(MODE_LEN | SIZE_W | SIZE_B | CLASS_LDX, 0, 0, 3);
-    let mut pkt = [0 as u8; 64];
-    pkt[3] = 0x1A;
+    let instr = Instruction::new(MODE_LEN | SIZE_W | CLASS_LD, 0, 0, 0);
+    let pkt = [0u8; 64];
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
-    assert!(pm.index() == 40);
+    assert!(ret.unwrap().is_none());
+    assert!(pm.accumulator() == 64);
   }
 
   #[test]
-  fn st() {
+  fn indirect_offset_overflow() {
     let mut pm = PsuedoMachine::new();
-    let instr = Instruction::new(MODE_MEM | CLASS_ST, 0, 0, 8);
-    let pkt = [0 as u8; 64];
-    pm.set_accumulator(0xDEADBEEF);
-    let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
-    assert!(pm.memory()[8] == 0xDEADBEEF);
+    let pkt = [0u8; 64];
+    pm.set_index(0xFFFFFFFF);
+    for &size in [SIZE_W, SIZE_H, SIZE_B].iter() {
+      let instr = Instruction::new(MODE_IND | size | CLASS_LD, 0, 0, 5);
+      assert!(pm.execute(&instr, &pkt).is_err());
+      assert!(pm.accumulator() == 0);
+    }
   }
 
   #[test]
-  fn stx() {
+  fn indirect_load_size_past_end() {
+    let pkt = [0u8; 64];
+    for &(size, len) in [(SIZE_W, 4), (SIZE_H, 2)].iter() {
+      let instr = Instruction::new(MODE_IND | size | CLASS_LD, 0, 0, 10);
+      let mut pm = PsuedoMachine::new();
+      pm.set_index(64 - len + 1 - 10);
+      assert!(
+        pm.execute(&instr, &pkt)
+          == Err(VmError::OutOfBounds {
+            offset: 64 - len as u64 + 1,
+            len: 64
+          })
+      );
+      assert!(pm.frame() == 0);
+      pm.set_index(64 - len - 10);
+      assert!(pm.execute(&instr, &pkt) == Ok(None));
+    }
+  }
+
+  #[test]
+  fn ldxi() {
     let mut pm = PsuedoMachine::new();
-    let instr = Instruction::new(MODE_MEM | CLASS_STX, 0, 0, 8);
-    let pkt = [0 as u8; 64];
-    pm.set_index(0xDEADBEEF);
+    let instr = Instruction::new(MODE_IMM | SIZE_W | CLASS_LDX, 0, 0, 14);
+    let pkt = [0u8; 64];
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
-    assert!(pm.memory()[8] == 0xDEADBEEF);
+    assert!(ret.unwrap().is_none());
+    assert!(pm.index() == 14);
+    assert!(pm.accumulator() == 0);
+    assert!(pm.frame() == 1);
   }
 
   #[test]
-  fn txa() {
+  fn ldxi_sizes() {
+    let pkt = [0u8; 64];
     let mut pm = PsuedoMachine::new();
-    let instr = Instruction::new(CLASS_MISC | OP_TXA, 0, 0, 0);
-    let pkt = [0 as u8; 64];
-    pm.set_index(0xDEADBEEF);
-    let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
-    assert!(pm.accumulator() == 0xDEADBEEF);
+    let word = Instruction::new(MODE_IMM | SIZE_W | CLASS_LDX, 0, 0, 0x1234);
+    assert!(pm.execute(&word, &pkt) == Ok(None));
+    assert!(pm.index() == 0x1234);
+    for &size in [SIZE_H, SIZE_B].iter() {
+      let opcode = MODE_IMM | size | CLASS_LDX;
+      let mut pm = PsuedoMachine::new();
+      let ret = pm.execute(&Instruction::new(opcode, 0, 0, 0x1234), &pkt);
+      assert!(ret == Err(VmError::InvalidOpcode(opcode)));
+      assert!(pm.index() == 0);
+      assert!(pm.frame() == 0);
+    }
   }
 
   #[test]
-  fn tax() {
+  fn ldxw() {
     let mut pm = PsuedoMachine::new();
-    let instr = Instruction::new(CLASS_MISC | OP_TAX, 0, 0, 0);
-    let pkt = [0 as u8; 64];
-    pm.set_accumulator(0xDEADBEEF);
+    let mut pkt = [0u8; 64];
+    pkt[3] = 0xDE;
+    pkt[4] = 0xAD;
+    pkt[5] = 0xBE;
+    pkt[6] = 0xEF;
+    let instr = Instruction::new(MODE_ABS | SIZE_W | CLASS_LDX, 0, 0, 3);
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap() == None);
+    assert!(ret.unwrap().is_none());
     assert!(pm.index() == 0xDEADBEEF);
+    assert!(pm.accumulator() == 0);
   }
 
   #[test]
-  fn reta() {
+  fn ldxl() {
     let mut pm = PsuedoMachine::new();
-    let instr = Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0);
-    let pkt = [0 as u8; 64];
-    pm.set_accumulator(0xDEADBEEF);
+    let instr = Instruction::new(MODE_LEN | SIZE_W | CLASS_LDX, 0, 0, 0);
+    let pkt = [0u8; 64];
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap().unwrap() == 0xDEADBEEF);
+    assert!(ret.unwrap().is_none());
+    assert!(pm.index() == 64);
   }
 
   #[test]
-  fn retk() {
+  fn ldxb() {
     let mut pm = PsuedoMachine::new();
-    let instr = Instruction::new(CLASS_RET | RVAL_K, 0, 0, 0xDEADBEEF);
-    let pkt = [0 as u8; 64];
+    let instr = Instruction::new(MODE_MSH | SIZE_B | CLASS_LDX, 0, 0, 3);
+    let mut pkt = [0u8; 64];
+    pkt[3] = 0x1A;
     let ret = pm.execute(&instr, &pkt);
-    assert!(ret.unwrap().unwrap() == 0xDEADBEEF);
+    assert!(ret.unwrap().is_none());
+    assert!(pm.index() == 40);
   }
 
   #[test]
-  fn alu_index() {
+  fn ldxb_ip_header_len() {
     let mut pm = PsuedoMachine::new();
-    let pkt = [0 as u8; 64];
-    let prog = vec![
-      Instruction::new(CLASS_ALU | OP_NEG, 0, 0, 0),
-      Instruction::new(CLASS_ALU | SRC_X | OP_XOR, 0, 0, 0),
-      Instruction::new(CLASS_ALU | SRC_X | OP_ADD, 0, 0, 0),
+    let instr = Instruction::new(MODE_MSH | SIZE_B | CLASS_LDX, 0, 0, 0);
+    let mut pkt = [0u8; 64];
+    pkt[0] = 0x45;
+    let ret = pm.execute(&instr, &pkt);
+    assert!(ret.unwrap().is_none());
+    assert!(pm.index() == 20);
+    let instr = Instruction::new(MODE_MSH | SIZE_B | CLASS_LDX, 0, 0, 64);
+    assert!(pm.execute(&instr, &pkt).is_err());
+  }
+
+  #[test]
+  fn st() {
+    let mut pm = PsuedoMachine::new();
+    let instr = Instruction::new(ST, 0, 0, 8);
+    let pkt = [0u8; 64];
+    pm.set_accumulator(0xDEADBEEF);
+    let ret = pm.execute(&instr, &pkt);
+    assert!(ret.unwrap().is_none());
+    assert!(pm.memory()[8] == 0xDEADBEEF);
+  }
+
+  #[test]
+  fn stx() {
+    let mut pm = PsuedoMachine::new();
+    let instr = Instruction::new(STX, 0, 0, 8);
+    let pkt = [0u8; 64];
+    pm.set_index(0xDEADBEEF);
+    let ret = pm.execute(&instr, &pkt);
+    assert!(ret.unwrap().is_none());
+    assert!(pm.memory()[8] == 0xDEADBEEF);
+  }
+
+  #[test]
+  fn st_ld_round_trip() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let prog = vec![
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 0xDEADBEEF),
+      Instruction::new(ST, 0, 0, 3),
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 0),
+      Instruction::new(MODE_MEM | SIZE_W | CLASS_LDX, 0, 0, 3),
+      Instruction::new(MODE_MEM | SIZE_W | CLASS_LD, 0, 0, 3),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    let ret = pm.run_program(prog.as_slice(), &pkt).unwrap();
+    assert!(ret == 0xDEADBEEF);
+    assert!(pm.index() == 0xDEADBEEF);
+    let instr = Instruction::new(MODE_MEM | SIZE_W | CLASS_LDX, 0, 0, SCRATCH_MEM_SLOTS as u32);
+    assert!(pm.execute(&instr, &pkt).is_err());
+  }
+
+  #[test]
+  fn st_out_of_range() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let err = Err(VmError::MemorySlotOutOfRange(SCRATCH_MEM_SLOTS));
+    let instr = Instruction::new(ST, 0, 0, SCRATCH_MEM_SLOTS as u32);
+    assert!(pm.execute(&instr, &pkt) == err);
+    let instr = Instruction::new(STX, 0, 0, SCRATCH_MEM_SLOTS as u32);
+    assert!(pm.execute(&instr, &pkt) == err);
+  }
+
+  #[test]
+  fn txa() {
+    let mut pm = PsuedoMachine::new();
+    let instr = Instruction::new(CLASS_MISC | OP_TXA, 0, 0, 0);
+    let pkt = [0u8; 64];
+    pm.set_index(0xDEADBEEF);
+    let ret = pm.execute(&instr, &pkt);
+    assert!(ret.unwrap().is_none());
+    assert!(pm.accumulator() == 0xDEADBEEF);
+  }
+
+  #[test]
+  fn tax() {
+    let mut pm = PsuedoMachine::new();
+    let instr = Instruction::new(CLASS_MISC | OP_TAX, 0, 0, 0);
+    let pkt = [0u8; 64];
+    pm.set_accumulator(0xDEADBEEF);
+    let ret = pm.execute(&instr, &pkt);
+    assert!(ret.unwrap().is_none());
+    assert!(pm.index() == 0xDEADBEEF);
+  }
+
+  #[test]
+  fn tax_then_indirect_load() {
+    let mut pm = PsuedoMachine::new();
+    let mut pkt = [0u8; 64];
+    pkt[14] = 0x45;
+    pkt[23] = 0x06;
+    let prog = vec![
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 14),
+      Instruction::new(CLASS_MISC | OP_TAX, 0, 0, 0),
+      Instruction::new(MODE_IND | SIZE_B | CLASS_LD, 0, 0, 9),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    assert!(pm.run_program(prog.as_slice(), &pkt).unwrap() == 0x06);
+    assert!(pm.index() == 14);
+  }
+
+  #[test]
+  fn reta() {
+    let mut pm = PsuedoMachine::new();
+    let instr = Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0);
+    let pkt = [0u8; 64];
+    pm.set_accumulator(0xDEADBEEF);
+    let ret = pm.execute(&instr, &pkt);
+    assert!(ret.unwrap().unwrap() == 0xDEADBEEF);
+  }
+
+  #[test]
+  fn retk() {
+    let mut pm = PsuedoMachine::new();
+    let instr = Instruction::new(CLASS_RET | RVAL_K, 0, 0, 0xDEADBEEF);
+    let pkt = [0u8; 64];
+    let ret = pm.execute(&instr, &pkt);
+    assert!(ret.unwrap().unwrap() == 0xDEADBEEF);
+  }
+
+  #[test]
+  fn ret_terminates_program() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let prog = vec![
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 7),
+      Instruction::new(CLASS_RET | RVAL_K, 0, 0, 42),
+      Instruction::new(0xFFFF, 0, 0, 0),
+    ];
+    assert!(pm.run_program(prog.as_slice(), &pkt).unwrap() == 42);
+  }
+
+  #[test]
+  fn alu_index() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let prog = vec![
+      Instruction::new(CLASS_ALU | OP_NEG, 0, 0, 0),
+      Instruction::new(CLASS_ALU | SRC_X | OP_XOR, 0, 0, 0),
+      Instruction::new(CLASS_ALU | SRC_X | OP_ADD, 0, 0, 0),
       Instruction::new(CLASS_ALU | SRC_X | OP_SUB, 0, 0, 0),
       Instruction::new(CLASS_ALU | SRC_X | OP_MUL, 0, 0, 0),
       Instruction::new(CLASS_ALU | SRC_X | OP_DIV, 0, 0, 0),
@@ -632,7 +1187,7 @@ mod tests {
       Instruction::new(CLASS_ALU | SRC_X | OP_RSH, 0, 0, 0),
       Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
     ];
-    pm.set_accumulator(!0xBEEF);
+    pm.set_accumulator(0u32.wrapping_sub(0xBEEF));
     pm.set_index(2);
     let ret = pm.run_program(prog.as_slice(), &pkt).unwrap();
     let mut expected = 0xBEEF;
@@ -653,7 +1208,7 @@ mod tests {
   #[test]
   fn alu_imm() {
     let mut pm = PsuedoMachine::new();
-    let pkt = [0 as u8; 64];
+    let pkt = [0u8; 64];
     let prog = vec![
       Instruction::new(CLASS_ALU | OP_NEG, 0, 0, 0),
       Instruction::new(CLASS_ALU | SRC_K | OP_XOR, 0, 0, 2),
@@ -667,7 +1222,7 @@ mod tests {
       Instruction::new(CLASS_ALU | SRC_K | OP_RSH, 0, 0, 2),
       Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
     ];
-    pm.set_accumulator(!0xBEEF);
+    pm.set_accumulator(0u32.wrapping_sub(0xBEEF));
     let ret = pm.run_program(prog.as_slice(), &pkt).unwrap();
     let mut expected = 0xBEEF;
     expected ^= 2;
@@ -684,38 +1239,1050 @@ mod tests {
     assert!(pm.accumulator() == expected % 2);
   }
 
+  #[test]
+  fn add_wrapping() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(0xFFFFFFFF);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_ADD, 0, 0, 2), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 1);
+    pm.set_accumulator(0xFFFFFFFF);
+    pm.set_index(3);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_ADD, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 2);
+  }
+
+  #[test]
+  fn add_checked() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let add = Instruction::new(CLASS_ALU | SRC_K | OP_ADD, 0, 0, 2);
+    pm.set_accumulator(0xFFFFFFFF);
+    pm.execute(&add, &pkt).unwrap();
+    assert!(pm.accumulator() == 1);
+
+    pm.set_arithmetic_mode(ArithmeticMode::Checked);
+    pm.set_accumulator(0xFFFFFFFD);
+    pm.execute(&add, &pkt).unwrap();
+    assert!(pm.accumulator() == 0xFFFFFFFF);
+    assert!(pm.execute(&add, &pkt) == Err(VmError::ArithmeticOverflow));
+    assert!(pm.accumulator() == 0xFFFFFFFF);
+    assert!(pm.frame() == 2);
+    pm.set_index(1);
+    let add_x = Instruction::new(CLASS_ALU | SRC_X | OP_ADD, 0, 0, 0);
+    assert!(pm.execute(&add_x, &pkt) == Err(VmError::ArithmeticOverflow));
+    pm.set_accumulator(0);
+    assert!(
+      pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_SUB, 0, 0, 1), &pkt) == Err(VmError::ArithmeticOverflow)
+    );
+    pm.set_accumulator(0x80000000);
+    assert!(
+      pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_MUL, 0, 0, 2), &pkt) == Err(VmError::ArithmeticOverflow)
+    );
+  }
+
+  #[test]
+  fn sub_wrapping() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(1);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_SUB, 0, 0, 3), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0xFFFFFFFE);
+    pm.set_accumulator(0);
+    pm.set_index(1);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_SUB, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0xFFFFFFFF);
+  }
+
+  #[test]
+  fn mul_wrapping() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(0x12345678);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_MUL, 0, 0, 0x100), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0x34567800);
+    pm.set_accumulator(0x80000001);
+    pm.set_index(2);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_MUL, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 2);
+    pm.set_index(0);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_MUL, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0);
+  }
+
+  #[test]
+  fn div() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(100);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_DIV, 0, 0, 7), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 14);
+    pm.set_index(4);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_DIV, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 3);
+  }
+
+  #[test]
+  fn div_by_zero() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(100);
+    let err = Err(VmError::DivideByZero);
+    assert!(pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_DIV, 0, 0, 0), &pkt) == err);
+    assert!(pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_DIV, 0, 0, 0), &pkt) == err);
+    assert!(pm.accumulator() == 100);
+    assert!(pm.frame() == 0);
+  }
+
+  #[test]
+  fn modulo() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(100);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_MOD, 0, 0, 7), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 2);
+    pm.set_accumulator(100);
+    pm.set_index(8);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_MOD, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 4);
+  }
+
+  #[test]
+  fn modulo_by_zero() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(100);
+    let err = Err(VmError::DivideByZero);
+    assert!(pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_MOD, 0, 0, 0), &pkt) == err);
+    assert!(pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_MOD, 0, 0, 0), &pkt) == err);
+    assert!(pm.accumulator() == 100);
+    assert!(pm.frame() == 0);
+  }
+
+  #[test]
+  fn and() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(0x4000);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_AND, 0, 0, 0x1FFF), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0);
+    pm.set_accumulator(0x2345);
+    pm.set_index(0x1FFF);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_AND, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0x0345);
+  }
+
+  #[test]
+  fn or() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(0xDE00);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_OR, 0, 0, 0xAD), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0xDEAD);
+    pm.set_index(0xBEEF0000);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_OR, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0xBEEFDEAD);
+  }
+
+  #[test]
+  fn xor() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(0xFF00FF00);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_XOR, 0, 0, 0xFFFFFFFF), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0x00FF00FF);
+    pm.set_index(0x00FF00FF);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_XOR, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0);
+  }
+
+  #[test]
+  fn shift() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    pm.set_accumulator(0xF0);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_LSH, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0xF0);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_RSH, 0, 0, 4), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0x0F);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_K | OP_LSH, 0, 0, 36), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0xF0);
+    pm.set_index(0);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_RSH, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0xF0);
+    pm.set_index(33);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_RSH, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0x78);
+    pm.set_index(32);
+    pm.execute(&Instruction::new(CLASS_ALU | SRC_X | OP_LSH, 0, 0, 0), &pkt)
+      .unwrap();
+    assert!(pm.accumulator() == 0x78);
+  }
+
+  #[test]
+  fn neg() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let instr = Instruction::new(CLASS_ALU | OP_NEG, 0, 0, 0xDEADBEEF);
+    pm.set_accumulator(5);
+    pm.set_index(7);
+    pm.execute(&instr, &pkt).unwrap();
+    assert!(pm.accumulator() == 0xFFFFFFFB);
+    pm.execute(&instr, &pkt).unwrap();
+    assert!(pm.accumulator() == 5);
+    pm.set_accumulator(0);
+    pm.execute(&instr, &pkt).unwrap();
+    assert!(pm.accumulator() == 0);
+  }
+
   #[test]
   fn jump() {
     let mut pm = PsuedoMachine::new();
-    let pkt = [0 as u8; 64];
+    let pkt = [0u8; 64];
     let mut instr = Instruction::new(CLASS_JMP | OP_JA, 0, 0, 10);
     pm.set_accumulator(100);
     pm.execute(&instr, &pkt).unwrap();
-    assert!(pm.frame() == 10);
+    assert!(pm.frame() == 11);
     instr = Instruction::new(CLASS_JMP | OP_JEQ, 2, 1, 100);
     pm.execute(&instr, &pkt).unwrap();
-    assert!(pm.frame() == 12);
+    assert!(pm.frame() == 14);
     instr = Instruction::new(CLASS_JMP | OP_JEQ, 1, 2, 3);
     pm.execute(&instr, &pkt).unwrap();
-    assert!(pm.frame() == 14);
+    assert!(pm.frame() == 17);
     instr = Instruction::new(CLASS_JMP | OP_JGT, 2, 1, 99);
     pm.execute(&instr, &pkt).unwrap();
-    assert!(pm.frame() == 16);
+    assert!(pm.frame() == 20);
     instr = Instruction::new(CLASS_JMP | OP_JGE, 2, 1, 99);
     pm.execute(&instr, &pkt).unwrap();
-    assert!(pm.frame() == 18);
+    assert!(pm.frame() == 23);
     instr = Instruction::new(CLASS_JMP | OP_JGE, 2, 1, 100);
     pm.execute(&instr, &pkt).unwrap();
-    assert!(pm.frame() == 20);
+    assert!(pm.frame() == 26);
     instr = Instruction::new(CLASS_JMP | OP_JSET, 2, 1, 100);
     pm.execute(&instr, &pkt).unwrap();
-    assert!(pm.frame() == 22);
+    assert!(pm.frame() == 29);
+  }
+
+  #[test]
+  fn ja() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let instr = Instruction::new(CLASS_JMP | OP_JA, 1, 2, 5);
+    pm.execute(&instr, &pkt).unwrap();
+    assert!(pm.frame() == 6);
+    pm.execute(&instr, &pkt).unwrap();
+    assert!(pm.frame() == 12);
+  }
+
+  #[test]
+  fn conditional_jumps() {
+    let pkt = [0u8; 64];
+    let cases = [
+      (OP_JEQ, 100, true),
+      (OP_JEQ, 101, false),
+      (OP_JGT, 99, true),
+      (OP_JGT, 100, false),
+      (OP_JGE, 100, true),
+      (OP_JGE, 101, false),
+      (OP_JSET, 0x04, true),
+      (OP_JSET, 0x03, false),
+    ];
+    for &(op, k, taken) in cases.iter() {
+      let mut pm = PsuedoMachine::new();
+      pm.set_accumulator(100);
+      pm.execute(&Instruction::new(CLASS_JMP | op, 3, 5, k), &pkt).unwrap();
+      assert!(pm.frame() == if taken { 4 } else { 6 });
+    }
+  }
+
+  #[test]
+  fn jeq_index() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let instr = Instruction::new(CLASS_JMP | SRC_X | OP_JEQ, 3, 5, 0);
+    pm.set_accumulator(100);
+    pm.set_index(100);
+    pm.execute(&instr, &pkt).unwrap();
+    assert!(pm.frame() == 4);
+    pm.set_index(101);
+    pm.execute(&instr, &pkt).unwrap();
+    assert!(pm.frame() == 10);
+  }
+
+  #[test]
+  fn jgt_jge_boundary() {
+    let pkt = [0u8; 64];
+    let cases = [
+      (CLASS_JMP | SRC_K | OP_JGT, false),
+      (CLASS_JMP | SRC_X | OP_JGT, false),
+      (CLASS_JMP | SRC_K | OP_JGE, true),
+      (CLASS_JMP | SRC_X | OP_JGE, true),
+    ];
+    for &(opcode, taken) in cases.iter() {
+      let mut pm = PsuedoMachine::new();
+      pm.set_accumulator(100);
+      pm.set_index(100);
+      pm.execute(&Instruction::new(opcode, 3, 5, 100), &pkt).unwrap();
+      assert!(pm.frame() == if taken { 4 } else { 6 });
+    }
+    let mut pm = PsuedoMachine::new();
+    pm.set_accumulator(100);
+    pm.set_index(99);
+    pm.execute(&Instruction::new(CLASS_JMP | SRC_X | OP_JGT, 3, 5, 0), &pkt)
+      .unwrap();
+    assert!(pm.frame() == 4);
+  }
+
+  #[test]
+  fn jset() {
+    let pkt = [0u8; 64];
+    // TCP SYN is set, FIN is clear.
+    let flags = 0x02;
+    let mut pm = PsuedoMachine::new();
+    pm.set_accumulator(flags);
+    pm.execute(&Instruction::new(CLASS_JMP | SRC_K | OP_JSET, 3, 5, 0x02), &pkt)
+      .unwrap();
+    assert!(pm.frame() == 4);
+    pm.execute(&Instruction::new(CLASS_JMP | SRC_K | OP_JSET, 3, 5, 0x01), &pkt)
+      .unwrap();
+    assert!(pm.frame() == 10);
+    let mut pm = PsuedoMachine::new();
+    pm.set_accumulator(flags);
+    pm.set_index(0x02);
+    pm.execute(&Instruction::new(CLASS_JMP | SRC_X | OP_JSET, 3, 5, 0), &pkt)
+      .unwrap();
+    assert!(pm.frame() == 4);
+    pm.set_index(0x01);
+    pm.execute(&Instruction::new(CLASS_JMP | SRC_X | OP_JSET, 3, 5, 0), &pkt)
+      .unwrap();
+    assert!(pm.frame() == 10);
+  }
+
+  #[test]
+  fn run_out_of_range_frame() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let prog = vec![
+      Instruction::new(CLASS_JMP | OP_JA, 0, 0, 10),
+      Instruction::new(CLASS_RET | RVAL_K, 0, 0, 1),
+    ];
+    assert!(pm.run_program(prog.as_slice(), &pkt) == Err(VmError::ProgramCounterOutOfRange(11)));
+    pm.reset();
+    let prog = vec![Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 1)];
+    assert!(pm.run_program(prog.as_slice(), &pkt) == Err(VmError::ProgramCounterOutOfRange(1)));
+  }
+
+  #[test]
+  fn ja_overflow() {
+    let pkt = [0u8; 64];
+    let prog = vec![
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 1),
+      Instruction::new(CLASS_JMP | OP_JA, 0, 0, 0xFFFFFFFE),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    let mut pm = PsuedoMachine::new();
+    // Where the JA would land, which is past even u32::MAX on 64-bit hosts.
+    let target = 2usize.saturating_add(0xFFFFFFFE);
+    assert!(pm.run_program(prog.as_slice(), &pkt) == Err(VmError::ProgramCounterOutOfRange(target)));
+    assert!(pm.frame() == 1);
+
+    let mut pm = PsuedoMachine::new();
+    pm.set_frame(1);
+    let ja = Instruction::new(CLASS_JMP | OP_JA, 0, 0, 0xFFFFFFFD);
+    assert!(pm.execute(&ja, &pkt) == Ok(None));
+    assert!(pm.frame() == 0xFFFFFFFF);
+    let ja = Instruction::new(CLASS_JMP | OP_JA, 0, 0, 0);
+    let target = MAX_FRAME.saturating_add(1);
+    assert!(pm.execute(&ja, &pkt) == Err(VmError::ProgramCounterOutOfRange(target)));
+    assert!(pm.frame() == 0xFFFFFFFF);
+  }
+
+  #[test]
+  fn ja_past_end() {
+    let pkt = [0u8; 64];
+    let prog = [Instruction::new(JMP, 0, 0, 0x7FFFFFFF), Instruction::new(RETK, 0, 0, 0)];
+    let mut pm = PsuedoMachine::new();
+    assert!(pm.run_program(&prog, &pkt) == Err(VmError::ProgramCounterOutOfRange(0x80000000)));
+    assert!(pm.frame() == 0);
+
+    // Landing exactly on the end only fails once the next instruction is fetched.
+    let prog = [Instruction::new(JMP, 0, 0, 1), Instruction::new(RETK, 0, 0, 0)];
+    let mut pm = PsuedoMachine::new();
+    assert!(pm.step(&prog, &pkt) == Ok(StepResult::Running));
+    assert!(pm.frame() == 2);
+    assert!(pm.step(&prog, &pkt) == Err(VmError::ProgramCounterOutOfRange(2)));
+
+    let mut pm = PsuedoMachine::new();
+    pm.set_frame(u32::MAX);
+    let err = VmError::ProgramCounterOutOfRange(MAX_FRAME);
+    assert!(pm.step(&prog, &pkt) == Err(err));
+    let mut traced = 0;
+    assert!(pm.run_program_traced(&prog, &pkt, |_, _, _| traced += 1) == Err(err));
+    assert!(traced == 0);
+  }
+
+  #[test]
+  fn insn_limit() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let mut prog = vec![Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 1); 200];
+    prog.push(Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0));
+    pm.set_insn_limit(100);
+    assert!(pm.run_program(prog.as_slice(), &pkt) == Err(VmError::InstructionLimitExceeded(100)));
+    assert!(pm.frame() == 100);
+    pm.reset();
+    let prog = vec![
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 1),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    pm.set_insn_limit(1);
+    assert!(pm.run_program(prog.as_slice(), &pkt).is_err());
+    pm.reset();
+    pm.set_insn_limit(2);
+    assert!(pm.run_program(prog.as_slice(), &pkt).unwrap() == 1);
+  }
+
+  #[test]
+  fn step() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let prog = vec![
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 5),
+      Instruction::new(CLASS_ALU | SRC_K | OP_ADD, 0, 0, 3),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    assert!(pm.step(&prog, &pkt) == Ok(StepResult::Running));
+    assert!(pm.frame() == 1);
+    assert!(pm.accumulator() == 5);
+    assert!(pm.step(&prog, &pkt) == Ok(StepResult::Running));
+    assert!(pm.frame() == 2);
+    assert!(pm.accumulator() == 8);
+    assert!(pm.step(&prog, &pkt) == Ok(StepResult::Returned(8)));
+    assert!(pm.frame() == 3);
+    assert!(pm.step(&prog, &pkt) == Err(VmError::ProgramCounterOutOfRange(3)));
+  }
+
+  #[test]
+  fn snapshot_restore() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let prog = vec![
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 5),
+      Instruction::new(ST, 0, 0, 2),
+      Instruction::new(CLASS_MISC | OP_TAX, 0, 0, 0),
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 9),
+      Instruction::new(ST, 0, 0, 2),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    pm.step(&prog, &pkt).unwrap();
+    pm.step(&prog, &pkt).unwrap();
+    pm.step(&prog, &pkt).unwrap();
+    let state = pm.snapshot();
+    assert!(state.frame == 3);
+    assert!(state.accumulator == 5);
+    assert!(state.index == 5);
+    assert!(state.memory[2] == 5);
+    assert!(pm.run_program(&prog, &pkt).unwrap() == 9);
+    assert!(pm.snapshot() != state);
+    pm.restore(&state);
+    assert!(pm.snapshot() == state);
+    assert!(pm.run_program(&prog, &pkt).unwrap() == 9);
+  }
+
+  #[test]
+  fn run_program_traced() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let prog = vec![
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 7),
+      Instruction::new(CLASS_JMP | OP_JEQ | SRC_K, 1, 0, 7),
+      Instruction::new(CLASS_RET | RVAL_K, 0, 0, 0),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    let mut frames = Vec::new();
+    let mut accs = Vec::new();
+    let ret = pm.run_program_traced(&prog, &pkt, |frame, _, m| {
+      frames.push(frame);
+      accs.push(m.accumulator());
+    });
+    assert!(ret.unwrap() == 7);
+    assert!(frames == vec![0, 1, 3]);
+    assert!(accs == vec![0, 7, 7]);
+  }
+
+  #[test]
+  fn extra_scratch_slots() {
+    let mut pm = PsuedoMachine::<32>::with_scratch_slots();
+    let pkt = [0u8; 64];
+    let prog = vec![
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 0xDEADBEEF),
+      Instruction::new(ST, 0, 0, 20),
+      Instruction::new(MODE_MEM | CLASS_LDX, 0, 0, 20),
+      Instruction::new(CLASS_MISC | OP_TXA, 0, 0, 0),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    assert!(pm.run_program(&prog, &pkt).unwrap() == 0xDEADBEEF);
+    assert!(pm.memory().len() == 32);
+    assert!(pm.memory()[20] == 0xDEADBEEF);
+    let instr = Instruction::new(ST, 0, 0, 32);
+    assert!(pm.execute(&instr, &pkt) == Err(VmError::MemorySlotOutOfRange(32)));
+
+    let mut pm = PsuedoMachine::new();
+    let instr = Instruction::new(ST, 0, 0, 20);
+    assert!(pm.execute(&instr, &pkt) == Err(VmError::MemorySlotOutOfRange(20)));
+  }
+
+  #[test]
+  fn ancillary_data() {
+    use vm::ancillary::{SKF_AD_PKTTYPE, SKF_AD_PROTOCOL};
+
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let ld_proto = Instruction::new(MODE_ABS | SIZE_H | CLASS_LD, 0, 0, SKF_AD_OFF + SKF_AD_PROTOCOL);
+    let ld_type = Instruction::new(MODE_ABS | SIZE_W | CLASS_LD, 0, 0, SKF_AD_OFF + SKF_AD_PKTTYPE);
+    let err = Err(VmError::OutOfBounds {
+      offset: (SKF_AD_OFF + SKF_AD_PROTOCOL) as u64,
+      len: 64,
+    });
+    assert!(pm.execute(&ld_proto, &pkt) == err);
+
+    pm.set_aux_data(Some(AuxData {
+      protocol: 0x86DD,
+      pkt_type: 1,
+      ..AuxData::default()
+    }));
+    assert!(pm.execute(&ld_proto, &pkt).unwrap().is_none());
+    assert!(pm.accumulator() == 0x86DD);
+    assert!(pm.execute(&ld_type, &pkt).unwrap().is_none());
+    assert!(pm.accumulator() == 1);
+
+    let ld_unknown = Instruction::new(MODE_ABS | SIZE_W | CLASS_LD, 0, 0, SKF_AD_OFF + 2);
+    assert!(pm.execute(&ld_unknown, &pkt).is_err());
+  }
+
+  /// A packet split across two buffers, like a header and a payload.
+  struct Segmented<'a> {
+    head: &'a [u8],
+    tail: &'a [u8],
+  }
+
+  impl<'a> Packet for Segmented<'a> {
+    fn len(&self) -> usize {
+      self.head.len() + self.tail.len()
+    }
+
+    fn read_u8(&self, offset: usize) -> Option<u8> {
+      if offset < self.head.len() {
+        Some(self.head[offset])
+      } else {
+        self.tail.get(offset - self.head.len()).cloned()
+      }
+    }
+  }
+
+  #[test]
+  fn segmented_packet() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = Segmented {
+      head: &[0x00, 0x11, 0xDE, 0xAD],
+      tail: &[0xBE, 0xEF],
+    };
+    let prog = vec![
+      Instruction::new(MODE_ABS | SIZE_W | CLASS_LD, 0, 0, 2),
+      Instruction::new(MODE_LEN | SIZE_W | CLASS_LDX, 0, 0, 0),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    assert!(pm.run_program(&prog, &pkt).unwrap() == 0xDEADBEEF);
+    assert!(pm.index() == 6);
+    let instr = Instruction::new(MODE_ABS | SIZE_H | CLASS_LD, 0, 0, 5);
+    assert!(pm.execute(&instr, &pkt) == Err(VmError::OutOfBounds { offset: 5, len: 6 }));
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn run_batch() {
+    let mut pm = PsuedoMachine::new();
+    // Accept 60 byte packets, counting how many packets have been seen in M[0] and X.
+    let prog = vec![
+      Instruction::new(MODE_MEM | SIZE_W | CLASS_LD, 0, 0, 0),
+      Instruction::new(CLASS_ALU | OP_ADD | SRC_K, 0, 0, 1),
+      Instruction::new(ST, 0, 0, 0),
+      Instruction::new(CLASS_MISC | OP_TAX, 0, 0, 0),
+      Instruction::new(MODE_LEN | SIZE_W | CLASS_LD, 0, 0, 0),
+      Instruction::new(CLASS_JMP | OP_JEQ | SRC_K, 0, 1, 60),
+      Instruction::new(CLASS_MISC | OP_TXA, 0, 0, 0),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    let short = [0u8; 14];
+    let long = [0u8; 60];
+    let pkts: Vec<&[u8]> = vec![&long, &short, &long, &long, &short];
+    let rets = pm.run_batch(&prog, &pkts);
+    assert!(rets == vec![Ok(1), Ok(14), Ok(1), Ok(1), Ok(14)]);
+
+    let ldw = vec![
+      Instruction::new(MODE_ABS | SIZE_W | CLASS_LD, 0, 0, 12),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    let rets = pm.run_batch(&ldw, &[&short[..], &long[..]]);
+    assert!(rets == vec![Err(VmError::OutOfBounds { offset: 12, len: 14 }), Ok(0)]);
+  }
+
+  #[test]
+  fn endianness() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0x12u8, 0x34, 0x56, 0x78];
+    let ldw = Instruction::new(MODE_ABS | SIZE_W | CLASS_LD, 0, 0, 0);
+    let ldh = Instruction::new(MODE_ABS | SIZE_H | CLASS_LD, 0, 0, 2);
+    let ldb = Instruction::new(MODE_ABS | SIZE_B | CLASS_LD, 0, 0, 1);
+
+    pm.execute(&ldw, &pkt).unwrap();
+    assert!(pm.accumulator() == 0x12345678);
+    pm.execute(&ldh, &pkt).unwrap();
+    assert!(pm.accumulator() == 0x5678);
+
+    pm.set_endianness(Endianness::Little);
+    pm.execute(&ldw, &pkt).unwrap();
+    assert!(pm.accumulator() == 0x78563412);
+    pm.execute(&ldh, &pkt).unwrap();
+    assert!(pm.accumulator() == 0x7856);
+    pm.execute(&ldb, &pkt).unwrap();
+    assert!(pm.accumulator() == 0x34);
+  }
+
+  /// Only uses what's available without `std`, so it also covers `--no-default-features` builds.
+  #[test]
+  fn execute_without_alloc() {
+    let mut pm = PsuedoMachine::new();
+    let mut pkt = [0u8; 64];
+    pkt[12] = 0x08;
+    let prog = [
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 1, 0x800),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    assert!(pm.execute(&prog[0], &pkt) == Ok(None));
+    assert!(pm.accumulator() == 0x800);
+    pm.reset();
+    assert!(pm.run_filter(&prog, &pkt) == Ok(FilterResult::Accept(0xFFFF)));
+  }
+
+  #[test]
+  fn with_state() {
+    let mut memory = [0; SCRATCH_MEM_SLOTS];
+    memory[4] = 0xAB;
+    let state = MachineState {
+      frame: 0,
+      accumulator: 0,
+      index: 14,
+      memory,
+    };
+    let mut pkt = [0u8; 64];
+    pkt[14] = 0x45;
+    let prog = vec![
+      Instruction::new(MODE_IND | SIZE_B | CLASS_LD, 0, 0, 0),
+      Instruction::new(CLASS_ALU | OP_ADD | SRC_X, 0, 0, 0),
+      Instruction::new(MODE_MEM | SIZE_W | CLASS_LDX, 0, 0, 4),
+      Instruction::new(CLASS_ALU | OP_ADD | SRC_X, 0, 0, 0),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    let mut pm = PsuedoMachine::with_state(state);
+    assert!(pm.snapshot() == state);
+    assert!(pm.run_program(&prog, &pkt).unwrap() == 0x45 + 14 + 0xAB);
+
+    pm.reset_to(state);
+    assert!(pm.snapshot() == state);
+    assert!(pm.run_program(&prog, &pkt).unwrap() == 0x45 + 14 + 0xAB);
+  }
+
+  #[test]
+  fn run_with_state() {
+    let mut pkt = [0u8; 64];
+    pkt[20] = 0x11;
+    let prog = vec![
+      Instruction::new(LDBI, 0, 0, 6),
+      Instruction::new(ADDX, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    let init = MachineState {
+      index: 14,
+      ..MachineState::default()
+    };
+    let mut pm = PsuedoMachine::new();
+    pm.set_accumulator(99);
+    assert!(pm.run_with_state(&prog, &pkt, &init) == Ok(0x11 + 14));
+    assert!(pm.run_with_state(&prog, &pkt, &MachineState::default()) == Ok(0));
+  }
+
+  #[test]
+  fn clone_is_independent() {
+    let mut pm = PsuedoMachine::new();
+    pm.set_index(7);
+    pm.set_memory(3, 9);
+    pm.set_insn_limit(2);
+    let mut clone = pm.clone();
+    assert!(clone.snapshot() == pm.snapshot());
+
+    clone.set_index(8);
+    clone.set_memory(3, 10);
+    assert!(pm.index() == 7);
+    assert!(pm.memory()[3] == 9);
+    pm.set_accumulator(1);
+    assert!(clone.accumulator() == 0);
+
+    let prog = vec![
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(LDI, 0, 0, 2),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert!(clone.run_program(&prog, &[0u8; 64]) == Err(VmError::InstructionLimitExceeded(2)));
+  }
+
+  #[test]
+  fn default_matches_new() {
+    let pm: PsuedoMachine = Default::default();
+    assert!(pm.snapshot() == PsuedoMachine::new().snapshot());
+    assert!(pm.snapshot() == MachineState::default());
+  }
+
+  #[test]
+  fn run_program_metered() {
+    let pkt = [0u8; 64];
+    let straight = vec![
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(TAX, 0, 0, 0),
+      Instruction::new(ADDX, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert!(PsuedoMachine::new().run_program_metered(&straight, &pkt) == Ok((2, 4)));
+
+    let jump = vec![
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(JMPEQ, 2, 0, 1),
+      Instruction::new(ADDK, 0, 0, 1),
+      Instruction::new(ADDK, 0, 0, 1),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert!(PsuedoMachine::new().run_program_metered(&jump, &pkt) == Ok((1, 3)));
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn run_program_covered() {
+    let prog = vec![
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 2, 1),
+      Instruction::new(LDI, 0, 0, 7),
+      Instruction::new(RETA, 0, 0, 0),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    let mut coverage = Coverage::new(prog.len());
+    let mut pkt = [0u8; 64];
+    assert!(PsuedoMachine::new().run_program_covered(&prog, &pkt, &mut coverage) == Ok(0));
+    assert!(coverage.covered() == [true, true, false, false, true]);
+    assert!(coverage.count() == 3);
+    assert!(!coverage.is_complete());
+
+    pkt[0] = 1;
+    assert!(PsuedoMachine::new().run_program_covered(&prog, &pkt, &mut coverage) == Ok(7));
+    assert!(coverage.is_complete());
+  }
+
+  #[test]
+  fn signed_jumps() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    // -2 as u32.
+    pm.set_accumulator(0xFFFFFFFE);
+    let cases = [
+      (JMPGT, 1, true),
+      (JMPSGT, 1, false),
+      (JMPSGE, 0xFFFFFFFE, true),
+      (JMPSGT, 0xFFFFFFFD, true),
+      (JMPSGE, 0, false),
+    ];
+    for &(opcode, k, taken) in cases.iter() {
+      pm.set_frame(0);
+      pm.execute(&Instruction::new(opcode, 1, 2, k), &pkt).unwrap();
+      assert!(pm.frame() == if taken { 2 } else { 3 });
+    }
+
+    pm.set_index(5);
+    pm.set_frame(0);
+    pm.execute(&Instruction::new(JMPSGTX, 1, 2, 0), &pkt).unwrap();
+    assert!(pm.frame() == 3);
+    pm.set_accumulator(5);
+    pm.set_frame(0);
+    pm.execute(&Instruction::new(JMPSGEX, 1, 2, 0), &pkt).unwrap();
+    assert!(pm.frame() == 2);
+  }
+
+  #[test]
+  fn source_bit_selects_operand() {
+    let add = Instruction::new(CLASS_ALU | OP_ADD, 0, 0, 5);
+    let addx = Instruction::new(add.opcode | SRC_X, 0, 0, 5);
+    assert!(add.source() == Source::K);
+    assert!(addx.source() == Source::X);
+
+    let mut pm = PsuedoMachine::new();
+    pm.set_accumulator(1);
+    pm.set_index(100);
+    pm.execute(&add, &[]).unwrap();
+    assert!(pm.accumulator() == 6);
+    pm.execute(&addx, &[]).unwrap();
+    assert!(pm.accumulator() == 106);
+
+    let jeqx = Instruction::new(JMPEQ | SRC_X, 1, 2, 106);
+    pm.set_frame(0);
+    pm.execute(&jeqx, &[]).unwrap();
+    assert!(pm.frame() == 3);
+  }
+
+  #[test]
+  fn store_indirect() {
+    let prog = [
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(TAX, 0, 0, 0),
+      Instruction::new(LDI, 0, 0, 0xAB),
+      Instruction::new(STI, 0, 0, 1),
+      Instruction::new(STXI, 0, 0, 0),
+      Instruction::new(LDWM, 0, 0, 8),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    let mut pm = PsuedoMachine::new();
+    assert!(pm.run_program(&prog, &[7]) == Ok(0xAB));
+    assert!(pm.memory()[7] == 7);
+    assert!(pm.memory()[8] == 0xAB);
+
+    let mut pm = PsuedoMachine::new();
+    pm.set_index(SCRATCH_MEM_SLOTS as u32 - 1);
+    let instr = Instruction::new(STXI, 0, 0, 1);
+    assert!(pm.execute(&instr, &[]) == Err(VmError::MemorySlotOutOfRange(SCRATCH_MEM_SLOTS)));
+    pm.set_index(0xFFFFFFFF);
+    assert!(pm.execute(&Instruction::new(STI, 0, 0, 0xFFFFFFFF), &[]).is_err());
+    assert!(pm.frame() == 0);
+  }
+
+  #[test]
+  fn empty_packet() {
+    let pkt: [u8; 0] = [];
+    let mut pm = PsuedoMachine::new();
+    pm.set_accumulator(7);
+    pm.set_index(7);
+    assert!(pm.execute(&Instruction::new(LDL, 0, 0, 0), &pkt) == Ok(None));
+    assert!(pm.accumulator() == 0);
+    assert!(pm.execute(&Instruction::new(LDXL, 0, 0, 0), &pkt) == Ok(None));
+    assert!(pm.index() == 0);
+    for &opcode in [LDW, LDH, LDB, LDWI, LDHI, LDBI, LDXW, LDXB].iter() {
+      let err = Err(VmError::OutOfBounds { offset: 0, len: 0 });
+      assert!(PsuedoMachine::new().execute(&Instruction::new(opcode, 0, 0, 0), &pkt) == err);
+    }
+
+    let prog = vec![
+      Instruction::new(LDI, 0, 0, 3),
+      Instruction::new(ST, 0, 0, 0),
+      Instruction::new(LDXM, 0, 0, 0),
+      Instruction::new(JMPEQX, 1, 0, 0),
+      Instruction::new(RETK, 0, 0, 0),
+      Instruction::new(RETK, 0, 0, 42),
+    ];
+    assert!(PsuedoMachine::new().run_program(&prog, &pkt) == Ok(42));
+    assert!(PsuedoMachine::new().run_program(&prog, &[0u8; 64]) == Ok(42));
+  }
+
+  #[cfg(feature = "log")]
+  #[test]
+  fn trace_log() {
+    use log::{Log, Metadata, Record};
+    use std::cell::Cell;
+
+    /// Counts the trace records logged by each thread, so other tests running in parallel don't
+    /// skew the count.
+    struct CountingLogger;
+
+    thread_local!(static RECORDS: Cell<u64> = const { Cell::new(0) });
+
+    impl Log for CountingLogger {
+      fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() == log::Level::Trace
+      }
+
+      fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+          RECORDS.with(|records| records.set(records.get() + 1));
+        }
+      }
+
+      fn flush(&self) {}
+    }
+
+    static LOGGER: CountingLogger = CountingLogger;
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let prog = [
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 1, 0, 1),
+      Instruction::new(ADDK, 0, 0, 1),
+      Instruction::new(RETA, 0, 0, 0),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+    ];
+    for &(byte, expected) in [(0u8, 4u64), (1, 3)].iter() {
+      RECORDS.with(|records| records.set(0));
+      let (_, executed) = PsuedoMachine::new().run_program_metered(&prog, &[byte]).unwrap();
+      assert!(executed == expected);
+      assert!(RECORDS.with(|records| records.get()) == expected);
+    }
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn dump() {
+    let mut pm = PsuedoMachine::new();
+    pm.set_accumulator(0xDEADBEEF);
+    pm.set_memory(3, 0x2A);
+    pm.set_frame(2);
+    let dump = pm.dump();
+    let lines: Vec<&str> = dump.lines().collect();
+    assert!(lines.len() == 3 + SCRATCH_MEM_SLOTS);
+    assert!(lines[0] == "frame  0x00000002");
+    assert!(lines[1] == "a      0xdeadbeef");
+    assert!(lines[2] == "x      0x00000000");
+    assert!(lines[3 + 3] == "m[3]   0x0000002a");
+    assert!(lines[3 + 15] == "m[15]  0x00000000");
+    assert!(PsuedoMachine::<2>::with_scratch_slots().dump().lines().count() == 5);
+  }
+
+  #[test]
+  fn end_of_program_policy() {
+    let prog = [Instruction::new(LDB, 0, 0, 0), Instruction::new(TAX, 0, 0, 0)];
+    let mut pm = PsuedoMachine::new();
+    assert!(pm.run_program(&prog, &[7]) == Err(VmError::ProgramCounterOutOfRange(2)));
+
+    let mut pm = PsuedoMachine::new();
+    pm.set_end_of_program_policy(EndOfProgramPolicy::ImplicitDrop);
+    assert!(pm.run_filter(&prog, &[7]) == Ok(FilterResult::Drop));
+    assert!(pm.index() == 7);
+    assert!(pm.step(&prog, &[7]) == Ok(StepResult::Returned(0)));
+
+    // Jumping further past the end is still an error.
+    let prog = [Instruction::new(JMP, 0, 0, 1)];
+    let mut pm = PsuedoMachine::new();
+    pm.set_end_of_program_policy(EndOfProgramPolicy::ImplicitDrop);
+    assert!(pm.run_program(&prog, &[]) == Err(VmError::ProgramCounterOutOfRange(2)));
+  }
+
+  #[test]
+  fn load_sizes() {
+    let pkt = [0x01, 0x02, 0x03, 0x04, 0x05];
+    let mut pm = PsuedoMachine::new();
+    assert!(pm.load(ReadSize::Byte, 1, &pkt[..]) == Ok(0x02));
+    assert!(pm.load(ReadSize::Half, 1, &pkt[..]) == Ok(0x0203));
+    assert!(pm.load(ReadSize::Word, 1, &pkt[..]) == Ok(0x02030405));
+    // Each size needs exactly its own length to fit.
+    for &size in [ReadSize::Byte, ReadSize::Half, ReadSize::Word].iter() {
+      let offset = (pkt.len() - size.bytes()) as u32;
+      assert!(pm.load(size, offset, &pkt[..]).is_ok());
+      assert!(pm.load(size, offset + 1, &pkt[..]).is_err());
+    }
+    pm.set_endianness(Endianness::Little);
+    assert!(pm.load(ReadSize::Half, 1, &pkt[..]) == Ok(0x0302));
+    assert!(pm.load(ReadSize::Word, 1, &pkt[..]) == Ok(0x05040302));
+
+    // The same three sizes through the absolute, indirect and memory loads.
+    let mut pm = PsuedoMachine::new();
+    pm.set_index(1);
+    pm.set_memory(0, 0x0A0B0C0D);
+    let cases = [
+      (LDB, 0x02),
+      (LDH, 0x0203),
+      (LDW, 0x02030405),
+      (LDBI, 0x03),
+      (LDHI, 0x0304),
+      (LDBM, 0x0D),
+      (LDHM, 0x0C0D),
+      (LDWM, 0x0A0B0C0D),
+    ];
+    for &(opcode, expected) in cases.iter() {
+      let k = if opcode & MASK_MODE == MODE_MEM { 0 } else { 1 };
+      pm.execute(&Instruction::new(opcode, 0, 0, k), &pkt[..]).unwrap();
+      assert!(pm.accumulator() == expected);
+    }
+  }
+
+  #[test]
+  fn memory_ne_bytes() {
+    let pkt = [0x80, 0x01, 0xFE, 0x7F];
+    let prog = [
+      Instruction::new(LDW, 0, 0, 0),
+      Instruction::new(ST, 0, 0, 3),
+      Instruction::new(LDI, 0, 0, 0),
+      Instruction::new(LDWM, 0, 0, 3),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    let mut pm = PsuedoMachine::new();
+    assert!(pm.run_program(&prog, &pkt) == Ok(0x8001FE7F));
+    assert!(pm.memory_ne_bytes(3) == Some(0x8001FE7Fu32.to_ne_bytes()));
+    assert!(pm.memory_ne_bytes(SCRATCH_MEM_SLOTS).is_none());
+
+    let mut pm = PsuedoMachine::new();
+    pm.set_memory_ne_bytes(3, 0xDEADBEEFu32.to_ne_bytes()).unwrap();
+    assert!(pm.run_program(&prog[3..], &pkt) == Ok(0xDEADBEEF));
+    assert!(pm.memory_ne_bytes(3) == Some(0xDEADBEEFu32.to_ne_bytes()));
+    let err = Err(VmError::MemorySlotOutOfRange(SCRATCH_MEM_SLOTS));
+    assert!(pm.set_memory_ne_bytes(SCRATCH_MEM_SLOTS, [0; 4]) == err);
   }
 
+  #[test]
+  fn run_filter() {
+    let mut pm = PsuedoMachine::new();
+    let pkt = [0u8; 64];
+    let drop = vec![Instruction::new(CLASS_RET | RVAL_K, 0, 0, 0)];
+    assert!(pm.run_filter(drop.as_slice(), &pkt) == Ok(FilterResult::Drop));
+    pm.reset();
+    let accept = vec![Instruction::new(CLASS_RET | RVAL_K, 0, 0, 0xFFFF)];
+    assert!(pm.run_filter(accept.as_slice(), &pkt) == Ok(FilterResult::Accept(0xFFFF)));
+  }
+
+  #[test]
+  fn truncate() {
+    let pkt = [0xAAu8; 64];
+    let prog = [Instruction::new(RETK, 0, 0, 14)];
+    let res = PsuedoMachine::new().run_filter(&prog, &pkt).unwrap();
+    assert!(res == FilterResult::Accept(14));
+    assert!(res.truncate(&pkt) == Some(&pkt[..14]));
+    assert!(res.truncate(&pkt[..10]) == Some(&pkt[..10]));
+    assert!(FilterResult::Accept(u32::MAX).truncate(&pkt) == Some(&pkt[..]));
+    assert!(FilterResult::Drop.truncate(&pkt).is_none());
+  }
+
+  #[cfg(feature = "std")]
   #[test]
   fn run_bytecode() {
     let mut pm = PsuedoMachine::new();
-    let pkt = [0 as u8; 64];
+    let pkt = [0u8; 64];
     let prog = vec![
       Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 0xBEEF),
       Instruction::new(CLASS_ALU | OP_NEG, 0, 0, 0),
@@ -724,7 +2291,195 @@ mod tests {
     ];
     let prog_bytes = unsafe { slice::from_raw_parts(prog.as_slice().as_ptr() as *const u8, 32) };
     let ret = pm.run_program_bytes(prog_bytes, &pkt).unwrap();
-    let expected = !0xBEEF ^ 0xDEAD;
+    let expected = 0u32.wrapping_sub(0xBEEF) ^ 0xDEAD;
     assert!(ret == expected);
   }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn run_bytecode_matches_slice() {
+    let pkt = [0u8; 64];
+    let prog = vec![
+      Instruction::new(MODE_IMM | CLASS_LD, 0, 0, 0xBEEF),
+      Instruction::new(CLASS_RET | RVAL_A, 0, 0, 0),
+    ];
+    let mut prog_bytes = Vec::new();
+    for instr in prog.iter() {
+      prog_bytes.extend_from_slice(&instr.opcode.to_ne_bytes());
+      prog_bytes.push(instr.jt);
+      prog_bytes.push(instr.jf);
+      prog_bytes.extend_from_slice(&instr.k.to_ne_bytes());
+    }
+    let mut pm = PsuedoMachine::new();
+    let expected = pm.run_program(prog.as_slice(), &pkt).unwrap();
+    pm.reset();
+    assert!(pm.run_program_bytes(&prog_bytes, &pkt).unwrap() == expected);
+    pm.reset();
+    let err = Err(VmError::InvalidProgramLength(15));
+    assert!(pm.run_program_bytes(&prog_bytes[..15], &pkt) == err);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn run_bytecode_verified() {
+    let pkt = [0u8; 64];
+    let mut prog = vec![
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 1, 0),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    let bytes = Instruction::program_to_sock_filter_bytes(&prog);
+    assert!(PsuedoMachine::new().run_program_bytes_verified(&bytes, &pkt) == Ok(0xFFFF));
+
+    // The false branch jumps past the end, but only packets that take it would find out.
+    prog[1].jf = 2;
+    let bytes = Instruction::program_to_sock_filter_bytes(&prog);
+    assert!(PsuedoMachine::new().run_program_bytes(&bytes, &pkt) == Ok(0xFFFF));
+    let err = Err(VmError::JumpOutOfRange(1));
+    assert!(PsuedoMachine::new().run_program_bytes_verified(&bytes, &pkt) == err);
+    assert!(
+      PsuedoMachine::new().run_program_bytes_verified(&bytes[..12], &pkt) == Err(VmError::InvalidProgramLength(12))
+    );
+  }
+}
+
+/// Property tests for the packet loaders: every load either decodes exactly the bytes at its
+/// offset or fails cleanly, whatever the offset and packet length.
+#[cfg(test)]
+mod bounds {
+  use super::*;
+  use proptest::collection::vec;
+  use proptest::prelude::*;
+
+  /// Offsets near the packet, where off-by-ones live, plus a few near the top of the range to
+  /// catch overflow. Both shrink towards small values.
+  fn offsets() -> impl Strategy<Value = u32> {
+    prop_oneof![0u32..80, (u32::MAX - 8)..=u32::MAX]
+  }
+
+  /// Returns the `size` bytes at `offset` as a big endian number, or None if they don't fit.
+  fn expected(pkt: &[u8], offset: u32, size: usize) -> Option<u32> {
+    let start = offset as usize;
+    let bytes = pkt.get(start..start.checked_add(size)?)?;
+    Some(bytes.iter().fold(0, |acc, &b| acc << 8 | b as u32))
+  }
+
+  proptest! {
+    #[test]
+    fn loads_in_bounds_or_fail(pkt in vec(any::<u8>(), 0..64), offset in offsets()) {
+      let pm = PsuedoMachine::new();
+      let err = VmError::OutOfBounds { offset: offset as u64, len: pkt.len() };
+      for &size in [ReadSize::Word, ReadSize::Half, ReadSize::Byte].iter() {
+        prop_assert_eq!(pm.load(size, offset, &pkt[..]), expected(&pkt, offset, size.bytes()).ok_or(err));
+      }
+    }
+  }
+}
+
+/// Every combination of load class, addressing mode and size, run through `execute`, so the set
+/// of loads the machine supports is written down in one place.
+#[cfg(test)]
+mod load_matrix {
+  use super::*;
+
+  const CLASSES: [u16; 2] = [CLASS_LD, CLASS_LDX];
+  const MODES: [u16; 8] = [MODE_IMM, MODE_ABS, MODE_IND, MODE_MEM, MODE_LEN, MODE_MSH, 0xC0, 0xE0];
+  /// The three classic sizes plus the double word size eBPF added.
+  const SIZES: [u16; 4] = [SIZE_W, SIZE_H, SIZE_B, 0x18];
+
+  const PKT: [u8; 8] = [0x10, 0x25, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80];
+  const IMM: u32 = 0x12345678;
+  const SLOT: u32 = 0xDEADBEEF;
+
+  /// The register a load writes.
+  #[derive(Clone, Copy, Debug)]
+  enum Reg {
+    A,
+    X,
+  }
+
+  /// Returns the `k` each mode is tested with: the immediate, offset 1 (or X + 1 = 3), slot 1.
+  fn k_for(mode: u16) -> u32 {
+    match mode {
+      MODE_IMM => IMM,
+      MODE_LEN => 0,
+      _ => 1,
+    }
+  }
+
+  /// Returns the register the load writes and the value it should get, or None if the opcode
+  /// isn't supported.
+  fn expected(class: u16, mode: u16, size: u16) -> Option<(Reg, u32)> {
+    let a = |val| Some((Reg::A, val));
+    let x = |val| Some((Reg::X, val));
+    match (class, mode, size) {
+      (CLASS_LD, MODE_IMM, SIZE_W) => a(IMM),
+      (CLASS_LD, MODE_ABS, SIZE_W) => a(0x25304050),
+      (CLASS_LD, MODE_ABS, SIZE_H) => a(0x2530),
+      (CLASS_LD, MODE_ABS, SIZE_B) => a(0x25),
+      (CLASS_LD, MODE_IND, SIZE_W) => a(0x40506070),
+      (CLASS_LD, MODE_IND, SIZE_H) => a(0x4050),
+      (CLASS_LD, MODE_IND, SIZE_B) => a(0x40),
+      (CLASS_LD, MODE_MEM, SIZE_W) => a(SLOT),
+      (CLASS_LD, MODE_MEM, SIZE_H) => a(SLOT & 0xFFFF),
+      (CLASS_LD, MODE_MEM, SIZE_B) => a(SLOT & 0xFF),
+      (CLASS_LD, MODE_LEN, SIZE_W) => a(PKT.len() as u32),
+      (CLASS_LDX, MODE_IMM, SIZE_W) => x(IMM),
+      (CLASS_LDX, MODE_ABS, SIZE_W) => x(0x25304050),
+      (CLASS_LDX, MODE_MEM, SIZE_W) => x(SLOT),
+      (CLASS_LDX, MODE_LEN, SIZE_W) => x(PKT.len() as u32),
+      (CLASS_LDX, MODE_MSH, SIZE_B) => x(4 * 5),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn every_load() {
+    let mut supported = 0;
+    for &class in CLASSES.iter() {
+      for &mode in MODES.iter() {
+        for &size in SIZES.iter() {
+          let opcode = class | mode | size;
+          let mut pm = PsuedoMachine::new();
+          pm.set_accumulator(0xAAAAAAAA);
+          pm.set_index(2);
+          pm.set_memory(1, SLOT);
+          let res = pm.execute(&Instruction::new(opcode, 0, 0, k_for(mode)), &PKT);
+          match expected(class, mode, size) {
+            Some((reg, val)) => {
+              supported += 1;
+              assert_eq!(res, Ok(None), "opcode {:#04x}", opcode);
+              let (written, untouched) = match reg {
+                Reg::A => (pm.accumulator(), pm.index() == 2),
+                Reg::X => (pm.index(), pm.accumulator() == 0xAAAAAAAA),
+              };
+              assert_eq!(written, val, "opcode {:#04x}", opcode);
+              assert!(untouched, "opcode {:#04x} clobbered the other register", opcode);
+              assert_eq!(pm.frame(), 1);
+            },
+            None => {
+              assert_eq!(res, Err(VmError::InvalidOpcode(opcode)), "opcode {:#04x}", opcode);
+              assert_eq!(pm.frame(), 0);
+            },
+          }
+          assert_eq!(
+            Opcode::from_raw(opcode).is_some(),
+            expected(class, mode, size).is_some()
+          );
+        }
+      }
+    }
+    assert_eq!(supported, 16);
+  }
+
+  #[test]
+  fn immediate_loads_differ_only_in_class() {
+    let (ld, ldx) = (Instruction::new(LDI, 0, 0, 7), Instruction::new(LDXI, 0, 0, 9));
+    assert_eq!(ld.opcode ^ ldx.opcode, CLASS_LD ^ CLASS_LDX);
+    let mut pm = PsuedoMachine::new();
+    pm.execute(&ld, &PKT).unwrap();
+    pm.execute(&ldx, &PKT).unwrap();
+    assert_eq!((pm.accumulator(), pm.index()), (7, 9));
+  }
 }
@@ -1,4 +1,4 @@
-mod machine;
+extern crate rust_bpf;
 
 fn main() {
   println!("todo");
@@ -0,0 +1,14 @@
+pub mod ancillary;
+#[cfg(feature = "std")]
+pub mod compiler;
+#[cfg(feature = "std")]
+pub mod coverage;
+#[cfg(feature = "std")]
+pub mod ebpf;
+pub mod machine;
+#[cfg(feature = "std")]
+pub mod optimizer;
+pub mod packet;
+#[cfg(feature = "std")]
+pub mod program;
+pub mod verifier;
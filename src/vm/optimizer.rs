@@ -0,0 +1,337 @@
+use common::instruction::*;
+use vm::machine::SCRATCH_MEM_SLOTS;
+use vm::verifier::reachable;
+
+/// Returns whether `instr` is a jump that always continues at the next instruction.
+fn is_noop_jump(instr: &Instruction) -> bool {
+  match instr.opcode {
+    JMP => instr.k == 0,
+    _ => instr.class() == CLASS_JMP && instr.jt == 0 && instr.jf == 0,
+  }
+}
+
+/// Returns whether `instr` loads the accumulator without touching the packet, so it can never
+/// fail.
+fn is_safe_accumulator_load(instr: &Instruction) -> bool {
+  match instr.opcode {
+    LDI | LDL => true,
+    LDWM | LDHM | LDBM => (instr.k as usize) < SCRATCH_MEM_SLOTS,
+    _ => false,
+  }
+}
+
+/// Returns whether `instr` loads the index register without touching the packet, so it can never
+/// fail.
+fn is_safe_index_load(instr: &Instruction) -> bool {
+  match instr.opcode {
+    LDXI | LDXL => true,
+    LDXM => (instr.k as usize) < SCRATCH_MEM_SLOTS,
+    _ => false,
+  }
+}
+
+/// Returns whether `instr` replaces the accumulator without reading it first.
+fn overwrites_accumulator(instr: &Instruction) -> bool {
+  instr.class() == CLASS_LD || instr.opcode == TXA || instr.opcode == RETK
+}
+
+/// Returns whether `instr` replaces the index register without reading it first.
+fn overwrites_index(instr: &Instruction) -> bool {
+  instr.class() == CLASS_LDX || instr.opcode == TAX || instr.class() == CLASS_RET
+}
+
+/// Returns whether the instruction at `pc` can be dropped without changing what the program does.
+fn is_redundant(prog: &[Instruction], pc: usize) -> bool {
+  let instr = &prog[pc];
+  if is_noop_jump(instr) {
+    return true;
+  }
+  match prog.get(pc + 1) {
+    Some(next) if is_safe_accumulator_load(instr) => overwrites_accumulator(next),
+    Some(next) if is_safe_index_load(instr) => overwrites_index(next),
+    _ => false,
+  }
+}
+
+/// Returns which instructions some jump lands on.
+fn jump_targets(prog: &[Instruction]) -> Vec<bool> {
+  let mut targets = vec![false; prog.len()];
+  let mut mark = |pc: usize, offset: u32| {
+    if let Some(target) = targets.get_mut(pc + 1 + offset as usize) {
+      *target = true;
+    }
+  };
+  for (pc, instr) in prog.iter().enumerate() {
+    if instr.opcode == JMP {
+      mark(pc, instr.k);
+    } else if instr.class() == CLASS_JMP {
+      mark(pc, instr.jt as u32);
+      mark(pc, instr.jf as u32);
+    }
+  }
+  targets
+}
+
+/// Returns `acc` after the immediate ALU instruction `instr` runs on it, or None if `instr`
+/// isn't one or could fail, overflow or shift by 32 or more, so it has to run as is.
+fn fold(acc: u32, instr: &Instruction) -> Option<u32> {
+  let k = instr.k;
+  match instr.opcode {
+    ADDK => acc.checked_add(k),
+    SUBK => acc.checked_sub(k),
+    MULK => acc.checked_mul(k),
+    DIVK => acc.checked_div(k),
+    MODK => acc.checked_rem(k),
+    ORK => Some(acc | k),
+    ANDK => Some(acc & k),
+    XORK => Some(acc ^ k),
+    LSHK if k < 32 => Some(acc << k),
+    RSHK if k < 32 => Some(acc >> k),
+    NEG => Some(acc.wrapping_neg()),
+    _ => None,
+  }
+}
+
+/// Drops the instructions that aren't marked in `keep` and re-patches the jumps left behind.
+/// A jump to a dropped instruction is redirected to the next kept one, so every dropped
+/// instruction must be one that simply falls through.
+pub(crate) fn retain(prog: &[Instruction], keep: &[bool]) -> Vec<Instruction> {
+  // new_pc[i] is the index the first kept instruction at or after i ends up at.
+  let mut new_pc = Vec::with_capacity(prog.len() + 1);
+  let mut kept = 0;
+  for &k in keep {
+    new_pc.push(kept);
+    if k {
+      kept += 1;
+    }
+  }
+  new_pc.push(kept);
+  let relocate = |pc: usize, offset: u32| {
+    let target = (pc as u64 + 1 + offset as u64).min(prog.len() as u64) as usize;
+    (new_pc[target] - new_pc[pc] - 1) as u32
+  };
+
+  let mut out = Vec::with_capacity(kept);
+  for (pc, instr) in prog.iter().enumerate() {
+    if !keep[pc] {
+      continue;
+    }
+    let mut instr = *instr;
+    if instr.opcode == JMP {
+      instr.k = relocate(pc, instr.k);
+    } else if instr.class() == CLASS_JMP {
+      instr.jt = relocate(pc, instr.jt as u32) as u8;
+      instr.jf = relocate(pc, instr.jf as u32) as u8;
+    }
+    out.push(instr);
+  }
+  out
+}
+
+/// Returns an equivalent, shorter program with jumps to the next instruction and loads that are
+/// immediately overwritten removed.
+/// Only loads that cannot fail are removed, so the result behaves exactly like `prog` for every
+/// packet.
+pub fn optimize(prog: &[Instruction]) -> Vec<Instruction> {
+  let mut prog = prog.to_vec();
+  // Removing an instruction can expose another redundant one, so keep going until nothing
+  // changes.
+  loop {
+    let keep: Vec<bool> = (0..prog.len()).map(|pc| !is_redundant(&prog, pc)).collect();
+    if keep.iter().all(|&k| k) {
+      return prog;
+    }
+    prog = retain(&prog, &keep);
+  }
+}
+
+/// Returns the program with each immediate load followed by a run of immediate ALU instructions,
+/// such as `ld #5; add #3`, collapsed into a single load of the result, `ld #8`.
+/// A run stops at the first instruction a jump lands on, since the load doesn't run on that
+/// path, and at any instruction that could fail or overflow, so the result behaves like `prog`
+/// in either arithmetic mode.
+pub fn fold_constants(prog: &[Instruction]) -> Vec<Instruction> {
+  let targets = jump_targets(prog);
+  let mut folded = prog.to_vec();
+  let mut keep = vec![true; prog.len()];
+  let mut pc = 0;
+  while pc < prog.len() {
+    if prog[pc].opcode != LDI {
+      pc += 1;
+      continue;
+    }
+    let mut next = pc + 1;
+    while next < prog.len() && !targets[next] {
+      match fold(folded[pc].k, &prog[next]) {
+        Some(acc) => folded[pc].k = acc,
+        None => break,
+      }
+      keep[next] = false;
+      next += 1;
+    }
+    pc = next;
+  }
+  retain(&folded, &keep)
+}
+
+/// Returns the program with every instruction that can't be reached from the first one removed,
+/// e.g. the leftovers of composing filters.
+pub fn eliminate_dead_code(prog: &[Instruction]) -> Vec<Instruction> {
+  retain(prog, &reachable(prog))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use vm::machine::PsuedoMachine;
+
+  fn run(prog: &[Instruction], pkt: &[u8]) -> u32 {
+    PsuedoMachine::new().run_program(prog, pkt).unwrap()
+  }
+
+  #[test]
+  fn optimize_redundant_program() {
+    let prog = vec![
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 0, 0x86DD),
+      Instruction::new(JMPEQ, 0, 4, 0x800),
+      Instruction::new(LDXI, 0, 0, 3),
+      Instruction::new(JMP, 0, 0, 0),
+      Instruction::new(LDXL, 0, 0, 0),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    let expected = vec![
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 1, 0x800),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    let optimized = optimize(&prog);
+    assert_eq!(optimized, expected);
+
+    let mut ip = [0u8; 64];
+    ip[12] = 0x08;
+    let mut arp = [0u8; 64];
+    arp[12] = 0x08;
+    arp[13] = 0x06;
+    for pkt in [ip, arp, [0u8; 64]].iter() {
+      assert_eq!(run(&prog, pkt), run(&optimized, pkt));
+    }
+  }
+
+  #[test]
+  fn keeps_loads_that_can_fail() {
+    let prog = vec![
+      Instruction::new(LDB, 0, 0, 100),
+      Instruction::new(LDWM, 0, 0, SCRATCH_MEM_SLOTS as u32),
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert_eq!(optimize(&prog), prog);
+  }
+
+  #[test]
+  fn redirects_jumps_into_removed_instructions() {
+    let prog = vec![
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 1, 0, 1),
+      Instruction::new(RETK, 0, 0, 1),
+      Instruction::new(LDI, 0, 0, 7),
+      Instruction::new(LDI, 0, 0, 2),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    let optimized = optimize(&prog);
+    assert_eq!(optimized.len(), 5);
+    assert_eq!(optimized[1], Instruction::new(JMPEQ, 1, 0, 1));
+    let mut pkt = [0u8; 64];
+    assert_eq!(run(&optimized, &pkt), 1);
+    pkt[0] = 1;
+    assert_eq!(run(&optimized, &pkt), 2);
+  }
+
+  #[test]
+  fn fold_immediate_chain() {
+    let prog = vec![
+      Instruction::new(LDI, 0, 0, 5),
+      Instruction::new(ADDK, 0, 0, 3),
+      Instruction::new(LSHK, 0, 0, 2),
+      Instruction::new(ORK, 0, 0, 1),
+      Instruction::new(TAX, 0, 0, 0),
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 1, 1),
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(SUBK, 0, 0, 1),
+      Instruction::new(ADDX, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    let expected = vec![
+      Instruction::new(LDI, 0, 0, 33),
+      Instruction::new(TAX, 0, 0, 0),
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 1, 1),
+      // A jump lands on the SUB, so it stays.
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(SUBK, 0, 0, 1),
+      Instruction::new(ADDX, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    let folded = fold_constants(&prog);
+    assert_eq!(folded, expected);
+    for pkt in [[0u8], [1], [2]].iter() {
+      assert_eq!(run(&prog, pkt), run(&folded, pkt));
+    }
+  }
+
+  #[test]
+  fn fold_stops_at_failures_and_overflow() {
+    let prog = vec![
+      Instruction::new(LDI, 0, 0, 6),
+      Instruction::new(DIVK, 0, 0, 2),
+      Instruction::new(MODK, 0, 0, 0),
+      Instruction::new(LDI, 0, 0, 0),
+      Instruction::new(SUBK, 0, 0, 1),
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(LSHK, 0, 0, 32),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    let folded = fold_constants(&prog);
+    assert_eq!(folded.len(), prog.len() - 1);
+    assert_eq!(folded[0], Instruction::new(LDI, 0, 0, 3));
+    assert_eq!(folded[1], Instruction::new(MODK, 0, 0, 0));
+    assert_eq!(fold_constants(&folded[2..]), &folded[2..]);
+  }
+
+  #[test]
+  fn eliminate_unreachable_tail() {
+    let prog = vec![
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 3, 0x800),
+      Instruction::new(LDB, 0, 0, 23),
+      Instruction::new(JMP, 0, 0, 2),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(RETK, 0, 0, 1),
+    ];
+    let expected = vec![
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 2, 0x800),
+      Instruction::new(LDB, 0, 0, 23),
+      Instruction::new(JMP, 0, 0, 1),
+      Instruction::new(RETK, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    let eliminated = eliminate_dead_code(&prog);
+    assert_eq!(eliminated, expected);
+
+    let mut ip = [0u8; 64];
+    ip[12] = 0x08;
+    ip[23] = 6;
+    for pkt in [ip, [0u8; 64]].iter() {
+      assert_eq!(run(&prog, pkt), run(&eliminated, pkt));
+    }
+  }
+}
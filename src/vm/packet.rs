@@ -0,0 +1,182 @@
+#[cfg(feature = "std")]
+extern crate byteorder;
+
+#[cfg(feature = "std")]
+use std::io::Cursor;
+
+#[cfg(feature = "std")]
+use self::byteorder::{BigEndian, ReadBytesExt};
+
+/// Packet data a program can load from.
+/// Multi-byte reads are in network byte order. Every read returns None if it doesn't fit in the
+/// packet.
+pub trait Packet {
+  /// Returns the length of the packet in bytes.
+  fn len(&self) -> usize;
+
+  /// Returns whether the packet is empty.
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Reads the byte at `offset`.
+  fn read_u8(&self, offset: usize) -> Option<u8>;
+
+  /// Reads the half-word starting at `offset`.
+  fn read_u16(&self, offset: usize) -> Option<u16> {
+    let hi = self.read_u8(offset)? as u16;
+    let lo = self.read_u8(offset.checked_add(1)?)? as u16;
+    Some(hi << 8 | lo)
+  }
+
+  /// Reads the word starting at `offset`.
+  fn read_u32(&self, offset: usize) -> Option<u32> {
+    let hi = self.read_u16(offset)? as u32;
+    let lo = self.read_u16(offset.checked_add(2)?)? as u32;
+    Some(hi << 16 | lo)
+  }
+}
+
+impl Packet for [u8] {
+  fn len(&self) -> usize {
+    <[u8]>::len(self)
+  }
+
+  fn read_u8(&self, offset: usize) -> Option<u8> {
+    self.get(offset).cloned()
+  }
+
+  #[cfg(feature = "std")]
+  fn read_u16(&self, offset: usize) -> Option<u16> {
+    cursor_read_u16(self, offset)
+  }
+
+  #[cfg(not(feature = "std"))]
+  fn read_u16(&self, offset: usize) -> Option<u16> {
+    manual_read_u16(self, offset)
+  }
+
+  #[cfg(feature = "std")]
+  fn read_u32(&self, offset: usize) -> Option<u32> {
+    cursor_read_u32(self, offset)
+  }
+
+  #[cfg(not(feature = "std"))]
+  fn read_u32(&self, offset: usize) -> Option<u32> {
+    manual_read_u32(self, offset)
+  }
+}
+
+/// Reads a big endian half-word with byteorder.
+#[cfg(feature = "std")]
+fn cursor_read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+  let mut cur = Cursor::new(buf.get(offset..)?);
+  cur.read_u16::<BigEndian>().ok()
+}
+
+/// Reads a big endian word with byteorder.
+#[cfg(feature = "std")]
+fn cursor_read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+  let mut cur = Cursor::new(buf.get(offset..)?);
+  cur.read_u32::<BigEndian>().ok()
+}
+
+/// Reads a big endian half-word without byteorder, for `no_std` builds.
+#[cfg(any(not(feature = "std"), test))]
+fn manual_read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+  let bytes = buf.get(offset..offset.checked_add(2)?)?;
+  Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a big endian word without byteorder, for `no_std` builds.
+#[cfg(any(not(feature = "std"), test))]
+fn manual_read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+  let bytes = buf.get(offset..offset.checked_add(4)?)?;
+  Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+impl<const N: usize> Packet for [u8; N] {
+  fn len(&self) -> usize {
+    N
+  }
+
+  fn read_u8(&self, offset: usize) -> Option<u8> {
+    self[..].read_u8(offset)
+  }
+
+  fn read_u16(&self, offset: usize) -> Option<u16> {
+    self[..].read_u16(offset)
+  }
+
+  fn read_u32(&self, offset: usize) -> Option<u32> {
+    self[..].read_u32(offset)
+  }
+}
+
+#[cfg(feature = "std")]
+impl Packet for Vec<u8> {
+  fn len(&self) -> usize {
+    Vec::len(self)
+  }
+
+  fn read_u8(&self, offset: usize) -> Option<u8> {
+    self[..].read_u8(offset)
+  }
+
+  fn read_u16(&self, offset: usize) -> Option<u16> {
+    self[..].read_u16(offset)
+  }
+
+  fn read_u32(&self, offset: usize) -> Option<u32> {
+    self[..].read_u32(offset)
+  }
+}
+
+impl<P: Packet + ?Sized> Packet for &P {
+  fn len(&self) -> usize {
+    (**self).len()
+  }
+
+  fn read_u8(&self, offset: usize) -> Option<u8> {
+    (**self).read_u8(offset)
+  }
+
+  fn read_u16(&self, offset: usize) -> Option<u16> {
+    (**self).read_u16(offset)
+  }
+
+  fn read_u32(&self, offset: usize) -> Option<u32> {
+    (**self).read_u32(offset)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn slice_reads() {
+    let pkt: &[u8] = &[0xDE, 0xAD, 0xBE, 0xEF];
+    assert_eq!(Packet::len(pkt), 4);
+    assert_eq!(pkt.read_u8(3), Some(0xEF));
+    assert_eq!(pkt.read_u16(2), Some(0xBEEF));
+    assert_eq!(pkt.read_u32(0), Some(0xDEADBEEF));
+    assert!(pkt.read_u8(4).is_none());
+    assert!(pkt.read_u16(3).is_none());
+    assert!(pkt.read_u32(1).is_none());
+    assert!(pkt.read_u32(usize::MAX).is_none());
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn manual_reads_match_byteorder() {
+    let buf: Vec<u8> = (0..=255u8).rev().chain(0..=255u8).collect();
+    for offset in 0..buf.len() + 2 {
+      assert_eq!(manual_read_u16(&buf, offset), cursor_read_u16(&buf, offset));
+      assert_eq!(manual_read_u32(&buf, offset), cursor_read_u32(&buf, offset));
+    }
+    assert_eq!(manual_read_u32(&buf, 0), Some(0xFFFEFDFC));
+    assert!(manual_read_u16(&buf, usize::MAX).is_none());
+    assert!(cursor_read_u16(&buf, usize::MAX).is_none());
+  }
+}
@@ -0,0 +1,519 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+
+use assembler::parser::{parse_filter, AssembleError};
+use common::error::VmError;
+use common::instruction::*;
+use vm::machine::{FilterResult, PsuedoMachine};
+use vm::verifier::verify;
+
+/// How `Program::and` and `Program::or` treat the first program's verdict.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Combine {
+  /// Run the second program if the first accepts.
+  And,
+  /// Run the second program if the first drops.
+  Or,
+}
+
+/// Reasons `Program::from_file` can fail.
+#[derive(Debug)]
+pub enum LoadError {
+  /// The file couldn't be read.
+  Io(io::Error),
+  /// The file isn't valid assembly or `tcpdump -dd` output.
+  Assemble(AssembleError),
+  /// The program failed verification.
+  Verify(VmError),
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      LoadError::Io(ref err) => write!(f, "couldn't read program: {}", err),
+      LoadError::Assemble(ref err) => write!(f, "couldn't assemble program: {}", err),
+      LoadError::Verify(ref err) => write!(f, "program failed verification: {}", err),
+    }
+  }
+}
+
+impl Error for LoadError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match *self {
+      LoadError::Io(ref err) => Some(err),
+      LoadError::Assemble(ref err) => Some(err),
+      LoadError::Verify(ref err) => Some(err),
+    }
+  }
+}
+
+impl From<io::Error> for LoadError {
+  fn from(err: io::Error) -> LoadError {
+    LoadError::Io(err)
+  }
+}
+
+impl From<AssembleError> for LoadError {
+  fn from(err: AssembleError) -> LoadError {
+    LoadError::Assemble(err)
+  }
+}
+
+impl From<VmError> for LoadError {
+  fn from(err: VmError) -> LoadError {
+    LoadError::Verify(err)
+  }
+}
+
+/// A program that has passed the verifier, so every jump stays inside it and it always ends in a
+/// RET.
+///
+/// Derefs to `[Instruction]`, so a `&Program` can be passed anywhere a program slice is expected,
+/// e.g. to `PsuedoMachine::run_program`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Program {
+  instrs: Vec<Instruction>,
+}
+
+impl Program {
+  /// Verifies `instrs`.
+  /// Returns Err with the verifier's complaint if they aren't a safe program.
+  pub fn new(instrs: Vec<Instruction>) -> Result<Program, VmError> {
+    verify(&instrs)?;
+    Ok(Program { instrs })
+  }
+
+  /// Reads a program from a text file, in either assembly or `tcpdump -dd` format, and verifies
+  /// it.
+  pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Program, LoadError> {
+    let src = fs::read_to_string(path)?;
+    Ok(Program::new(parse_filter(&src)?)?)
+  }
+
+  /// Returns the instructions.
+  pub fn instructions(&self) -> &[Instruction] {
+    &self.instrs
+  }
+
+  /// Returns the instructions, giving up the guarantee they were verified.
+  pub fn into_instructions(self) -> Vec<Instruction> {
+    self.instrs
+  }
+
+  /// Returns a program accepting the packets both this program and `other` accept, with the
+  /// length `other` returns.
+  /// Returns Err(ProgramTooLong) if the result would be longer than `BPF_MAXINSNS`.
+  pub fn and(&self, other: &Program) -> Result<Program, VmError> {
+    self.combine(other, Combine::And)
+  }
+
+  /// Returns a program accepting the packets either this program or `other` accepts, with the
+  /// length returned by the first to accept.
+  /// Returns Err(ProgramTooLong) if the result would be longer than `BPF_MAXINSNS`.
+  pub fn or(&self, other: &Program) -> Result<Program, VmError> {
+    self.combine(other, Combine::Or)
+  }
+
+  /// Concatenates the two programs, turning each RET in this one whose verdict means `other`
+  /// has to run into a JA to it. RET A only decides at runtime, so it jumps to a shared JEQ #0
+  /// placed after this program, which either returns or falls through. Nothing in this program
+  /// moves, so its jumps are untouched, and `other` runs unchanged after a prologue resetting
+  /// the registers and the scratch slots it reads to the zeroes it would have started with.
+  fn combine(&self, other: &Program, how: Combine) -> Result<Program, VmError> {
+    let mut instrs = self.instrs.clone();
+    let needs_test = self.instrs.iter().any(|instr| instr.opcode == RETA);
+    let test = instrs.len();
+    if needs_test {
+      instrs.push(match how {
+        Combine::And => Instruction::new(JMPEQ, 0, 1, 0),
+        Combine::Or => Instruction::new(JMPEQ, 1, 0, 0),
+      });
+      instrs.push(match how {
+        Combine::And => Instruction::new(RETK, 0, 0, 0),
+        Combine::Or => Instruction::new(RETA, 0, 0, 0),
+      });
+    }
+    let second = instrs.len();
+    for pc in 0..self.instrs.len() {
+      let instr = self.instrs[pc];
+      let target = match (instr.opcode, how) {
+        (RETA, _) => test,
+        (RETK, Combine::And) if instr.k != 0 => second,
+        (RETK, Combine::Or) if instr.k == 0 => second,
+        _ => continue,
+      };
+      instrs[pc] = Instruction::new(JMP, 0, 0, (target - pc - 1) as u32);
+    }
+
+    instrs.push(Instruction::new(LDI, 0, 0, 0));
+    instrs.push(Instruction::new(TAX, 0, 0, 0));
+    let mut slots: Vec<u32> = other
+      .instrs
+      .iter()
+      .filter(|instr| (instr.class() == CLASS_LD || instr.class() == CLASS_LDX) && instr.mode() == MODE_MEM)
+      .map(|instr| instr.k)
+      .collect();
+    slots.sort_unstable();
+    slots.dedup();
+    instrs.extend(slots.into_iter().map(|slot| Instruction::new(ST, 0, 0, slot)));
+    instrs.extend_from_slice(&other.instrs);
+    Program::new(instrs)
+  }
+}
+
+impl Deref for Program {
+  type Target = [Instruction];
+
+  fn deref(&self) -> &[Instruction] {
+    &self.instrs
+  }
+}
+
+impl<'a> TryFrom<&'a [Instruction]> for Program {
+  type Error = VmError;
+
+  fn try_from(instrs: &'a [Instruction]) -> Result<Program, VmError> {
+    Program::new(instrs.to_vec())
+  }
+}
+
+/// A verified program to run over many packets, e.g. from several threads behind an `Arc`.
+/// Each packet gets a fresh machine, so runs never see each other's state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Filter {
+  prog: Program,
+}
+
+impl Filter {
+  /// Returns a filter running `prog`.
+  pub fn new(prog: Program) -> Filter {
+    Filter { prog }
+  }
+
+  /// Returns the program the filter runs.
+  pub fn program(&self) -> &Program {
+    &self.prog
+  }
+
+  /// Returns whether the program accepts `pkt`. A packet the program fails on, e.g. by loading
+  /// past its end, is dropped like the kernel does.
+  pub fn matches(&self, pkt: &[u8]) -> bool {
+    matches!(
+      PsuedoMachine::new().run_filter(&self.prog, pkt),
+      Ok(FilterResult::Accept(_))
+    )
+  }
+
+  /// Returns the part of `pkt` the program keeps, truncated to the length it returned, or None
+  /// if it drops the packet.
+  pub fn capture<'p>(&self, pkt: &'p [u8]) -> Option<&'p [u8]> {
+    PsuedoMachine::new().run_filter(&self.prog, pkt).ok()?.truncate(pkt)
+  }
+
+  /// Returns an iterator over the packets in `packets` the program accepts, running it lazily
+  /// as the iterator advances.
+  pub fn filter_packets<I: IntoIterator>(&self, packets: I) -> FilterIter<'_, I::IntoIter> {
+    FilterIter::new(self, packets.into_iter())
+  }
+
+  /// Like `filter_packets`, but yields each accepted packet truncated to the length the program
+  /// returned, the way a capture's snaplen is applied.
+  pub fn capture_packets<'p, I>(&self, packets: I) -> CaptureIter<'_, I::IntoIter>
+  where
+    I: IntoIterator<Item = &'p [u8]>,
+  {
+    CaptureIter {
+      filter: self,
+      packets: packets.into_iter(),
+    }
+  }
+}
+
+/// Yields the packets of an inner iterator that a filter accepts, e.g. from
+/// `filter.filter_packets(pkts.iter())`.
+#[derive(Clone, Debug)]
+pub struct FilterIter<'f, I> {
+  filter: &'f Filter,
+  packets: I,
+}
+
+impl<'f, I> FilterIter<'f, I> {
+  /// Returns an iterator over the packets in `packets` that `filter` accepts.
+  pub fn new(filter: &'f Filter, packets: I) -> FilterIter<'f, I> {
+    FilterIter { filter, packets }
+  }
+}
+
+impl<'f, I> Iterator for FilterIter<'f, I>
+where
+  I: Iterator,
+  I::Item: AsRef<[u8]>,
+{
+  type Item = I::Item;
+
+  fn next(&mut self) -> Option<I::Item> {
+    let filter = self.filter;
+    self.packets.find(|pkt| filter.matches(pkt.as_ref()))
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    (0, self.packets.size_hint().1)
+  }
+}
+
+/// Yields the packets of an inner iterator that a filter accepts, truncated to the length it
+/// returned. Made by `Filter::capture_packets`.
+#[derive(Clone, Debug)]
+pub struct CaptureIter<'f, I> {
+  filter: &'f Filter,
+  packets: I,
+}
+
+impl<'f, 'p, I: Iterator<Item = &'p [u8]>> Iterator for CaptureIter<'f, I> {
+  type Item = &'p [u8];
+
+  fn next(&mut self) -> Option<&'p [u8]> {
+    let filter = self.filter;
+    self.packets.find_map(|pkt| filter.capture(pkt))
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    (0, self.packets.size_hint().1)
+  }
+}
+
+impl From<Program> for Filter {
+  fn from(prog: Program) -> Filter {
+    Filter::new(prog)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env;
+  use std::sync::Arc;
+  use std::thread;
+
+  #[test]
+  fn new_verifies() {
+    let unterminated = vec![Instruction::new(LDI, 0, 0, 1), Instruction::new(TAX, 0, 0, 0)];
+    assert_eq!(Program::new(unterminated), Err(VmError::MissingReturn(1)));
+
+    let instrs = vec![
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 1, 1),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    let prog = Program::try_from(&instrs[..]).unwrap();
+    assert_eq!(prog.len(), 4);
+    assert_eq!(prog[1], Instruction::new(JMPEQ, 0, 1, 1));
+    assert_eq!(PsuedoMachine::new().run_program(&prog, &[1]), Ok(0xFFFF));
+    assert_eq!(prog.into_instructions(), instrs);
+  }
+
+  #[test]
+  fn from_file() {
+    let dir = env::temp_dir().join(format!("rust-bpf-program-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let expected = [
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 1, 0x800),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+
+    let asm = dir.join("ip.bpf");
+    fs::write(
+      &asm,
+      "ldh [12]\njeq #0x800, accept, drop\naccept: ret #0xffff\ndrop: ret #0\n",
+    )
+    .unwrap();
+    assert_eq!(Program::from_file(&asm).unwrap().instructions(), &expected[..]);
+
+    let dd = dir.join("ip.dd");
+    let dump = "{ 0x28, 0, 0, 0x0000000c },\n{ 0x15, 0, 1, 0x00000800 },\n{ 0x6, 0, 0, 0x0000ffff },\n{ 0x6, 0, 0, 0x00000000 },\n";
+    fs::write(&dd, dump).unwrap();
+    assert_eq!(Program::from_file(&dd).unwrap().instructions(), &expected[..]);
+
+    let bad = dir.join("bad.bpf");
+    fs::write(&bad, "ldh [12]\nfrob #1\n").unwrap();
+    match Program::from_file(&bad) {
+      Err(LoadError::Assemble(err)) => assert_eq!(err.line, 2),
+      other => panic!("unexpected {:?}", other),
+    }
+    fs::write(&bad, "ldh [12]\n").unwrap();
+    match Program::from_file(&bad) {
+      Err(LoadError::Verify(VmError::MissingReturn(0))) => (),
+      other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(
+      Program::from_file(dir.join("missing.bpf")),
+      Err(LoadError::Io(_))
+    ));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn and_or() {
+    // Packets of at least 4 bytes.
+    let long = Program::new(vec![
+      Instruction::new(LDL, 0, 0, 0),
+      Instruction::new(JMPGE, 0, 1, 4),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ])
+    .unwrap();
+    // Packets starting with 0x45, keeping their first two bytes.
+    let ipv4 = Program::new(vec![
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 1, 0x45),
+      Instruction::new(RETK, 0, 0, 2),
+      Instruction::new(RETK, 0, 0, 0),
+    ])
+    .unwrap();
+    let pkts: [&[u8]; 4] = [&[0x45, 0, 0, 0], &[0x45], &[0x60, 0, 0, 0], &[0x60]];
+    let run = |prog: &Program| -> Vec<FilterResult> {
+      pkts
+        .iter()
+        .map(|pkt| PsuedoMachine::new().run_filter(prog, pkt).unwrap())
+        .collect()
+    };
+    assert_eq!(
+      run(&long.and(&ipv4).unwrap()),
+      vec![
+        FilterResult::Accept(2),
+        FilterResult::Drop,
+        FilterResult::Drop,
+        FilterResult::Drop
+      ]
+    );
+    assert_eq!(
+      run(&long.or(&ipv4).unwrap()),
+      vec![
+        FilterResult::Accept(0xFFFF),
+        FilterResult::Accept(2),
+        FilterResult::Accept(0xFFFF),
+        FilterResult::Drop
+      ]
+    );
+    assert_eq!(long.and(&ipv4).unwrap().len(), long.len() + 2 + ipv4.len());
+  }
+
+  #[test]
+  fn and_or_return_a() {
+    // Returns the second byte, so accepts packets whose second byte isn't zero. Leaves 7 in X
+    // and M[0] for the program after it.
+    let second = Program::new(vec![
+      Instruction::new(LDXI, 0, 0, 7),
+      Instruction::new(STX, 0, 0, 0),
+      Instruction::new(LDB, 0, 0, 1),
+      Instruction::new(RETA, 0, 0, 0),
+    ])
+    .unwrap();
+    // Accepts when X and M[0] start out zero.
+    let fresh = Program::new(vec![
+      Instruction::new(LDWM, 0, 0, 0),
+      Instruction::new(ADDX, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 1, 0),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ])
+    .unwrap();
+    let run = |prog: &Program, pkt: &[u8]| PsuedoMachine::new().run_filter(prog, pkt).unwrap();
+
+    let both = second.and(&fresh).unwrap();
+    assert_eq!(run(&both, &[0, 3]), FilterResult::Accept(0xFFFF));
+    assert_eq!(run(&both, &[0, 0]), FilterResult::Drop);
+    let either = second.or(&fresh).unwrap();
+    assert_eq!(run(&either, &[0, 3]), FilterResult::Accept(3));
+    assert_eq!(run(&either, &[0, 0]), FilterResult::Accept(0xFFFF));
+
+    let big = Program::new(vec![Instruction::new(RETK, 0, 0, 0); 4000]).unwrap();
+    assert_eq!(big.or(&big), Err(VmError::ProgramTooLong(8002)));
+  }
+
+  #[test]
+  fn filter_iter() {
+    let filter = Filter::new(
+      Program::new(vec![
+        Instruction::new(LDB, 0, 0, 0),
+        Instruction::new(JMPGE, 0, 1, 0x80),
+        Instruction::new(RETK, 0, 0, 0xFFFF),
+        Instruction::new(RETK, 0, 0, 0),
+      ])
+      .unwrap(),
+    );
+    let pkts: [&[u8]; 5] = [&[0x7F], &[0x80, 1], &[], &[0x00], &[0xFF]];
+    let matched: Vec<&[u8]> = filter.filter_packets(pkts.iter().cloned()).collect();
+    assert_eq!(matched, vec![&[0x80, 1][..], &[0xFF][..]]);
+
+    let owned: Vec<Vec<u8>> = (0..=255u8).map(|i| vec![i]).collect();
+    let mut iter = FilterIter::new(&filter, owned.into_iter());
+    assert_eq!(iter.size_hint(), (0, Some(256)));
+    assert_eq!(iter.next(), Some(vec![0x80]));
+    assert_eq!(
+      iter.map(|pkt| pkt[0]).step_by(64).collect::<Vec<u8>>(),
+      vec![0x81, 0xC1]
+    );
+  }
+
+  #[test]
+  fn capture_truncates() {
+    let filter = Filter::new(
+      Program::new(vec![
+        Instruction::new(LDH, 0, 0, 12),
+        Instruction::new(JMPEQ, 0, 1, 0x800),
+        Instruction::new(RETK, 0, 0, 14),
+        Instruction::new(RETK, 0, 0, 0),
+      ])
+      .unwrap(),
+    );
+    let mut ip = vec![0xFFu8; 60];
+    ip[12..14].copy_from_slice(&[0x08, 0x00]);
+    let arp = {
+      let mut arp = ip.clone();
+      arp[13] = 0x06;
+      arp
+    };
+    assert_eq!(filter.capture(&ip), Some(&ip[..14]));
+    assert_eq!(filter.capture(&arp), None);
+    assert_eq!(filter.capture(&ip[..13]), None);
+
+    let pkts: Vec<&[u8]> = vec![&ip, &arp, &ip[..14], &ip[..20]];
+    let captured: Vec<&[u8]> = filter.capture_packets(pkts).collect();
+    assert_eq!(captured, vec![&ip[..14]; 3]);
+  }
+
+  #[test]
+  fn filter_shared_across_threads() {
+    let prog = Program::new(vec![
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPSET, 0, 1, 1),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ])
+    .unwrap();
+    let filter = Arc::new(Filter::new(prog));
+    let pkts: Vec<Vec<u8>> = (0..64u8).map(|i| vec![i, 0xFF]).collect();
+    let expected: Vec<bool> = pkts.iter().map(|pkt| pkt[0] & 1 == 1).collect();
+
+    let threads: Vec<_> = (0..4)
+      .map(|_| {
+        let filter = filter.clone();
+        let pkts = pkts.clone();
+        thread::spawn(move || pkts.iter().map(|pkt| filter.matches(pkt)).collect::<Vec<bool>>())
+      })
+      .collect();
+    for handle in threads {
+      assert_eq!(handle.join().unwrap(), expected);
+    }
+    assert!(!filter.matches(&[]));
+  }
+}
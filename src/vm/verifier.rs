@@ -0,0 +1,422 @@
+use common::error::VmError;
+use common::instruction::*;
+#[cfg(feature = "std")]
+use vm::ancillary::SKF_AD_MAX;
+use vm::ancillary::SKF_AD_OFF;
+use vm::machine::SCRATCH_MEM_SLOTS;
+
+/// The most instructions the kernel accepts in a classic BPF program.
+pub const BPF_MAXINSNS: usize = 4096;
+
+/// The number of scratch memory slots the kernel gives a classic BPF program.
+pub const BPF_MEMWORDS: usize = 16;
+
+/// Returns the index execution continues at after the instruction at `pc` skips `offset`
+/// instructions. Offsets are unsigned, so every jump moves forward; one too far for a `usize`
+/// saturates, which lands past the end of any program.
+fn jump_target(pc: usize, offset: u32) -> usize {
+  pc.saturating_add(1).saturating_add(offset as usize)
+}
+
+/// Returns whether `instr` reads or writes the scratch memory slot `k`. Stores to slot X + k
+/// can only be checked at runtime.
+fn uses_scratch_memory(instr: &Instruction) -> bool {
+  match instr.class() {
+    CLASS_LD | CLASS_LDX => instr.mode() == MODE_MEM,
+    CLASS_ST | CLASS_STX => instr.mode() != MODE_IND,
+    _ => false,
+  }
+}
+
+/// Returns how many instructions each way out of `instr` skips.
+fn offsets(instr: &Instruction) -> [Option<u32>; 2] {
+  match instr.class() {
+    CLASS_RET => [None, None],
+    CLASS_JMP if instr.op() == OP_JA => [Some(instr.k), None],
+    CLASS_JMP => [Some(instr.jt as u32), Some(instr.jf as u32)],
+    _ => [Some(0), None],
+  }
+}
+
+/// Returns which instructions can be reached by following jumps and fall throughs from the
+/// first one.
+#[cfg(feature = "std")]
+pub fn reachable(prog: &[Instruction]) -> Vec<bool> {
+  let mut seen = vec![false; prog.len()];
+  let mut todo = vec![0];
+  while let Some(pc) = todo.pop() {
+    if pc >= prog.len() || seen[pc] {
+      continue;
+    }
+    seen[pc] = true;
+    let targets = offsets(&prog[pc]);
+    todo.extend(targets.iter().flatten().map(|&offset| jump_target(pc, offset)));
+  }
+  seen
+}
+
+/// Returns how long a packet has to be for every absolute load in `prog` to fit, i.e. the largest
+/// `offset + size` any of them reads.
+/// Returns None if the program has indirect loads, whose offsets depend on the packet.
+pub fn min_packet_len(prog: &[Instruction]) -> Option<u32> {
+  let mut len = 0u32;
+  for instr in prog {
+    let size = match (instr.class(), instr.mode()) {
+      (CLASS_LD, MODE_IND) => return None,
+      (CLASS_LD, MODE_ABS) if instr.k >= SKF_AD_OFF => continue,
+      (CLASS_LD, MODE_ABS) => match instr.size() {
+        SIZE_W => 4,
+        SIZE_H => 2,
+        _ => 1,
+      },
+      (CLASS_LDX, MODE_ABS) => 4,
+      (CLASS_LDX, MODE_MSH) => 1,
+      _ => continue,
+    };
+    len = len.max(instr.k.saturating_add(size));
+  }
+  Some(len)
+}
+
+/// Returns a bitmask of the scratch memory slots `prog` reads or writes, bit `n` standing for
+/// `M[n]`. An indirect store can land in any slot, so it sets every bit, while slots past
+/// `BPF_MEMWORDS` are left out.
+pub fn memory_slots_used(prog: &[Instruction]) -> u16 {
+  let mut used = 0u16;
+  for instr in prog {
+    if instr.opcode == STI || instr.opcode == STXI {
+      return u16::MAX;
+    }
+    if uses_scratch_memory(instr) && (instr.k as usize) < BPF_MEMWORDS {
+      used |= 1 << instr.k;
+    }
+  }
+  used
+}
+
+/// Checks that a program is safe to run before handing it to the machine.
+/// Every jump must land inside the program and only move forward, every scratch memory access
+/// must address an existing slot, the last instruction must be a RET, and the program can't be
+/// longer than `BPF_MAXINSNS`.
+/// Returns Err identifying the first offending instruction otherwise.
+pub fn verify(prog: &[Instruction]) -> Result<(), VmError> {
+  verify_with_max_len(prog, BPF_MAXINSNS)
+}
+
+/// Like `verify`, but allows programs of up to `max_len` instructions.
+pub fn verify_with_max_len(prog: &[Instruction], max_len: usize) -> Result<(), VmError> {
+  if prog.len() > max_len {
+    return Err(VmError::ProgramTooLong(prog.len()));
+  }
+  for (pc, instr) in prog.iter().enumerate() {
+    if instr.class() == CLASS_JMP {
+      for &offset in offsets(instr).iter().flatten() {
+        if jump_target(pc, offset) >= prog.len() {
+          return Err(VmError::JumpOutOfRange(pc));
+        }
+      }
+    }
+    if uses_scratch_memory(instr) && instr.k as usize >= SCRATCH_MEM_SLOTS {
+      return Err(VmError::ScratchSlotOutOfRange(pc));
+    }
+  }
+  match prog.last() {
+    Some(instr) if instr.class() == CLASS_RET => Ok(()),
+    _ => Err(VmError::MissingReturn(prog.len().saturating_sub(1))),
+  }
+}
+
+/// Checks a program against the rules the Linux classic BPF verifier (`bpf_check_classic`)
+/// enforces, so it's guaranteed to load with `SO_ATTACH_FILTER`. On top of `verify` this rejects
+/// empty programs, the opcodes only this crate supports (half-word and byte scratch memory loads,
+/// `ldx [k]`, `M[x + k]` stores and the signed jumps), unknown ancillary data offsets, DIV and MOD
+/// by a constant zero, shifts by a constant 32 or more, and scratch memory loads that aren't
+/// preceded by a store to the slot on every path.
+/// Returns Err identifying the first offending instruction otherwise.
+#[cfg(feature = "std")]
+pub fn verify_linux_compat(prog: &[Instruction]) -> Result<(), VmError> {
+  if prog.len() > BPF_MAXINSNS {
+    return Err(VmError::ProgramTooLong(prog.len()));
+  }
+  for (pc, instr) in prog.iter().enumerate() {
+    let opcode = Opcode::from_raw(instr.opcode).ok_or(VmError::UnsupportedInstruction(pc))?;
+    match opcode {
+      Opcode::Ldhm
+      | Opcode::Ldbm
+      | Opcode::Ldxw
+      | Opcode::Sti
+      | Opcode::Stxi
+      | Opcode::Jmpsgt
+      | Opcode::Jmpsge
+      | Opcode::Jmpsgtx
+      | Opcode::Jmpsgex => return Err(VmError::UnsupportedInstruction(pc)),
+      Opcode::Ldw | Opcode::Ldh | Opcode::Ldb if instr.k >= SKF_AD_OFF && !known_ancillary(instr.k) => {
+        return Err(VmError::UnsupportedInstruction(pc))
+      },
+      Opcode::Divk | Opcode::Modk if instr.k == 0 => return Err(VmError::ConstantDivideByZero(pc)),
+      Opcode::Lshk | Opcode::Rshk if instr.k >= 32 => return Err(VmError::ShiftOutOfRange(pc)),
+      _ => (),
+    }
+  }
+  verify_with_max_len(prog, BPF_MAXINSNS)?;
+  check_loads_and_stores(prog)
+}
+
+/// Returns whether an absolute load from `k` reads ancillary data the kernel knows about.
+#[cfg(feature = "std")]
+fn known_ancillary(k: u32) -> bool {
+  let offset = k - SKF_AD_OFF;
+  offset < SKF_AD_MAX && offset.is_multiple_of(4)
+}
+
+/// Walks the program in order, tracking which scratch memory slots are stored to on every path
+/// reaching each instruction, like the kernel's `check_load_and_stores`. Like the kernel, only a
+/// jump resets what's known; the instruction after a RET inherits the stores before it.
+#[cfg(feature = "std")]
+fn check_loads_and_stores(prog: &[Instruction]) -> Result<(), VmError> {
+  // Bit i of masks[pc] is set if M[i] is stored to on every path into pc seen so far.
+  let mut masks = vec![u16::MAX; prog.len()];
+  let mut valid = 0u16;
+  for (pc, instr) in prog.iter().enumerate() {
+    valid &= masks[pc];
+    let slot = 1u16 << (instr.k as usize % BPF_MEMWORDS);
+    match (instr.class(), instr.mode()) {
+      (CLASS_ST, _) | (CLASS_STX, _) => valid |= slot,
+      (CLASS_LD, MODE_MEM) | (CLASS_LDX, MODE_MEM) if valid & slot == 0 => {
+        return Err(VmError::UninitializedScratchSlot(pc))
+      },
+      (CLASS_JMP, _) => {
+        for &offset in offsets(instr).iter().flatten() {
+          masks[pc + 1 + offset as usize] &= valid;
+        }
+        valid = u16::MAX;
+      },
+      _ => (),
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn accepts_valid_program() {
+    let prog = vec![
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 1, 0x800),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(ST, 0, 0, 15),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    assert_eq!(verify(&prog), Ok(()));
+  }
+
+  #[test]
+  fn rejects_jump_out_of_range() {
+    let prog = vec![
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(JMPEQ, 0, 1, 1),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    assert_eq!(verify(&prog), Err(VmError::JumpOutOfRange(1)));
+    let prog = vec![Instruction::new(JMP, 0, 0, 1), Instruction::new(RETK, 0, 0, 0)];
+    assert_eq!(verify(&prog), Err(VmError::JumpOutOfRange(0)));
+  }
+
+  #[test]
+  fn rejects_jump_past_frame_limit() {
+    let prog = vec![
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(JMP, 0, 0, 0xFFFFFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    assert_eq!(verify(&prog), Err(VmError::JumpOutOfRange(1)));
+  }
+
+  #[test]
+  fn rejects_bad_scratch_slot() {
+    let prog = vec![Instruction::new(STX, 0, 0, 16), Instruction::new(RETK, 0, 0, 0)];
+    assert_eq!(verify(&prog), Err(VmError::ScratchSlotOutOfRange(0)));
+    let prog = vec![
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(LDXM, 0, 0, 16),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    assert_eq!(verify(&prog), Err(VmError::ScratchSlotOutOfRange(1)));
+  }
+
+  #[test]
+  fn rejects_missing_return() {
+    let prog = vec![Instruction::new(LDI, 0, 0, 1), Instruction::new(TAX, 0, 0, 0)];
+    assert_eq!(verify(&prog), Err(VmError::MissingReturn(1)));
+    assert_eq!(verify(&[]), Err(VmError::MissingReturn(0)));
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn rejects_long_program() {
+    let mut prog = vec![Instruction::new(LDI, 0, 0, 1); BPF_MAXINSNS];
+    prog[BPF_MAXINSNS - 1] = Instruction::new(RETA, 0, 0, 0);
+    assert_eq!(verify(&prog), Ok(()));
+    prog.insert(0, Instruction::new(TAX, 0, 0, 0));
+    assert_eq!(verify(&prog), Err(VmError::ProgramTooLong(BPF_MAXINSNS + 1)));
+    assert_eq!(verify_with_max_len(&prog[..3], 2), Err(VmError::ProgramTooLong(3)));
+    assert_eq!(verify_with_max_len(&prog, BPF_MAXINSNS + 1), Ok(()));
+  }
+
+  #[test]
+  fn packet_len_needed() {
+    let prog = [
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 3, 0x800),
+      Instruction::new(LDB, 0, 0, 23),
+      Instruction::new(JMPEQ, 0, 1, 6),
+      Instruction::new(RETK, 0, 0, 0xFFFF),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    assert_eq!(min_packet_len(&prog), Some(24));
+    assert_eq!(min_packet_len(&prog[..2]), Some(14));
+    assert_eq!(min_packet_len(&[Instruction::new(LDXW, 0, 0, 30)]), Some(34));
+    assert_eq!(min_packet_len(&[Instruction::new(LDW, 0, 0, SKF_AD_OFF)]), Some(0));
+    assert_eq!(
+      min_packet_len(&[Instruction::new(LDXW, 0, 0, 0xFFFFFFFF)]),
+      Some(u32::MAX)
+    );
+    assert!(min_packet_len(&[Instruction::new(LDXB, 0, 0, 14), Instruction::new(LDHI, 0, 0, 16)]).is_none());
+  }
+
+  #[test]
+  fn slots_used() {
+    let prog = [
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(ST, 0, 0, 0),
+      Instruction::new(LDXI, 0, 0, 2),
+      Instruction::new(STX, 0, 0, 5),
+      Instruction::new(LDWM, 0, 0, 0),
+      Instruction::new(LDXM, 0, 0, 5),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert_eq!(memory_slots_used(&prog), 0b10_0001);
+    assert_eq!(memory_slots_used(&prog[..1]), 0);
+    let indirect = [Instruction::new(STI, 0, 0, 0), Instruction::new(RETK, 0, 0, 0)];
+    assert_eq!(memory_slots_used(&indirect), u16::MAX);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn reachable_instructions() {
+    let prog = vec![
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 1, 2, 0x800),
+      Instruction::new(RETK, 0, 0, 1),
+      Instruction::new(JMP, 0, 0, 1),
+      Instruction::new(RETK, 0, 0, 2),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    assert_eq!(reachable(&prog), vec![true, true, false, true, true, true]);
+    assert!(reachable(&[]).is_empty());
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn linux_compat() {
+    let prog = [
+      Instruction::new(LDH, 0, 0, 12),
+      Instruction::new(JMPEQ, 0, 3, 0x800),
+      Instruction::new(ST, 0, 0, 0),
+      Instruction::new(LDWM, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    assert_eq!(verify_linux_compat(&prog), Ok(()));
+    assert_eq!(verify_linux_compat(&[]), Err(VmError::MissingReturn(0)));
+
+    let div = [Instruction::new(DIVK, 0, 0, 0), Instruction::new(RETA, 0, 0, 0)];
+    assert_eq!(verify(&div), Ok(()));
+    assert_eq!(verify_linux_compat(&div), Err(VmError::ConstantDivideByZero(0)));
+    let modulo = [
+      Instruction::new(LDI, 0, 0, 1),
+      Instruction::new(MODK, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert_eq!(verify_linux_compat(&modulo), Err(VmError::ConstantDivideByZero(1)));
+    let shift = [Instruction::new(LSHK, 0, 0, 32), Instruction::new(RETA, 0, 0, 0)];
+    assert_eq!(verify_linux_compat(&shift), Err(VmError::ShiftOutOfRange(0)));
+    let indirect = [Instruction::new(STI, 0, 0, 1), Instruction::new(RETA, 0, 0, 0)];
+    assert_eq!(verify_linux_compat(&indirect), Err(VmError::UnsupportedInstruction(0)));
+    let unknown = [Instruction::new(0xFFFF, 0, 0, 0), Instruction::new(RETA, 0, 0, 0)];
+    assert_eq!(verify_linux_compat(&unknown), Err(VmError::UnsupportedInstruction(0)));
+    for &opcode in [LDHM, LDBM, LDXW, JMPSGT, JMPSGEX].iter() {
+      let prog = [
+        Instruction::new(ST, 0, 0, 0),
+        Instruction::new(opcode, 0, 0, 0),
+        Instruction::new(RETA, 0, 0, 0),
+      ];
+      assert_eq!(verify_linux_compat(&prog), Err(VmError::UnsupportedInstruction(1)));
+    }
+    let ancillary = [
+      Instruction::new(LDW, 0, 0, SKF_AD_OFF + 60),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert_eq!(verify_linux_compat(&ancillary), Ok(()));
+    let ancillary = [
+      Instruction::new(LDB, 0, 0, SKF_AD_OFF + SKF_AD_MAX),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert_eq!(verify_linux_compat(&ancillary), Err(VmError::UnsupportedInstruction(0)));
+    let slot = [
+      Instruction::new(LDXM, 0, 0, BPF_MEMWORDS as u32),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert_eq!(verify_linux_compat(&slot), Err(VmError::ScratchSlotOutOfRange(0)));
+    // A RET doesn't make the slots after it valid, even on a path no packet can take.
+    let after_ret = [
+      Instruction::new(RETK, 0, 0, 0),
+      Instruction::new(LDWM, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert_eq!(
+      verify_linux_compat(&after_ret),
+      Err(VmError::UninitializedScratchSlot(1))
+    );
+    let stored_before_ret = [
+      Instruction::new(ST, 0, 0, 0),
+      Instruction::new(RETK, 0, 0, 0),
+      Instruction::new(LDWM, 0, 0, 0),
+      Instruction::new(RETA, 0, 0, 0),
+    ];
+    assert_eq!(verify_linux_compat(&stored_before_ret), Ok(()));
+    assert_eq!(verify_linux_compat(&prog[..4]), Err(VmError::JumpOutOfRange(1)));
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn linux_compat_uninitialized_memory() {
+    assert_eq!(
+      verify_linux_compat(&[Instruction::new(LDWM, 0, 0, 3), Instruction::new(RETA, 0, 0, 0)]),
+      Err(VmError::UninitializedScratchSlot(0))
+    );
+    // M[1] is only stored to when the jump is taken.
+    let prog = [
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 1, 1),
+      Instruction::new(ST, 0, 0, 1),
+      Instruction::new(LDXM, 0, 0, 1),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    assert_eq!(verify(&prog), Ok(()));
+    assert_eq!(verify_linux_compat(&prog), Err(VmError::UninitializedScratchSlot(3)));
+    // Storing before the branch covers both paths.
+    let prog = [
+      Instruction::new(STX, 0, 0, 1),
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 1, 1),
+      Instruction::new(ST, 0, 0, 2),
+      Instruction::new(LDXM, 0, 0, 1),
+      Instruction::new(RETK, 0, 0, 0),
+    ];
+    assert_eq!(verify_linux_compat(&prog), Ok(()));
+  }
+}
@@ -0,0 +1,93 @@
+#![cfg(all(feature = "socket", target_os = "linux"))]
+
+//! Loads the output of `to_ebpf` into the kernel with the `bpf()` system call, so its verifier
+//! checks the translation.
+
+extern crate libc;
+extern crate rust_bpf;
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use rust_bpf::common::instruction::*;
+use rust_bpf::filter::builder::FilterBuilder;
+use rust_bpf::vm::ebpf::{to_ebpf, EbpfInsn};
+
+/// The leading fields of `union bpf_attr` for `BPF_PROG_LOAD`.
+#[repr(C)]
+struct ProgLoadAttr {
+  prog_type: u32,
+  insn_cnt: u32,
+  insns: u64,
+  license: u64,
+  log_level: u32,
+  log_size: u32,
+  log_buf: u64,
+}
+
+/// Loads `insns` as a socket filter.
+/// Returns the kernel verifier's log on failure, or None if the process isn't allowed to load
+/// eBPF programs.
+fn load_socket_filter(insns: &[EbpfInsn]) -> Option<Result<(), String>> {
+  const BPF_PROG_LOAD: libc::c_long = 5;
+  const BPF_PROG_TYPE_SOCKET_FILTER: u32 = 1;
+  let license = b"GPL\0";
+  let mut log = vec![0u8; 1 << 16];
+  let attr = ProgLoadAttr {
+    prog_type: BPF_PROG_TYPE_SOCKET_FILTER,
+    insn_cnt: insns.len() as u32,
+    insns: insns.as_ptr() as u64,
+    license: license.as_ptr() as u64,
+    log_level: 1,
+    log_size: log.len() as u32,
+    log_buf: log.as_mut_ptr() as u64,
+  };
+  let fd = unsafe {
+    libc::syscall(
+      libc::SYS_bpf,
+      BPF_PROG_LOAD,
+      &attr as *const ProgLoadAttr,
+      mem::size_of::<ProgLoadAttr>(),
+    )
+  };
+  if fd < 0 {
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::PermissionDenied && log[0] == 0 {
+      return None;
+    }
+    return Some(Err(String::from_utf8_lossy(&log).trim_end_matches('\0').to_string()));
+  }
+  unsafe { libc::close(fd as RawFd) };
+  Some(Ok(()))
+}
+
+#[test]
+fn ebpf_translation_loads() {
+  let progs = vec![
+    FilterBuilder::new().tcp_dst_port(80).build().unwrap(),
+    FilterBuilder::new().tcp_src_port(53).build().unwrap(),
+    vec![
+      Instruction::new(ST, 0, 0, 3),
+      Instruction::new(LDXM, 0, 0, 3),
+      Instruction::new(LDL, 0, 0, 0),
+      Instruction::new(DIVX, 0, 0, 0),
+      Instruction::new(LDXB, 0, 0, 14),
+      Instruction::new(LDHI, 0, 0, 0),
+      Instruction::new(JMPGT, 1, 0, 0xFFFFFFFF),
+      Instruction::new(JMPSET, 1, 2, 1),
+      Instruction::new(RETA, 0, 0, 0),
+      Instruction::new(RETK, 0, 0, 1),
+      Instruction::new(RETK, 0, 0, 0),
+    ],
+  ];
+  for prog in progs {
+    match load_socket_filter(&to_ebpf(&prog).unwrap()) {
+      Some(loaded) => assert!(loaded.is_ok(), "{:?}: {}", prog, loaded.unwrap_err()),
+      None => {
+        eprintln!("skipping: loading eBPF programs needs CAP_BPF");
+        return;
+      },
+    }
+  }
+}
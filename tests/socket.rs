@@ -0,0 +1,177 @@
+#![cfg(all(feature = "socket", target_os = "linux"))]
+
+extern crate libc;
+extern crate rust_bpf;
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::UdpSocket;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use rust_bpf::common::instruction::*;
+use rust_bpf::socket::{attach_filter, detach_filter};
+use rust_bpf::vm::ancillary::SKF_AD_OFF;
+use rust_bpf::vm::verifier::verify_linux_compat;
+
+/// Accepts IPv4 UDP datagrams to `port`.
+fn udp_dst_port(port: u16) -> Vec<Instruction> {
+  vec![
+    Instruction::new(LDH, 0, 0, 12),
+    Instruction::new(JMPEQ, 0, 6, 0x0800),
+    Instruction::new(LDB, 0, 0, 23),
+    Instruction::new(JMPEQ, 0, 4, 17),
+    Instruction::new(LDXB, 0, 0, 14),
+    Instruction::new(LDHI, 0, 0, 16),
+    Instruction::new(JMPEQ, 0, 1, port as u32),
+    Instruction::new(RETK, 0, 0, 0xFFFF),
+    Instruction::new(RETK, 0, 0, 0),
+  ]
+}
+
+/// Opens a packet socket that receives nothing until it's bound.
+/// Returns None if the process isn't allowed to open packet sockets.
+fn packet_socket() -> Option<RawFd> {
+  let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+  if fd < 0 {
+    let err = io::Error::last_os_error();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "socket: {}", err);
+    return None;
+  }
+  let timeout = libc::timeval {
+    tv_sec: 0,
+    tv_usec: 200_000,
+  };
+  let ret = unsafe {
+    libc::setsockopt(
+      fd,
+      libc::SOL_SOCKET,
+      libc::SO_RCVTIMEO,
+      &timeout as *const libc::timeval as *const libc::c_void,
+      mem::size_of::<libc::timeval>() as libc::socklen_t,
+    )
+  };
+  assert_eq!(ret, 0, "SO_RCVTIMEO: {}", io::Error::last_os_error());
+  Some(fd)
+}
+
+/// Starts delivering every packet on the loopback interface to `fd`.
+fn bind_loopback(fd: RawFd) {
+  let lo = CString::new("lo").unwrap();
+  let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+  addr.sll_family = libc::AF_PACKET as u16;
+  addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+  addr.sll_ifindex = unsafe { libc::if_nametoindex(lo.as_ptr()) } as i32;
+  let ret = unsafe {
+    libc::bind(
+      fd,
+      &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+      mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+    )
+  };
+  assert_eq!(ret, 0, "bind: {}", io::Error::last_os_error());
+}
+
+/// Returns the UDP destination ports of the IPv4 packets `fd` receives until it times out.
+fn received_udp_ports(fd: RawFd) -> Vec<u16> {
+  let mut ports = Vec::new();
+  let mut buf = [0u8; 2048];
+  loop {
+    let len = unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+    if len < 0 {
+      let err = io::Error::last_os_error();
+      assert_eq!(err.kind(), io::ErrorKind::WouldBlock, "recv: {}", err);
+      return ports;
+    }
+    let pkt = &buf[..len as usize];
+    if pkt.len() >= 34 && pkt[12..14] == [0x08, 0x00] && pkt[23] == 17 {
+      let udp = 14 + (pkt[14] & 0xF) as usize * 4;
+      ports.push(u16::from_be_bytes([pkt[udp + 2], pkt[udp + 3]]));
+    }
+  }
+}
+
+#[test]
+fn attach_to_loopback_packet_socket() {
+  let fd = match packet_socket() {
+    Some(fd) => fd,
+    None => {
+      eprintln!("skipping: opening a packet socket needs CAP_NET_RAW");
+      return;
+    },
+  };
+  let wanted = UdpSocket::bind("127.0.0.1:0").unwrap();
+  let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+  let wanted_port = wanted.local_addr().unwrap().port();
+  let other_port = other.local_addr().unwrap().port();
+  let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+  attach_filter(fd, &udp_dst_port(wanted_port)).unwrap();
+  bind_loopback(fd);
+  for _ in 0..3 {
+    sender.send_to(b"other", other.local_addr().unwrap()).unwrap();
+    sender.send_to(b"wanted", wanted.local_addr().unwrap()).unwrap();
+  }
+  let ports = received_udp_ports(fd);
+  assert!(!ports.is_empty());
+  assert!(ports.iter().all(|&port| port == wanted_port), "{:?}", ports);
+
+  detach_filter(fd).unwrap();
+  sender.send_to(b"other", other.local_addr().unwrap()).unwrap();
+  assert!(received_udp_ports(fd).contains(&other_port));
+  assert!(detach_filter(fd).is_err());
+  unsafe { libc::close(fd) };
+}
+
+#[test]
+fn kernel_agrees_with_linux_compat() {
+  let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+  let ret = Instruction::new(RETA, 0, 0, 0);
+  let progs = vec![
+    udp_dst_port(53),
+    vec![],
+    vec![Instruction::new(LDI, 0, 0, 1)],
+    vec![Instruction::new(DIVK, 0, 0, 0), ret],
+    vec![Instruction::new(MODK, 0, 0, 0), ret],
+    vec![Instruction::new(DIVX, 0, 0, 0), ret],
+    vec![Instruction::new(LSHK, 0, 0, 32), ret],
+    vec![Instruction::new(RSHK, 0, 0, 31), ret],
+    vec![Instruction::new(STI, 0, 0, 0), ret],
+    vec![Instruction::new(ST, 0, 0, 16), ret],
+    vec![Instruction::new(LDWM, 0, 0, 0), ret],
+    vec![Instruction::new(ST, 0, 0, 0), Instruction::new(LDWM, 0, 0, 0), ret],
+    vec![Instruction::new(RETK, 0, 0, 0), Instruction::new(LDWM, 0, 0, 0), ret],
+    vec![
+      Instruction::new(ST, 0, 0, 0),
+      Instruction::new(RETK, 0, 0, 0),
+      Instruction::new(LDWM, 0, 0, 0),
+      ret,
+    ],
+    vec![Instruction::new(JMP, 0, 0, 1), ret],
+    vec![
+      Instruction::new(LDB, 0, 0, 0),
+      Instruction::new(JMPEQ, 0, 1, 1),
+      Instruction::new(ST, 0, 0, 1),
+      Instruction::new(LDXM, 0, 0, 1),
+      ret,
+    ],
+  ];
+  let opcodes = Opcode::ALL.iter().map(|opcode| {
+    let k = if opcode.raw() & MASK_OP == OP_DIV || opcode.raw() & MASK_OP == OP_MOD {
+      1
+    } else {
+      0
+    };
+    vec![
+      Instruction::new(ST, 0, 0, 0),
+      Instruction::new(opcode.raw(), 0, 0, k),
+      ret,
+    ]
+  });
+  let ancillary = (0..20).map(|n| vec![Instruction::new(LDB, 0, 0, SKF_AD_OFF + n * 4 - 2), ret]);
+  for prog in progs.into_iter().chain(opcodes).chain(ancillary) {
+    let ours = verify_linux_compat(&prog).is_ok();
+    let kernel = attach_filter(sock.as_raw_fd(), &prog);
+    assert_eq!(ours, kernel.is_ok(), "{:?}: {:?}", prog, kernel);
+  }
+}
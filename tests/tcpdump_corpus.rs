@@ -0,0 +1,235 @@
+#![cfg(feature = "std")]
+
+//! Programs printed by `tcpdump -dd` for an Ethernet interface, run against packets built to
+//! land on each side of them.
+
+extern crate rust_bpf;
+
+use rust_bpf::assembler::parser::parse_dd;
+use rust_bpf::common::instruction::Instruction;
+use rust_bpf::vm::machine::{FilterResult, PsuedoMachine};
+use rust_bpf::vm::program::{Filter, Program};
+
+/// `tcpdump -dd ip`
+const IP: &str = "\
+{ 0x28, 0, 0, 0x0000000c },
+{ 0x15, 0, 1, 0x00000800 },
+{ 0x6, 0, 0, 0x00040000 },
+{ 0x6, 0, 0, 0x00000000 },
+";
+
+/// `tcpdump -dd icmp`
+const ICMP: &str = "\
+{ 0x28, 0, 0, 0x0000000c },
+{ 0x15, 0, 3, 0x00000800 },
+{ 0x30, 0, 0, 0x00000017 },
+{ 0x15, 0, 1, 0x00000001 },
+{ 0x6, 0, 0, 0x00040000 },
+{ 0x6, 0, 0, 0x00000000 },
+";
+
+/// `tcpdump -dd udp`
+const UDP: &str = "\
+{ 0x28, 0, 0, 0x0000000c },
+{ 0x15, 0, 5, 0x000086dd },
+{ 0x30, 0, 0, 0x00000014 },
+{ 0x15, 6, 0, 0x00000011 },
+{ 0x15, 0, 6, 0x0000002c },
+{ 0x30, 0, 0, 0x00000036 },
+{ 0x15, 3, 4, 0x00000011 },
+{ 0x15, 0, 3, 0x00000800 },
+{ 0x30, 0, 0, 0x00000017 },
+{ 0x15, 0, 1, 0x00000011 },
+{ 0x6, 0, 0, 0x00040000 },
+{ 0x6, 0, 0, 0x00000000 },
+";
+
+/// `tcpdump -dd tcp port 80`
+const TCP_PORT_80: &str = "\
+{ 0x28, 0, 0, 0x0000000c },
+{ 0x15, 0, 6, 0x000086dd },
+{ 0x30, 0, 0, 0x00000014 },
+{ 0x15, 0, 15, 0x00000006 },
+{ 0x28, 0, 0, 0x00000036 },
+{ 0x15, 12, 0, 0x00000050 },
+{ 0x28, 0, 0, 0x00000038 },
+{ 0x15, 10, 11, 0x00000050 },
+{ 0x15, 0, 10, 0x00000800 },
+{ 0x30, 0, 0, 0x00000017 },
+{ 0x15, 0, 8, 0x00000006 },
+{ 0x28, 0, 0, 0x00000014 },
+{ 0x45, 6, 0, 0x00001fff },
+{ 0xb1, 0, 0, 0x0000000e },
+{ 0x48, 0, 0, 0x0000000e },
+{ 0x15, 2, 0, 0x00000050 },
+{ 0x48, 0, 0, 0x00000010 },
+{ 0x15, 0, 1, 0x00000050 },
+{ 0x6, 0, 0, 0x00040000 },
+{ 0x6, 0, 0, 0x00000000 },
+";
+
+/// The snaplen tcpdump returns for accepted packets.
+const SNAPLEN: u32 = 262144;
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_IPV6_FRAG: u8 = 44;
+const PROTO_ICMPV6: u8 = 58;
+
+/// Decodes the `tcpdump -dd` output, checks it survives a trip through `struct sock_filter`
+/// bytes, and verifies it.
+fn load(dd: &str) -> Filter {
+  let instrs = parse_dd(dd).unwrap();
+  let bytes = Instruction::program_to_sock_filter_bytes(&instrs);
+  assert_eq!(Instruction::from_sock_filter_bytes(&bytes).unwrap(), instrs);
+  Filter::new(Program::new(instrs).unwrap())
+}
+
+/// Returns an Ethernet frame carrying `payload` with this EtherType.
+fn ether(ether_type: u16, payload: &[u8]) -> Vec<u8> {
+  let mut pkt = vec![0xFF; 6];
+  pkt.extend_from_slice(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+  pkt.extend_from_slice(&ether_type.to_be_bytes());
+  pkt.extend_from_slice(payload);
+  pkt
+}
+
+/// Returns an IPv4 packet with `options_words` words of options, in an Ethernet frame.
+fn ipv4_with_options(proto: u8, frag_off: u16, options_words: u8, payload: &[u8]) -> Vec<u8> {
+  let header_len = 20 + 4 * options_words as usize;
+  let mut ip = vec![0x40 | (5 + options_words), 0];
+  ip.extend_from_slice(&((header_len + payload.len()) as u16).to_be_bytes());
+  ip.extend_from_slice(&[0x12, 0x34]);
+  ip.extend_from_slice(&frag_off.to_be_bytes());
+  ip.extend_from_slice(&[64, proto, 0, 0]);
+  ip.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+  ip.resize(header_len, 1);
+  ip.extend_from_slice(payload);
+  ether(0x0800, &ip)
+}
+
+fn ipv4(proto: u8, payload: &[u8]) -> Vec<u8> {
+  ipv4_with_options(proto, 0, 0, payload)
+}
+
+/// Returns an IPv6 packet in an Ethernet frame.
+fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+  let mut ip = vec![0x60, 0, 0, 0];
+  ip.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+  ip.extend_from_slice(&[next_header, 64]);
+  ip.extend_from_slice(&[0xFE; 32]);
+  ip.extend_from_slice(payload);
+  ether(0x86DD, &ip)
+}
+
+/// Returns a TCP header between these ports.
+fn tcp(src: u16, dst: u16) -> Vec<u8> {
+  let mut hdr = src.to_be_bytes().to_vec();
+  hdr.extend_from_slice(&dst.to_be_bytes());
+  hdr.resize(20, 0);
+  hdr[12] = 5 << 4;
+  hdr
+}
+
+/// Returns a UDP header between these ports.
+fn udp(src: u16, dst: u16) -> Vec<u8> {
+  let mut hdr = src.to_be_bytes().to_vec();
+  hdr.extend_from_slice(&dst.to_be_bytes());
+  hdr.extend_from_slice(&[0, 8, 0, 0]);
+  hdr
+}
+
+fn arp() -> Vec<u8> {
+  ether(0x0806, &[0x00, 0x01, 0x08, 0x00, 6, 4, 0, 1])
+}
+
+/// Checks `filter` keeps every packet in `accept` with tcpdump's snaplen and drops every packet
+/// in `drop`.
+fn check(filter: &Filter, accept: &[Vec<u8>], drop: &[Vec<u8>]) {
+  for (n, pkt) in accept.iter().enumerate() {
+    assert_eq!(
+      PsuedoMachine::new().run_filter(filter.program(), &pkt[..]),
+      Ok(FilterResult::Accept(SNAPLEN)),
+      "accept[{}]",
+      n
+    );
+  }
+  for (n, pkt) in drop.iter().enumerate() {
+    assert!(!filter.matches(pkt), "drop[{}]", n);
+  }
+}
+
+#[test]
+fn ip() {
+  check(
+    &load(IP),
+    &[ipv4(PROTO_TCP, &tcp(1234, 80)), ipv4(PROTO_UDP, &[])],
+    &[
+      ipv6(PROTO_TCP, &tcp(1234, 80)),
+      arp(),
+      ether(0x0800, &[])[..13].to_vec(),
+    ],
+  );
+}
+
+#[test]
+fn icmp() {
+  check(
+    &load(ICMP),
+    &[ipv4(PROTO_ICMP, &[8, 0, 0, 0])],
+    &[
+      ipv4(PROTO_TCP, &tcp(1234, 80)),
+      ipv6(PROTO_ICMPV6, &[128, 0, 0, 0]),
+      // Too short to hold the protocol field, which the kernel treats as a drop.
+      ether(0x0800, &[0x45, 0, 0, 20]),
+    ],
+  );
+}
+
+#[test]
+fn udp_either_family() {
+  let mut fragment = vec![PROTO_UDP, 0, 0, 0, 0, 0, 0, 0];
+  fragment.extend_from_slice(&udp(53, 53));
+  check(
+    &load(UDP),
+    &[
+      ipv4(PROTO_UDP, &udp(53, 1234)),
+      ipv6(PROTO_UDP, &udp(53, 1234)),
+      ipv6(PROTO_IPV6_FRAG, &fragment),
+    ],
+    &[
+      ipv4(PROTO_TCP, &tcp(53, 1234)),
+      ipv6(PROTO_TCP, &tcp(53, 1234)),
+      ipv6(PROTO_IPV6_FRAG, &[PROTO_TCP, 0, 0, 0, 0, 0, 0, 0]),
+      arp(),
+    ],
+  );
+}
+
+#[test]
+fn tcp_port_80() {
+  check(
+    &load(TCP_PORT_80),
+    &[
+      ipv4(PROTO_TCP, &tcp(1234, 80)),
+      ipv4(PROTO_TCP, &tcp(80, 1234)),
+      // Options push the TCP header along, which the ldxb picks up.
+      ipv4_with_options(PROTO_TCP, 0, 3, &tcp(1234, 80)),
+      // The first fragment still carries the ports.
+      ipv4_with_options(PROTO_TCP, 0x2000, 0, &tcp(1234, 80)),
+      ipv6(PROTO_TCP, &tcp(1234, 80)),
+      ipv6(PROTO_TCP, &tcp(80, 1234)),
+    ],
+    &[
+      ipv4(PROTO_TCP, &tcp(1234, 8080)),
+      ipv4(PROTO_UDP, &udp(1234, 80)),
+      // A later fragment has no TCP header, whatever its bytes look like.
+      ipv4_with_options(PROTO_TCP, 0x0010, 0, &tcp(1234, 80)),
+      ipv4_with_options(PROTO_TCP, 0, 3, &tcp(1234, 443)),
+      ipv6(PROTO_TCP, &tcp(1234, 443)),
+      ipv6(PROTO_UDP, &udp(1234, 80)),
+      arp(),
+    ],
+  );
+}
@@ -0,0 +1,69 @@
+#![cfg(feature = "pcap")]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Returns a little endian pcap file holding 16 byte Ethernet frames with these EtherTypes.
+fn capture(ether_types: &[u16]) -> Vec<u8> {
+  let mut buf = vec![0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0];
+  buf.extend_from_slice(&[0; 8]);
+  buf.extend_from_slice(&65535u32.to_le_bytes());
+  buf.extend_from_slice(&1u32.to_le_bytes());
+  for (n, ether_type) in ether_types.iter().enumerate() {
+    for field in &[n as u32, 0, 16, 16] {
+      buf.extend_from_slice(&field.to_le_bytes());
+    }
+    buf.extend_from_slice(&[0xFF; 12]);
+    buf.extend_from_slice(&ether_type.to_be_bytes());
+    buf.extend_from_slice(&[0x45, 0]);
+  }
+  buf
+}
+
+/// Writes `contents` to a file named `name` in a scratch directory and returns its path.
+fn scratch_file(name: &str, contents: &[u8]) -> PathBuf {
+  let dir = env::temp_dir().join(format!("tinker-bpf-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let path = dir.join(name);
+  fs::write(&path, contents).unwrap();
+  path
+}
+
+/// Runs the binary and returns its exit status and stdout.
+fn tinker_bpf(filter: &PathBuf, pcap: &PathBuf) -> (bool, String) {
+  let out = Command::new(env!("CARGO_BIN_EXE_tinker-bpf"))
+    .arg(filter)
+    .arg(pcap)
+    .output()
+    .unwrap();
+  (out.status.success(), String::from_utf8(out.stdout).unwrap())
+}
+
+#[test]
+fn prints_accepted_packets() {
+  let pcap = scratch_file("ip.pcap", &capture(&[0x0800, 0x0806, 0x86DD, 0x0800]));
+  let asm = scratch_file(
+    "ip.asm",
+    b"ldh [12]\njeq #0x800, accept, drop\naccept: ret #65535\ndrop: ret #0\n",
+  );
+  assert_eq!(tinker_bpf(&asm, &pcap), (true, "1\n4\n".to_string()));
+
+  let dd = scratch_file(
+    "arp.dd",
+    b"{ 0x28, 0, 0, 0x0000000c },\n{ 0x15, 0, 1, 0x00000806 },\n{ 0x6, 0, 0, 0x00040000 },\n{ 0x6, 0, 0, 0 },\n",
+  );
+  assert_eq!(tinker_bpf(&dd, &pcap), (true, "2\n".to_string()));
+}
+
+#[test]
+fn rejects_bad_input() {
+  let pcap = scratch_file("bad.pcap", &capture(&[0x0800]));
+  let unterminated = scratch_file("unterminated.asm", b"ldh [12]\n");
+  assert_eq!(tinker_bpf(&unterminated, &pcap), (false, String::new()));
+
+  let asm = scratch_file("accept.asm", b"ret #1\n");
+  let truncated = scratch_file("truncated.pcap", &capture(&[0x0800])[..30]);
+  assert_eq!(tinker_bpf(&asm, &truncated), (false, String::new()));
+}